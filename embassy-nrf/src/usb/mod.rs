@@ -150,6 +150,36 @@ impl<'d, T: Instance, V: VbusDetect + 'd> driver::Driver<'d> for Driver<'d, T, V
         }))
     }
 
+    fn placeholder_endpoint_in(
+        &mut self,
+        ep_type: EndpointType,
+        packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointIn, Unsupported> {
+        // Endpoint number 0 belongs to the control pipe, so a placeholder never touches a real
+        // endpoint: all `Endpoint` methods assert the number isn't 0.
+        Ok(Endpoint::new(EndpointInfo {
+            addr: EndpointAddress::from_parts(0, Direction::In),
+            ep_type,
+            max_packet_size: packet_size,
+            interval_ms,
+        }))
+    }
+
+    fn placeholder_endpoint_out(
+        &mut self,
+        ep_type: EndpointType,
+        packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointOut, Unsupported> {
+        Ok(Endpoint::new(EndpointInfo {
+            addr: EndpointAddress::from_parts(0, Direction::Out),
+            ep_type,
+            max_packet_size: packet_size,
+            interval_ms,
+        }))
+    }
+
     fn start(self, control_max_packet_size: u16) -> (Self::Bus, Self::ControlPipe) {
         (
             Bus {
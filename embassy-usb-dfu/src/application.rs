@@ -145,6 +145,5 @@ pub fn usb_dfu<'d, D: Driver<'d>, MARK: DfuMarker, RST: Reset>(
         ],
     );
 
-    drop(func);
-    builder.handler(handler);
+    func.handler(handler);
 }
@@ -42,6 +42,14 @@ impl<'d, DFU: NorFlash, STATE: NorFlash, RST: Reset, const BLOCK_SIZE: usize> Co
         self.state = State::DfuIdle;
         self.status = Status::Ok;
     }
+
+    /// Handles a request that is not valid in the current state, as per the DFU 1.1 state
+    /// transition table: the request is stalled and the device enters `dfuERROR`.
+    fn reject_unexpected(&mut self) -> OutResponse {
+        self.status = Status::ErrStalledPkt;
+        self.state = State::Error;
+        OutResponse::Rejected
+    }
 }
 
 impl From<FirmwareUpdaterError> for Status {
@@ -70,24 +78,38 @@ impl<'d, DFU: NorFlash, STATE: NorFlash, RST: Reset, const BLOCK_SIZE: usize> Ha
             debug!("Unknown out request: {:?}", req);
             return None;
         }
+        if matches!(self.state, State::DlSync | State::ManifestSync) {
+            error!("Unexpected request while chip is waiting for a GETSTATUS");
+            return Some(self.reject_unexpected());
+        }
         match Request::try_from(req.request) {
-            Ok(Request::Abort) => {
-                info!("Abort requested");
-                self.reset_state();
-                Some(OutResponse::Accepted)
-            }
-            Ok(Request::Dnload) if self.attrs.contains(DfuAttributes::CAN_DOWNLOAD) => {
-                if req.value == 0 {
-                    info!("Download starting");
-                    self.state = State::Download;
-                    self.offset = 0;
+            Ok(Request::Abort) => match self.state {
+                State::DfuIdle | State::Download | State::UploadIdle => {
+                    info!("Abort requested");
+                    self.reset_state();
+                    Some(OutResponse::Accepted)
                 }
-
-                if self.state != State::Download {
-                    error!("Unexpected DNLOAD while chip is waiting for a GETSTATUS");
-                    self.status = Status::ErrUnknown;
-                    self.state = State::Error;
-                    return Some(OutResponse::Rejected);
+                _ => {
+                    error!("Unexpected ABORT, a CLRSTATUS is required to leave the error state");
+                    Some(self.reject_unexpected())
+                }
+            },
+            Ok(Request::Dnload) if self.attrs.contains(DfuAttributes::CAN_DOWNLOAD) => {
+                match (self.state, req.length) {
+                    (State::DfuIdle, 0) => {
+                        error!("Unexpected zero-length DNLOAD before download started");
+                        return Some(self.reject_unexpected());
+                    }
+                    (State::DfuIdle, _) => {
+                        info!("Download starting");
+                        self.state = State::Download;
+                        self.offset = 0;
+                    }
+                    (State::Download, _) => {}
+                    _ => {
+                        error!("Unexpected DNLOAD outside of a download");
+                        return Some(self.reject_unexpected());
+                    }
                 }
 
                 if data.len() > BLOCK_SIZE {
@@ -135,11 +157,15 @@ impl<'d, DFU: NorFlash, STATE: NorFlash, RST: Reset, const BLOCK_SIZE: usize> Ha
                 Some(OutResponse::Accepted)
             }
             Ok(Request::Detach) => Some(OutResponse::Accepted), // Device is already in DFU mode
-            Ok(Request::ClrStatus) => {
+            Ok(Request::ClrStatus) if self.state == State::Error => {
                 info!("Clear status requested");
                 self.reset_state();
                 Some(OutResponse::Accepted)
             }
+            Ok(Request::ClrStatus) => {
+                error!("Unexpected CLRSTATUS outside of the error state");
+                Some(self.reject_unexpected())
+            }
             _ => None,
         }
     }
@@ -155,12 +181,18 @@ impl<'d, DFU: NorFlash, STATE: NorFlash, RST: Reset, const BLOCK_SIZE: usize> Ha
         }
         match Request::try_from(req.request) {
             Ok(Request::GetStatus) => {
+                // Blocks are written synchronously while handling DNLOAD, so by the time the host
+                // asks for the status the block is complete and we never have to report `dfuDNBUSY`.
+                // bState is the state the device enters right after this response, so a completed
+                // block moves `dfuDNLOAD-SYNC` to `dfuDNLOAD-IDLE` before replying.
+                if self.state == State::DlSync {
+                    self.state = State::Download;
+                }
+
                 //TODO: Configurable poll timeout, ability to add string for Vendor error
                 buf[0..6].copy_from_slice(&[self.status as u8, 0x32, 0x00, 0x00, self.state as u8, 0x00]);
-                match self.state {
-                    State::DlSync => self.state = State::Download,
-                    State::ManifestSync => RST::sys_reset(),
-                    _ => {}
+                if self.state == State::ManifestSync {
+                    RST::sys_reset()
                 }
 
                 Some(InResponse::Accepted(&buf[0..6]))
@@ -169,6 +201,11 @@ impl<'d, DFU: NorFlash, STATE: NorFlash, RST: Reset, const BLOCK_SIZE: usize> Ha
                 buf[0] = self.state as u8;
                 Some(InResponse::Accepted(&buf[0..1]))
             }
+            _ if matches!(self.state, State::DlSync | State::ManifestSync) => {
+                error!("Unexpected request while chip is waiting for a GETSTATUS");
+                self.reject_unexpected();
+                Some(InResponse::Rejected)
+            }
             Ok(Request::Upload) if self.attrs.contains(DfuAttributes::CAN_UPLOAD) => {
                 //TODO: FirmwareUpdater does not provide a way of reading the active partition, can't upload.
                 Some(InResponse::Rejected)
@@ -205,6 +242,213 @@ pub fn usb_dfu<'d, D: Driver<'d>, DFU: NorFlash, STATE: NorFlash, RST: Reset, co
         ],
     );
 
-    drop(func);
-    builder.handler(handler);
+    func.handler(handler);
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::future::poll_fn;
+    use core::task::Poll;
+    use std::boxed::Box;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::vec;
+    use std::vec::Vec;
+
+    use embassy_boot::FirmwareUpdaterConfig;
+    use embassy_futures::block_on;
+    use embassy_futures::select::select;
+    use embassy_usb::driver::test::{ControlEvent, Shared, TestDriver};
+    use embassy_usb::{Config, UsbDevice};
+    use embedded_storage::nor_flash::{ErrorType, ReadNorFlash};
+
+    use super::*;
+
+    const BLOCK_SIZE: usize = 64;
+
+    /// Flash backed by RAM.
+    struct Ram<const N: usize>([u8; N]);
+
+    impl<const N: usize> ErrorType for Ram<N> {
+        type Error = NorFlashErrorKind;
+    }
+
+    impl<const N: usize> ReadNorFlash for Ram<N> {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            let offset = offset as usize;
+            bytes.copy_from_slice(&self.0[offset..offset + bytes.len()]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            N
+        }
+    }
+
+    impl<const N: usize> NorFlash for Ram<N> {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = 64;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            self.0[from as usize..to as usize].fill(0xff);
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            let offset = offset as usize;
+            self.0[offset..offset + bytes.len()].copy_from_slice(bytes);
+            Ok(())
+        }
+    }
+
+    struct NoReset;
+
+    impl Reset for NoReset {
+        fn sys_reset() -> ! {
+            panic!("reset")
+        }
+    }
+
+    fn leak<T>(x: T) -> &'static mut T {
+        Box::leak(Box::new(x))
+    }
+
+    fn device() -> (UsbDevice<'static, TestDriver>, Rc<RefCell<Shared>>) {
+        let (driver, shared) = TestDriver::new();
+        let mut builder = Builder::new(
+            driver,
+            Config::new(0xc0de, 0xcafe),
+            leak([0; 256]),
+            leak([0; 256]),
+            leak([0; 256]),
+            leak([0; BLOCK_SIZE]),
+        );
+        let config = FirmwareUpdaterConfig {
+            dfu: Ram([0; 4096]),
+            state: Ram([0; 64]),
+        };
+        let updater = BlockingFirmwareUpdater::new(config, leak([0; 4]));
+        let control = Control::<_, _, NoReset, BLOCK_SIZE>::new(updater, DfuAttributes::CAN_DOWNLOAD);
+        usb_dfu(&mut builder, leak(control));
+        (builder.build(), shared)
+    }
+
+    /// Sends a DFU class request through the run loop and returns what the device did.
+    fn request(
+        dev: &mut UsbDevice<'static, TestDriver>,
+        shared: &RefCell<Shared>,
+        request: Request,
+        data: &[u8],
+    ) -> Vec<ControlEvent> {
+        let request_type = match request {
+            Request::GetStatus | Request::GetState => 0xa1,
+            _ => 0x21,
+        };
+        let length = match request {
+            Request::GetStatus => 6,
+            Request::GetState => 1,
+            _ => data.len() as u16,
+        };
+        let [l0, l1] = length.to_le_bytes();
+        {
+            let mut s = shared.borrow_mut();
+            s.setups.push_back([request_type, request as u8, 0, 0, 0, 0, l0, l1]);
+            if !data.is_empty() {
+                s.control_out_packets.push_back(data.to_vec());
+            }
+        }
+        let done = poll_fn(|_| match shared.borrow().setups.is_empty() {
+            true => Poll::Ready(()),
+            false => Poll::Pending,
+        });
+        block_on(select(dev.run(), done));
+        core::mem::take(&mut shared.borrow_mut().control_log)
+    }
+
+    fn state(dev: &mut UsbDevice<'static, TestDriver>, shared: &RefCell<Shared>) -> u8 {
+        match &request(dev, shared, Request::GetState, &[])[..] {
+            [ControlEvent::DataIn { data, .. }] => data[0],
+            log => panic!("unexpected control log {:?}", log),
+        }
+    }
+
+    #[test]
+    fn download_block_and_abort() {
+        let (mut dev, shared) = device();
+        assert_eq!(state(&mut dev, &shared), State::DfuIdle as u8);
+
+        let log = request(&mut dev, &shared, Request::Dnload, &[0x55; BLOCK_SIZE]);
+        assert_eq!(
+            log,
+            vec![ControlEvent::DataOut { len: BLOCK_SIZE }, ControlEvent::Accept]
+        );
+        assert_eq!(state(&mut dev, &shared), State::DlSync as u8);
+
+        // GETSTATUS reports the state entered after the response: the block is complete.
+        let log = request(&mut dev, &shared, Request::GetStatus, &[]);
+        assert_eq!(
+            log,
+            vec![ControlEvent::DataIn {
+                data: vec![Status::Ok as u8, 0x32, 0, 0, State::Download as u8, 0],
+                first: true,
+                last: true
+            }]
+        );
+
+        let log = request(&mut dev, &shared, Request::Abort, &[]);
+        assert_eq!(log, vec![ControlEvent::Accept]);
+        assert_eq!(state(&mut dev, &shared), State::DfuIdle as u8);
+    }
+
+    #[test]
+    fn unexpected_requests_enter_error() {
+        let (mut dev, shared) = device();
+
+        // A zero-length DNLOAD is only valid to end a download.
+        let log = request(&mut dev, &shared, Request::Dnload, &[]);
+        assert_eq!(log, vec![ControlEvent::Reject]);
+        assert_eq!(state(&mut dev, &shared), State::Error as u8);
+
+        // Only CLRSTATUS leaves the error state.
+        let log = request(&mut dev, &shared, Request::Abort, &[]);
+        assert_eq!(log, vec![ControlEvent::Reject]);
+        assert_eq!(state(&mut dev, &shared), State::Error as u8);
+        let log = request(&mut dev, &shared, Request::ClrStatus, &[]);
+        assert_eq!(log, vec![ControlEvent::Accept]);
+        assert_eq!(state(&mut dev, &shared), State::DfuIdle as u8);
+
+        // CLRSTATUS outside of the error state is itself unexpected.
+        let log = request(&mut dev, &shared, Request::ClrStatus, &[]);
+        assert_eq!(log, vec![ControlEvent::Reject]);
+        assert_eq!(state(&mut dev, &shared), State::Error as u8);
+    }
+
+    #[test]
+    fn sync_states_only_accept_status() {
+        let (mut dev, shared) = device();
+
+        // In dfuDNLOAD-SYNC, even ABORT must wait for GETSTATUS.
+        for unexpected in [Request::Abort, Request::Detach] {
+            request(&mut dev, &shared, Request::Dnload, &[0x55; BLOCK_SIZE]);
+            assert_eq!(state(&mut dev, &shared), State::DlSync as u8);
+            let log = request(&mut dev, &shared, unexpected, &[]);
+            assert_eq!(log, vec![ControlEvent::Reject]);
+            assert_eq!(state(&mut dev, &shared), State::Error as u8);
+            request(&mut dev, &shared, Request::ClrStatus, &[]);
+        }
+
+        // The same goes for dfuMANIFEST-SYNC, entered by the final zero-length DNLOAD.
+        request(&mut dev, &shared, Request::Dnload, &[0x55; BLOCK_SIZE]);
+        request(&mut dev, &shared, Request::GetStatus, &[]);
+        let log = request(&mut dev, &shared, Request::Dnload, &[]);
+        assert_eq!(log, vec![ControlEvent::Accept]);
+        assert_eq!(state(&mut dev, &shared), State::ManifestSync as u8);
+        let log = request(&mut dev, &shared, Request::Abort, &[]);
+        assert_eq!(log, vec![ControlEvent::Reject]);
+        assert_eq!(state(&mut dev, &shared), State::Error as u8);
+    }
 }
@@ -3,6 +3,9 @@
 #![doc = include_str!("../README.md")]
 #![warn(missing_docs)]
 
+#[cfg(feature = "std")]
+pub mod test;
+
 /// Direction of USB traffic. Note that in the USB standard the direction is always indicated from
 /// the perspective of the host, which is backward for devices, but the standard directions are used
 /// for consistency.
@@ -27,7 +30,7 @@ pub enum EndpointType {
     /// Control endpoint. Used for device management. Only the host can initiate requests. Usually
     /// used only endpoint 0.
     Control = 0b00,
-    /// Isochronous endpoint. Used for time-critical unreliable data. Not implemented yet.
+    /// Isochronous endpoint. Used for time-critical unreliable data, such as audio or video streams.
     Isochronous = 0b01,
     /// Bulk endpoint. Used for large amounts of best-effort reliable data.
     Bulk = 0b10,
@@ -126,13 +129,16 @@ pub trait Driver<'a> {
     /// Allocates an OUT endpoint.
     ///
     /// This method is called by the USB stack to allocate endpoints.
-    /// It can only be called before [`start`](Self::start) is called.
+    /// It can only be called before [`start`](Self::start) is called. Drivers that don't support
+    /// isochronous endpoints return [`EndpointAllocError`] when `ep_type` is
+    /// [`EndpointType::Isochronous`].
     ///
     /// # Arguments
     ///
     /// * `ep_type` - the endpoint's type.
     /// * `max_packet_size` - Maximum packet size in bytes.
-    /// * `interval_ms` - Polling interval parameter for interrupt endpoints.
+    /// * `interval_ms` - Polling interval parameter (`bInterval`) for interrupt and isochronous
+    ///   endpoints.
     fn alloc_endpoint_out(
         &mut self,
         ep_type: EndpointType,
@@ -143,13 +149,16 @@ pub trait Driver<'a> {
     /// Allocates an IN endpoint.
     ///
     /// This method is called by the USB stack to allocate endpoints.
-    /// It can only be called before [`start`](Self::start) is called.
+    /// It can only be called before [`start`](Self::start) is called. Drivers that don't support
+    /// isochronous endpoints return [`EndpointAllocError`] when `ep_type` is
+    /// [`EndpointType::Isochronous`].
     ///
     /// # Arguments
     ///
     /// * `ep_type` - the endpoint's type.
     /// * `max_packet_size` - Maximum packet size in bytes.
-    /// * `interval_ms` - Polling interval parameter for interrupt endpoints.
+    /// * `interval_ms` - Polling interval parameter (`bInterval`) for interrupt and isochronous
+    ///   endpoints.
     fn alloc_endpoint_in(
         &mut self,
         ep_type: EndpointType,
@@ -157,6 +166,47 @@ pub trait Driver<'a> {
         interval_ms: u8,
     ) -> Result<Self::EndpointIn, EndpointAllocError>;
 
+    /// Create an OUT endpoint standing in for one that couldn't be allocated.
+    ///
+    /// When [`alloc_endpoint_out`](Self::alloc_endpoint_out) fails, the USB stack records the
+    /// failure and passes this endpoint to the class instead, so it can report all failures when
+    /// building the device rather than panicking on the first one. The device is never started
+    /// then, so the endpoint is never enabled. It must not touch the hardware, and its methods
+    /// may panic.
+    ///
+    /// The default implementation just returns `Unsupported`, in which case the stack panics on
+    /// the allocation failure.
+    ///
+    /// # Errors
+    ///
+    /// * [`Unsupported`](crate::Unsupported) - The driver can't create placeholder endpoints.
+    fn placeholder_endpoint_out(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointOut, Unsupported> {
+        let _ = (ep_type, max_packet_size, interval_ms);
+        Err(Unsupported)
+    }
+
+    /// Create an IN endpoint standing in for one that couldn't be allocated.
+    ///
+    /// See [`placeholder_endpoint_out`](Self::placeholder_endpoint_out).
+    ///
+    /// # Errors
+    ///
+    /// * [`Unsupported`](crate::Unsupported) - The driver can't create placeholder endpoints.
+    fn placeholder_endpoint_in(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointIn, Unsupported> {
+        let _ = (ep_type, max_packet_size, interval_ms);
+        Err(Unsupported)
+    }
+
     /// Start operation of the USB device.
     ///
     /// This returns the `Bus` and `ControlPipe` instances that are used to operate
@@ -209,6 +259,32 @@ pub trait Bus {
         Err(Unsupported)
     }
 
+    /// Enables or disables the device initiating transitions to the U1 link state (USB 3 only).
+    ///
+    /// The default implementation just returns `Unsupported`.
+    ///
+    /// # Errors
+    ///
+    /// * [`Unsupported`](crate::Unsupported) - This UsbBus implementation doesn't support
+    ///   U1 link power management.
+    fn set_u1_enabled(&mut self, enabled: bool) -> Result<(), Unsupported> {
+        let _ = enabled;
+        Err(Unsupported)
+    }
+
+    /// Enables or disables the device initiating transitions to the U2 link state (USB 3 only).
+    ///
+    /// The default implementation just returns `Unsupported`.
+    ///
+    /// # Errors
+    ///
+    /// * [`Unsupported`](crate::Unsupported) - This UsbBus implementation doesn't support
+    ///   U2 link power management.
+    fn set_u2_enabled(&mut self, enabled: bool) -> Result<(), Unsupported> {
+        let _ = enabled;
+        Err(Unsupported)
+    }
+
     /// Initiate a remote wakeup of the host by the device.
     ///
     /// # Errors
@@ -219,12 +295,79 @@ pub trait Bus {
 }
 
 /// Endpoint trait, common for OUT and IN.
+///
+/// Transfers complete by resolving the `read`/`write` futures, there are no completion
+/// callbacks. A class that manages many endpoints from a central dispatcher can wait on all of
+/// them at once, for example with `embassy_futures::select::select_array`, and react to
+/// whichever transfer completes first.
 pub trait Endpoint {
     /// Get the endpoint address
     fn info(&self) -> &EndpointInfo;
 
+    /// Get the max packet size of the endpoint.
+    ///
+    /// This is the size granted by the driver, which may be smaller than the one requested
+    /// when allocating the endpoint.
+    fn max_packet_size(&self) -> u16 {
+        self.info().max_packet_size
+    }
+
     /// Wait for the endpoint to be enabled.
     async fn wait_enabled(&mut self);
+
+    /// Returns the number of isochronous packets dropped since the last call, and resets the count.
+    ///
+    /// A packet is dropped when the application doesn't provide data for an IN endpoint, or doesn't
+    /// read data from an OUT endpoint, in time for its service interval. This lets audio and video
+    /// classes report glitches. The count is kept by the driver, so polling it is the only overhead.
+    ///
+    /// The default implementation just returns `Unsupported`.
+    ///
+    /// # Errors
+    ///
+    /// * [`Unsupported`](crate::Unsupported) - This is not an isochronous endpoint, or the driver
+    ///   can't detect dropped packets.
+    fn take_dropped_packets(&mut self) -> Result<u32, Unsupported> {
+        Err(Unsupported)
+    }
+
+    /// Sets or clears the STALL condition of the endpoint.
+    ///
+    /// This is the same as [`Bus::endpoint_set_stalled`] for this endpoint, but lets a class stall
+    /// its own endpoints from its task, for example to signal a protocol error on a bulk pipe.
+    /// The host sees the halt in GET_STATUS, and usually clears it again with
+    /// CLEAR_FEATURE(ENDPOINT_HALT).
+    ///
+    /// Only `embassy-usb-synopsys-otg`, which drives the STM32 OTG peripherals, implements this so
+    /// far. The nRF, RP and STM32 USB drivers use the default implementation, which just returns
+    /// `Unsupported`.
+    ///
+    /// # Errors
+    ///
+    /// * [`Unsupported`](crate::Unsupported) - The driver can't stall endpoints outside of the
+    ///   [`Bus`].
+    fn set_stalled(&mut self, stalled: bool) -> Result<(), Unsupported> {
+        let _ = stalled;
+        Err(Unsupported)
+    }
+
+    /// Returns whether the endpoint is stalled.
+    ///
+    /// A class can use this to see if the host has cleared a halt set with
+    /// [`set_stalled`](Self::set_stalled), since CLEAR_FEATURE(ENDPOINT_HALT) is handled by the
+    /// USB stack.
+    ///
+    /// Only `embassy-usb-synopsys-otg`, which drives the STM32 OTG peripherals, implements this so
+    /// far. The nRF, RP and STM32 USB drivers use the default implementation, which just returns
+    /// `Unsupported`.
+    ///
+    /// # Errors
+    ///
+    /// * [`Unsupported`](crate::Unsupported) - The driver can't read the STALL condition outside
+    ///   of the [`Bus`].
+    fn is_stalled(&mut self) -> Result<bool, Unsupported> {
+        Err(Unsupported)
+    }
 }
 
 /// OUT Endpoint trait.
@@ -233,7 +376,38 @@ pub trait EndpointOut: Endpoint {
     /// the packet.
     ///
     /// This should also clear any NAK flags and prepare the endpoint to receive the next packet.
+    ///
+    /// Each call returns exactly one packet as sent by the host, so packet boundaries are kept.
+    /// Protocols that frame messages as bulk transfers can detect the end of a message by a
+    /// packet shorter than the max packet size, including a zero-length packet (returned as
+    /// `Ok(0)`). Use [`read_transfer`](Self::read_transfer) to collect a whole message at once.
     async fn read(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError>;
+
+    /// Read a whole transfer of data from the endpoint, and return its total length.
+    ///
+    /// The transfer ends with a short packet, or when `buf` is full. `buf` should be a multiple
+    /// of the max packet size long, otherwise the last packet may not fit.
+    ///
+    /// The default implementation calls [`read`](Self::read) for each packet, so it is a
+    /// convenience rather than a speedup. Drivers that can receive several packets directly into
+    /// `buf` in one DMA operation can override it to avoid the per-packet overhead.
+    ///
+    /// The endpoint's max packet size must not be zero, which no usable endpoint has. If it is,
+    /// the default implementation reads a single packet instead of waiting for a short one.
+    async fn read_transfer(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError> {
+        let max_packet_size = usize::from(self.max_packet_size());
+        if max_packet_size == 0 {
+            return self.read(buf).await;
+        }
+        let mut total = 0;
+        loop {
+            let size = self.read(&mut buf[total..]).await?;
+            total += size;
+            if size < max_packet_size || total == buf.len() {
+                return Ok(total);
+            }
+        }
+    }
 }
 
 /// USB control pipe trait.
@@ -308,6 +482,13 @@ pub trait EndpointOut: Endpoint {
 /// to let the stack call `setup()` again to start handling the new control request. Not doing
 /// so will cause things to get stuck, because the host will never read/send the packet we're
 /// waiting for.
+///
+/// Hardware errors during a transfer (such as an EP0 buffer overrun, or a SETUP packet overwriting
+/// an ongoing transfer) must be reported by returning an [`EndpointError`] from `data_out` or
+/// `data_in`. The stack never panics on these: it logs a warning, abandons the current control
+/// transfer and goes back to waiting for the next `setup()`. `setup()`, `accept()`, `reject()` and
+/// `accept_set_address()` can't fail; the driver must recover internally and reset its EP0 state
+/// so that the next `setup()` call works.
 pub trait ControlPipe {
     /// Maximum packet size for the control pipe
     fn max_packet_size(&self) -> usize;
@@ -340,6 +521,12 @@ pub trait ControlPipe {
     ///
     /// For most drivers this function should firstly call `accept()` and then change the bus address.
     /// However, there are peripherals (Synopsys USB OTG) that have reverse order.
+    ///
+    /// The host keeps talking to address 0 until the status stage is done, so on peripherals
+    /// where the address register takes effect immediately, it must only be written once the
+    /// status stage has been acknowledged (USB 2.0 section 9.4.6). Peripherals that latch the
+    /// address until the status stage completes, like the Synopsys OTG core, can write it first.
+    /// The stack calls this instead of `accept()` only for a SET_ADDRESS it accepted.
     async fn accept_set_address(&mut self, addr: u8);
 }
 
@@ -347,6 +534,54 @@ pub trait ControlPipe {
 pub trait EndpointIn: Endpoint {
     /// Write a single packet of data to the endpoint.
     async fn write(&mut self, buf: &[u8]) -> Result<(), EndpointError>;
+
+    /// Returns whether a packet written to the endpoint hasn't been sent to the host yet.
+    ///
+    /// [`write`](Self::write) returns once the packet is handed to the hardware, which then waits
+    /// for the host to poll the endpoint. While the endpoint is busy, the next `write` waits for
+    /// the pending packet to go out. Applications can use this to decide whether to queue more
+    /// data, or to check that everything was transmitted before shutting down.
+    ///
+    /// For double-buffered endpoints, this returns `true` only while all buffers are full, so a
+    /// packet may still be in flight when it returns `false`.
+    ///
+    /// Only `embassy-usb-synopsys-otg`, which drives the STM32 OTG peripherals, implements this so
+    /// far. The nRF, RP and STM32 USB drivers use the default implementation, which just returns
+    /// `Unsupported`.
+    ///
+    /// # Errors
+    ///
+    /// * [`Unsupported`](crate::Unsupported) - The driver can't tell whether a packet is pending.
+    fn is_busy(&self) -> Result<bool, Unsupported> {
+        Err(Unsupported)
+    }
+
+    /// Write a whole transfer of data to the endpoint.
+    ///
+    /// `buf` is sent as packets of the max packet size. If `zlp` is true and `buf` is a multiple
+    /// of the max packet size long (including empty), a zero-length packet is sent at the end so
+    /// the host sees the end of the transfer.
+    ///
+    /// The default implementation calls [`write`](Self::write) for each packet, so it is a
+    /// convenience rather than a speedup. Drivers that can send several packets directly from
+    /// `buf` in one DMA operation can override it to avoid the per-packet overhead.
+    ///
+    /// The endpoint's max packet size must not be zero, which no usable endpoint has. If it is,
+    /// the default implementation writes `buf` as a single packet, which the driver usually
+    /// rejects with [`EndpointError::BufferOverflow`].
+    async fn write_transfer(&mut self, buf: &[u8], zlp: bool) -> Result<(), EndpointError> {
+        let max_packet_size = usize::from(self.max_packet_size());
+        if max_packet_size == 0 {
+            return self.write(buf).await;
+        }
+        for chunk in buf.chunks(max_packet_size) {
+            self.write(chunk).await?;
+        }
+        if zlp && buf.len() % max_packet_size == 0 {
+            self.write(&[]).await?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
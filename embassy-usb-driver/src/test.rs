@@ -0,0 +1,374 @@
+//! In-memory driver for testing classes and the device stack without hardware.
+//!
+//! [`TestDriver`] implements all the driver traits on top of a [`Shared`] state. Tests play
+//! the host by queueing SETUP packets, control data, bus events and endpoint packets in it, and
+//! check what the device did through the control log and the packets written to IN endpoints.
+extern crate std;
+
+use core::future::pending;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::vec::Vec;
+
+use crate::{
+    Bus, ControlPipe, Direction, Driver, Endpoint, EndpointAddress, EndpointAllocError, EndpointError, EndpointIn,
+    EndpointInfo, EndpointOut, EndpointType, Event, Unsupported,
+};
+
+/// An operation performed by the stack on the control pipe.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ControlEvent {
+    /// A packet sent in the data stage of a control IN transfer, see [`ControlPipe::data_in`].
+    DataIn {
+        /// The packet's data.
+        data: Vec<u8>,
+        /// Whether this is the first packet of the data stage.
+        first: bool,
+        /// Whether this is the last packet of the data stage.
+        last: bool,
+    },
+    /// A packet of `len` bytes received in the data stage of a control OUT transfer.
+    DataOut {
+        /// Length of the packet.
+        len: usize,
+    },
+    /// The status stage accepted the request.
+    Accept,
+    /// The request was rejected by stalling the control pipe.
+    Reject,
+    /// A SET_ADDRESS request was accepted with the given address.
+    AcceptSetAddress(u8),
+}
+
+/// State shared between a [`TestDriver`], everything it created, and the test.
+#[derive(Default)]
+pub struct Shared {
+    /// SETUP packets the host sends, in order. When this runs empty, `setup` waits forever.
+    pub setups: VecDeque<[u8; 8]>,
+    /// Bus events returned by `Bus::poll`, in order. When this runs empty, `poll` waits forever.
+    pub bus_events: VecDeque<Event>,
+    /// Packets the host sends in the data stage of control OUT transfers. When this runs empty,
+    /// `data_out` fails as if the host had aborted the transfer with a new SETUP packet.
+    pub control_out_packets: VecDeque<Vec<u8>>,
+    /// Everything the stack did on the control pipe, in order.
+    pub control_log: Vec<ControlEvent>,
+    /// Endpoints currently enabled by the stack.
+    pub enabled: Vec<EndpointAddress>,
+    /// Endpoints currently stalled.
+    pub stalled: Vec<EndpointAddress>,
+    /// Packets written to IN endpoints, in order.
+    pub in_packets: Vec<(EndpointAddress, Vec<u8>)>,
+    /// Packets the host sends to OUT endpoints. Reads wait forever if none is queued for the
+    /// endpoint.
+    pub out_packets: VecDeque<(EndpointAddress, Vec<u8>)>,
+}
+
+/// Like a full-speed peripheral, bulk endpoints are clamped to 64 byte packets.
+const MAX_BULK_PACKET_SIZE: u16 = 64;
+
+/// A [`Driver`] that runs entirely in memory.
+///
+/// Endpoints are allocated in order, starting at 1 in each direction. Like a full-speed
+/// peripheral, bulk endpoints are clamped to 64 byte packets. Placeholder endpoints have
+/// number 0.
+pub struct TestDriver {
+    shared: Rc<RefCell<Shared>>,
+    next_in: usize,
+    next_out: usize,
+}
+
+impl TestDriver {
+    /// Creates a driver, and returns it along with the state it shares with the test.
+    pub fn new() -> (Self, Rc<RefCell<Shared>>) {
+        let shared = Rc::new(RefCell::new(Shared::default()));
+        let driver = Self {
+            shared: shared.clone(),
+            next_in: 1,
+            next_out: 1,
+        };
+        (driver, shared)
+    }
+}
+
+impl Driver<'_> for TestDriver {
+    type EndpointOut = TestEndpointOut;
+    type EndpointIn = TestEndpointIn;
+    type ControlPipe = TestControlPipe;
+    type Bus = TestBus;
+
+    fn alloc_endpoint_out(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointOut, EndpointAllocError> {
+        if self.next_out > 15 {
+            return Err(EndpointAllocError);
+        }
+        let addr = EndpointAddress::from_parts(self.next_out, Direction::Out);
+        let max_packet_size = clamp_max_packet_size(ep_type, max_packet_size);
+        self.next_out += 1;
+        Ok(TestEndpointOut {
+            shared: self.shared.clone(),
+            info: EndpointInfo {
+                addr,
+                ep_type,
+                max_packet_size,
+                interval_ms,
+            },
+        })
+    }
+
+    fn alloc_endpoint_in(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointIn, EndpointAllocError> {
+        if self.next_in > 15 {
+            return Err(EndpointAllocError);
+        }
+        let addr = EndpointAddress::from_parts(self.next_in, Direction::In);
+        let max_packet_size = clamp_max_packet_size(ep_type, max_packet_size);
+        self.next_in += 1;
+        Ok(TestEndpointIn {
+            shared: self.shared.clone(),
+            info: EndpointInfo {
+                addr,
+                ep_type,
+                max_packet_size,
+                interval_ms,
+            },
+        })
+    }
+
+    fn placeholder_endpoint_out(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointOut, Unsupported> {
+        Ok(TestEndpointOut {
+            shared: self.shared.clone(),
+            info: EndpointInfo {
+                addr: EndpointAddress::from_parts(0, Direction::Out),
+                ep_type,
+                max_packet_size,
+                interval_ms,
+            },
+        })
+    }
+
+    fn placeholder_endpoint_in(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointIn, Unsupported> {
+        Ok(TestEndpointIn {
+            shared: self.shared.clone(),
+            info: EndpointInfo {
+                addr: EndpointAddress::from_parts(0, Direction::In),
+                ep_type,
+                max_packet_size,
+                interval_ms,
+            },
+        })
+    }
+
+    fn start(self, control_max_packet_size: u16) -> (Self::Bus, Self::ControlPipe) {
+        (
+            TestBus {
+                shared: self.shared.clone(),
+            },
+            TestControlPipe {
+                shared: self.shared,
+                max_packet_size: control_max_packet_size as usize,
+            },
+        )
+    }
+}
+
+fn clamp_max_packet_size(ep_type: EndpointType, max_packet_size: u16) -> u16 {
+    match ep_type {
+        EndpointType::Bulk => max_packet_size.min(MAX_BULK_PACKET_SIZE),
+        _ => max_packet_size,
+    }
+}
+
+fn set_stalled(shared: &RefCell<Shared>, ep_addr: EndpointAddress, stalled: bool) {
+    let mut s = shared.borrow_mut();
+    s.stalled.retain(|&a| a != ep_addr);
+    if stalled {
+        s.stalled.push(ep_addr);
+    }
+}
+
+/// The [`Bus`] of a [`TestDriver`].
+pub struct TestBus {
+    shared: Rc<RefCell<Shared>>,
+}
+
+impl Bus for TestBus {
+    async fn enable(&mut self) {}
+
+    async fn disable(&mut self) {}
+
+    async fn poll(&mut self) -> Event {
+        let event = self.shared.borrow_mut().bus_events.pop_front();
+        match event {
+            Some(event) => event,
+            None => pending().await,
+        }
+    }
+
+    fn endpoint_set_enabled(&mut self, ep_addr: EndpointAddress, enabled: bool) {
+        let mut s = self.shared.borrow_mut();
+        s.enabled.retain(|&a| a != ep_addr);
+        if enabled {
+            s.enabled.push(ep_addr);
+        }
+    }
+
+    fn endpoint_set_stalled(&mut self, ep_addr: EndpointAddress, stalled: bool) {
+        set_stalled(&self.shared, ep_addr, stalled);
+    }
+
+    fn endpoint_is_stalled(&mut self, ep_addr: EndpointAddress) -> bool {
+        self.shared.borrow().stalled.contains(&ep_addr)
+    }
+
+    async fn remote_wakeup(&mut self) -> Result<(), Unsupported> {
+        Ok(())
+    }
+}
+
+/// The [`ControlPipe`] of a [`TestDriver`].
+pub struct TestControlPipe {
+    shared: Rc<RefCell<Shared>>,
+    max_packet_size: usize,
+}
+
+impl ControlPipe for TestControlPipe {
+    fn max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+
+    async fn setup(&mut self) -> [u8; 8] {
+        let setup = self.shared.borrow_mut().setups.pop_front();
+        match setup {
+            Some(setup) => setup,
+            None => pending().await,
+        }
+    }
+
+    async fn data_out(&mut self, buf: &mut [u8], _first: bool, _last: bool) -> Result<usize, EndpointError> {
+        let mut s = self.shared.borrow_mut();
+        let packet = s.control_out_packets.pop_front().ok_or(EndpointError::Disabled)?;
+        if packet.len() > buf.len() {
+            return Err(EndpointError::BufferOverflow);
+        }
+        buf[..packet.len()].copy_from_slice(&packet);
+        s.control_log.push(ControlEvent::DataOut { len: packet.len() });
+        Ok(packet.len())
+    }
+
+    async fn data_in(&mut self, data: &[u8], first: bool, last: bool) -> Result<(), EndpointError> {
+        self.shared.borrow_mut().control_log.push(ControlEvent::DataIn {
+            data: data.to_vec(),
+            first,
+            last,
+        });
+        Ok(())
+    }
+
+    async fn accept(&mut self) {
+        self.shared.borrow_mut().control_log.push(ControlEvent::Accept);
+    }
+
+    async fn reject(&mut self) {
+        self.shared.borrow_mut().control_log.push(ControlEvent::Reject);
+    }
+
+    async fn accept_set_address(&mut self, addr: u8) {
+        self.shared
+            .borrow_mut()
+            .control_log
+            .push(ControlEvent::AcceptSetAddress(addr));
+    }
+}
+
+/// An IN endpoint of a [`TestDriver`].
+pub struct TestEndpointIn {
+    shared: Rc<RefCell<Shared>>,
+    info: EndpointInfo,
+}
+
+impl Endpoint for TestEndpointIn {
+    fn info(&self) -> &EndpointInfo {
+        &self.info
+    }
+
+    async fn wait_enabled(&mut self) {}
+
+    fn set_stalled(&mut self, stalled: bool) -> Result<(), Unsupported> {
+        set_stalled(&self.shared, self.info.addr, stalled);
+        Ok(())
+    }
+
+    fn is_stalled(&mut self) -> Result<bool, Unsupported> {
+        Ok(self.shared.borrow().stalled.contains(&self.info.addr))
+    }
+}
+
+impl EndpointIn for TestEndpointIn {
+    async fn write(&mut self, buf: &[u8]) -> Result<(), EndpointError> {
+        if buf.len() > usize::from(self.info.max_packet_size) {
+            return Err(EndpointError::BufferOverflow);
+        }
+        self.shared.borrow_mut().in_packets.push((self.info.addr, buf.to_vec()));
+        Ok(())
+    }
+}
+
+/// An OUT endpoint of a [`TestDriver`].
+pub struct TestEndpointOut {
+    shared: Rc<RefCell<Shared>>,
+    info: EndpointInfo,
+}
+
+impl Endpoint for TestEndpointOut {
+    fn info(&self) -> &EndpointInfo {
+        &self.info
+    }
+
+    async fn wait_enabled(&mut self) {}
+
+    fn set_stalled(&mut self, stalled: bool) -> Result<(), Unsupported> {
+        set_stalled(&self.shared, self.info.addr, stalled);
+        Ok(())
+    }
+
+    fn is_stalled(&mut self) -> Result<bool, Unsupported> {
+        Ok(self.shared.borrow().stalled.contains(&self.info.addr))
+    }
+}
+
+impl EndpointOut for TestEndpointOut {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError> {
+        let packet = {
+            let mut s = self.shared.borrow_mut();
+            let pos = s.out_packets.iter().position(|(addr, _)| *addr == self.info.addr);
+            pos.and_then(|pos| s.out_packets.remove(pos))
+        };
+        let Some((_, packet)) = packet else {
+            return pending().await;
+        };
+        if packet.len() > buf.len() {
+            return Err(EndpointError::BufferOverflow);
+        }
+        buf[..packet.len()].copy_from_slice(&packet);
+        Ok(packet.len())
+    }
+}
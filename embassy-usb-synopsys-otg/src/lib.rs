@@ -1012,6 +1012,23 @@ impl<'d> embassy_usb_driver::Endpoint for Endpoint<'d, In> {
         &self.info
     }
 
+    fn set_stalled(&mut self, stalled: bool) -> Result<(), Unsupported> {
+        trace!("set_stalled ep={:?} en={}", self.info.addr, stalled);
+
+        critical_section::with(|_| {
+            self.regs.diepctl(self.info.addr.index()).modify(|w| {
+                w.set_stall(stalled);
+            });
+        });
+
+        self.state.in_waker.wake();
+        Ok(())
+    }
+
+    fn is_stalled(&mut self) -> Result<bool, Unsupported> {
+        Ok(self.regs.diepctl(self.info.addr.index()).read().stall())
+    }
+
     async fn wait_enabled(&mut self) {
         poll_fn(|cx| {
             let ep_index = self.info.addr.index();
@@ -1033,6 +1050,23 @@ impl<'d> embassy_usb_driver::Endpoint for Endpoint<'d, Out> {
         &self.info
     }
 
+    fn set_stalled(&mut self, stalled: bool) -> Result<(), Unsupported> {
+        trace!("set_stalled ep={:?} en={}", self.info.addr, stalled);
+
+        critical_section::with(|_| {
+            self.regs.doepctl(self.info.addr.index()).modify(|w| {
+                w.set_stall(stalled);
+            });
+        });
+
+        self.state.out_waker.wake();
+        Ok(())
+    }
+
+    fn is_stalled(&mut self) -> Result<bool, Unsupported> {
+        Ok(self.regs.doepctl(self.info.addr.index()).read().stall())
+    }
+
     async fn wait_enabled(&mut self) {
         poll_fn(|cx| {
             let ep_index = self.info.addr.index();
@@ -1117,6 +1151,11 @@ impl<'d> embassy_usb_driver::EndpointOut for Endpoint<'d, Out> {
 }
 
 impl<'d> embassy_usb_driver::EndpointIn for Endpoint<'d, In> {
+    fn is_busy(&self) -> Result<bool, Unsupported> {
+        // EPENA stays set until the core has sent the packet.
+        Ok(self.regs.diepctl(self.info.addr.index()).read().epena())
+    }
+
     async fn write(&mut self, buf: &[u8]) -> Result<(), EndpointError> {
         trace!("write ep={:?} data={:?}", self.info.addr, Bytes(buf));
 
@@ -1,8 +1,9 @@
 use heapless::Vec;
 
 use crate::config::MAX_HANDLER_COUNT;
-use crate::descriptor::{BosWriter, DescriptorWriter, SynchronizationType, UsageType};
-use crate::driver::{Driver, Endpoint, EndpointInfo, EndpointType};
+use crate::descriptor::{self, descriptor_type, lang_id, BosWriter, DescriptorWriter, SynchronizationType, UsageType};
+use crate::descriptor_reader::foreach_endpoint;
+use crate::driver::{Direction, Driver, Endpoint, EndpointAddress, EndpointInfo, EndpointType, Unsupported};
 use crate::msos::{DeviceLevelDescriptor, FunctionLevelDescriptor, MsOsDescriptorWriter};
 use crate::types::{InterfaceNumber, StringIndex};
 use crate::{Handler, Interface, UsbDevice, MAX_INTERFACE_COUNT, STRING_INDEX_CUSTOM_START};
@@ -18,6 +19,17 @@ pub enum UsbVersion {
     TwoOne = 0x0210,
 }
 
+/// What to do with class and vendor requests no handler accepts or rejects.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum UnhandledRequests {
+    /// Stall them, as the USB specification requires.
+    Stall,
+    /// Accept requests without a data stage (`wLength = 0`) without doing anything, and stall
+    /// the others. This is a workaround for hosts that treat a stall on endpoint 0 as fatal.
+    AcceptZeroLength,
+}
+
 #[derive(Debug, Copy, Clone)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[non_exhaustive]
@@ -28,6 +40,10 @@ pub struct Config<'a> {
 
     /// Device BCD USB version.
     ///
+    /// Hosts ignore the BOS descriptor for USB 2.0 devices, so it is not served in that case.
+    /// If BOS capabilities such as WebUSB or MS OS 2.0 descriptors are added, 2.0 is bumped
+    /// to 2.1 with a warning.
+    ///
     /// Default: `0x0210` ("2.1")
     pub bcd_usb: UsbVersion,
 
@@ -62,6 +78,9 @@ pub struct Config<'a> {
     /// - full speed: 8, 16, 32, or 64
     /// - high speed: 64
     ///
+    /// [`Builder::try_build`] returns [`BuilderError::InvalidMaxPacketSize`] for any other value,
+    /// and for values other than 64 unless [`full_speed_only`](Self::full_speed_only) is set.
+    ///
     /// Default: 64 bytes
     pub max_packet_size_0: u8,
 
@@ -80,6 +99,25 @@ pub struct Config<'a> {
     /// Default: (none)
     pub serial_number: Option<&'a str>,
 
+    /// Language IDs of the supported string descriptor languages, in order of preference.
+    ///
+    /// These are reported to the host in the string descriptor with index 0. The list can be
+    /// built at runtime, for example if the supported languages depend on installed modules.
+    /// String requests for any other language are stalled. The strings in this config are
+    /// returned for every supported language; localized strings can be provided by a handler's
+    /// [`get_string`](crate::Handler::get_string). See [`lang_id`](crate::descriptor::lang_id).
+    ///
+    /// Default: `[lang_id::ENGLISH_US]`
+    pub supported_lang_ids: &'a [u16],
+
+    /// Configuration name string descriptor, referenced by `iConfiguration`.
+    ///
+    /// If set, it takes the first string index after the serial number, so the indices that
+    /// [`Builder::string`] allocates start one higher.
+    ///
+    /// Default: (none)
+    pub configuration_string: Option<&'a str>,
+
     /// Whether the device supports remotely waking up the host is requested.
     ///
     /// Default: `false`
@@ -117,6 +155,43 @@ pub struct Config<'a> {
     /// Default: 100mA
     /// Max: 500mA
     pub max_power: u16,
+
+    /// Whether to disable all class endpoints while the bus is suspended.
+    ///
+    /// Bus-powered devices may only draw 2.5 mA while suspended. When this is set, the endpoints
+    /// are disabled before [`Handler::suspended`](crate::Handler::suspended) is called with
+    /// `true`, where the application can lower its clocks and power down peripherals. On resume,
+    /// the endpoints of the current configuration are enabled again before handlers are called
+    /// with `false`. Class reads and writes in progress fail with `EndpointError::Disabled`.
+    ///
+    /// Default: `false`
+    pub disable_endpoints_on_suspend: bool,
+
+    /// What to do with class and vendor requests that no handler handles.
+    ///
+    /// Standard requests the stack doesn't support are always stalled.
+    ///
+    /// Default: [`UnhandledRequests::Stall`]
+    pub unhandled_requests: UnhandledRequests,
+
+    /// Whether the device supports USB 2.0 Link Power Management (L1 sleep).
+    ///
+    /// This sets the LPM bit in the USB 2.0 extension capability of the BOS descriptor. Only
+    /// enable it if the driver supports entering and leaving L1. Hosts only read the BOS from
+    /// USB 2.1 devices, so a [`bcd_usb`](Self::bcd_usb) of 2.0 is bumped to 2.1 with a warning.
+    ///
+    /// Default: `false`
+    pub link_power_management: bool,
+
+    /// Whether the device can only operate at full speed (or low speed).
+    ///
+    /// By default the stack answers GET_DESCRIPTOR for the device qualifier, which hosts use
+    /// during high-speed enumeration, and offers the other-speed configuration to handlers. USB
+    /// 2.0 requires full-speed only devices to stall both requests, which they do when this is set.
+    /// It must also be set to use a [`max_packet_size_0`](Self::max_packet_size_0) below 64.
+    ///
+    /// Default: `false`
+    pub full_speed_only: bool,
 }
 
 impl<'a> Config<'a> {
@@ -134,15 +209,199 @@ impl<'a> Config<'a> {
             manufacturer: None,
             product: None,
             serial_number: None,
+            supported_lang_ids: &[lang_id::ENGLISH_US],
+            configuration_string: None,
             self_powered: false,
             supports_remote_wakeup: false,
             composite_with_iads: true,
+            disable_endpoints_on_suspend: false,
+            unhandled_requests: UnhandledRequests::Stall,
+            link_power_management: false,
+            full_speed_only: false,
             max_power: 100,
         }
     }
 }
 
+/// Buffers for assembling the descriptors and handling control requests, for
+/// [`Builder::with_buffers`].
+///
+/// This keeps all the buffers a [`UsbDevice`] needs in one value, sized by the const parameters:
+/// the configuration descriptor, the BOS descriptor, the MS OS descriptors and the control
+/// buffer. Use 0 for `MSOS` if the device has no MS OS descriptors. The device descriptor doesn't
+/// need a buffer, it's built from the [`Config`].
+///
+/// ```ignore
+/// use embassy_usb::{Buffers, Builder, Config};
+/// use static_cell::StaticCell;
+///
+/// static BUFFERS: StaticCell<Buffers<256, 256, 0, 64>> = StaticCell::new();
+///
+/// let config = Config::new(0xc0de, 0xcafe);
+/// let mut builder = Builder::with_buffers(driver, config, BUFFERS.init(Buffers::new()));
+/// // Add classes...
+/// let usb = builder.build();
+/// ```
+pub struct Buffers<const CONFIG: usize, const BOS: usize, const MSOS: usize, const CONTROL: usize> {
+    config_descriptor: [u8; CONFIG],
+    bos_descriptor: [u8; BOS],
+    msos_descriptor: [u8; MSOS],
+    control: [u8; CONTROL],
+}
+
+impl<const CONFIG: usize, const BOS: usize, const MSOS: usize, const CONTROL: usize> Default
+    for Buffers<CONFIG, BOS, MSOS, CONTROL>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const CONFIG: usize, const BOS: usize, const MSOS: usize, const CONTROL: usize>
+    Buffers<CONFIG, BOS, MSOS, CONTROL>
+{
+    /// Create new, zeroed buffers.
+    pub const fn new() -> Self {
+        Self {
+            config_descriptor: [0; CONFIG],
+            bos_descriptor: [0; BOS],
+            msos_descriptor: [0; MSOS],
+            control: [0; CONTROL],
+        }
+    }
+}
+
+/// An endpoint the driver couldn't allocate, see [`BuilderError::EndpointAllocFailed`].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct EndpointAllocFailure {
+    /// Direction of the endpoint.
+    pub direction: Direction,
+    /// Type of the endpoint.
+    pub ep_type: EndpointType,
+    /// Requested maximum packet size.
+    pub max_packet_size: u16,
+    /// Interface the endpoint was requested for.
+    pub interface: InterfaceNumber,
+}
+
+impl core::fmt::Display for EndpointAllocFailure {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let direction = match self.direction {
+            Direction::In => "IN",
+            Direction::Out => "OUT",
+        };
+        write!(
+            f,
+            "{:?} {} endpoint with max packet size {} for interface {}",
+            self.ep_type, direction, self.max_packet_size, self.interface
+        )
+    }
+}
+
+/// Maximum number of failed endpoint allocations a [`Builder`] records. Past that, the set of
+/// classes is far beyond what the hardware can do, and more entries wouldn't help. This keeps
+/// [`BuilderError`] small.
+const MAX_ENDPOINT_ALLOC_FAILURES: usize = 16;
+
+/// All endpoints the driver couldn't allocate while adding classes to a [`Builder`], in the
+/// order they were requested.
+///
+/// Only the first 16 failures are recorded.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct EndpointAllocFailures(Vec<EndpointAllocFailure, MAX_ENDPOINT_ALLOC_FAILURES>);
+
+impl EndpointAllocFailures {
+    /// Returns the failed allocations.
+    pub fn as_slice(&self) -> &[EndpointAllocFailure] {
+        &self.0
+    }
+}
+
+impl core::fmt::Display for EndpointAllocFailures {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for (i, failure) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", failure)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for EndpointAllocFailures {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "{}", self.as_slice())
+    }
+}
+
+/// Error returned by [`Builder::try_build`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub enum BuilderError {
+    /// More handlers were added than the `max_handler_count` compile-time setting allows.
+    TooManyHandlers,
+    /// An endpoint descriptor was written for an endpoint that wasn't allocated.
+    UnallocatedEndpointDescriptor(EndpointAddress),
+    /// An endpoint was allocated but has no endpoint descriptor.
+    MissingEndpointDescriptor(EndpointAddress),
+    /// The BOS descriptor has capabilities, such as LPM support, but the device descriptor
+    /// passed to [`Builder::device_descriptor`] has a bcdUSB below 0x0201, so hosts won't read it.
+    BosRequiresUsb21,
+    /// [`Config::max_packet_size_0`] isn't 8, 16, 32 or 64, or isn't 64 for a device that can
+    /// operate at high speed.
+    InvalidMaxPacketSize,
+    /// The driver couldn't allocate some of the endpoints the classes asked for. The classes got
+    /// placeholder endpoints from the driver instead, which must not be used.
+    EndpointAllocFailed(EndpointAllocFailures),
+}
+
+impl core::fmt::Display for BuilderError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            BuilderError::TooManyHandlers => write!(
+                f,
+                "handler list full. Increase the `max_handler_count` compile-time setting. Current value: {}",
+                MAX_HANDLER_COUNT
+            ),
+            BuilderError::UnallocatedEndpointDescriptor(addr) => {
+                write!(
+                    f,
+                    "endpoint descriptor for {:?} doesn't match any allocated endpoint",
+                    addr
+                )
+            }
+            BuilderError::MissingEndpointDescriptor(addr) => {
+                write!(f, "endpoint {:?} was allocated but has no endpoint descriptor", addr)
+            }
+            BuilderError::BosRequiresUsb21 => write!(
+                f,
+                "the BOS descriptor has capabilities, but the overridden device descriptor's bcdUSB is below 0x0201"
+            ),
+            BuilderError::InvalidMaxPacketSize => write!(
+                f,
+                "invalid max_packet_size_0, the allowed values are 8, 16, 32 or 64, and only 64 unless `full_speed_only` is set"
+            ),
+            BuilderError::EndpointAllocFailed(failures) => write!(
+                f,
+                "failed to allocate endpoints, the hardware has too few endpoints or too little endpoint memory: {}",
+                failures
+            ),
+        }
+    }
+}
+
 /// [`UsbDevice`] builder.
+///
+/// All descriptors are assembled once, when [`Builder::build`] is called, so the set of classes
+/// is fixed for the lifetime of the [`UsbDevice`]. To make a class optional (for example depending
+/// on a license or operating mode), only add it to the builder when it's enabled. To change the
+/// set of classes at runtime, call [`UsbDevice::disable`], drop the device and its classes, and
+/// build a new one from a new driver instance. The host sees a disconnect and enumerates the
+/// device again with the new descriptors.
 pub struct Builder<'d, D: Driver<'d>> {
     config: Config<'d>,
     handlers: Vec<&'d mut dyn Handler, MAX_HANDLER_COUNT>,
@@ -151,7 +410,12 @@ pub struct Builder<'d, D: Driver<'d>> {
 
     driver: D,
     next_string_index: u8,
+    allocated_endpoints: u32,
+    /// First error found while adding handlers, reported by [`Builder::try_build`].
+    error: Option<BuilderError>,
+    alloc_failures: EndpointAllocFailures,
 
+    device_descriptor: Option<[u8; 18]>,
     config_descriptor: DescriptorWriter<'d>,
     bos_descriptor: BosWriter<'d>,
 
@@ -164,6 +428,12 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
     /// `control_buf` is a buffer used for USB control request data. It should be sized
     /// large enough for the length of the largest control request (in or out)
     /// anticipated by any class added to the device.
+    ///
+    /// The descriptor buffers are plain byte buffers the descriptors are assembled into at
+    /// runtime, and the device serves them from the used prefix only, so there's no padding
+    /// between descriptors. To save RAM, size them to what the device actually needs: with the
+    /// `log` or `defmt` feature, [`build`](Self::build) reports the number of bytes used in each
+    /// buffer at trace level.
     pub fn new(
         driver: D,
         config: Config<'d>,
@@ -184,16 +454,27 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
             "The maximum allowed value for `max_power` is 500mA"
         );
 
-        match config.max_packet_size_0 {
-            8 | 16 | 32 | 64 => {}
-            _ => panic!("invalid max_packet_size_0, the allowed values are 8, 16, 32 or 64"),
+        assert!(
+            !config.supported_lang_ids.is_empty() && config.supported_lang_ids.len() <= 126,
+            "`supported_lang_ids` must contain between 1 and 126 language IDs"
+        );
+
+        let mut error = None;
+        let max_packet_size_0_valid = match config.max_packet_size_0 {
+            8 | 16 | 32 => config.full_speed_only,
+            64 => true,
+            _ => false,
+        };
+        if !max_packet_size_0_valid {
+            warn!("USB: invalid max_packet_size_0, the device will fail to build");
+            error = Some(BuilderError::InvalidMaxPacketSize);
         }
 
         let mut config_descriptor = DescriptorWriter::new(config_descriptor_buf);
         let mut bos_descriptor = BosWriter::new(DescriptorWriter::new(bos_descriptor_buf));
 
         config_descriptor.configuration(&config);
-        bos_descriptor.bos();
+        bos_descriptor.bos(config.link_power_management);
 
         Builder {
             driver,
@@ -201,8 +482,12 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
             interfaces: Vec::new(),
             handlers: Vec::new(),
             control_buf,
-            next_string_index: STRING_INDEX_CUSTOM_START,
+            next_string_index: STRING_INDEX_CUSTOM_START + config.configuration_string.is_some() as u8,
+            allocated_endpoints: 0,
+            error,
+            alloc_failures: EndpointAllocFailures::default(),
 
+            device_descriptor: None,
             config_descriptor,
             bos_descriptor,
 
@@ -210,29 +495,128 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
         }
     }
 
+    /// Creates a builder using a single [`Buffers`] value for all its buffers.
+    ///
+    /// This is the same as [`Builder::new`] with the buffers from `buffers`.
+    pub fn with_buffers<const CONFIG: usize, const BOS: usize, const MSOS: usize, const CONTROL: usize>(
+        driver: D,
+        config: Config<'d>,
+        buffers: &'d mut Buffers<CONFIG, BOS, MSOS, CONTROL>,
+    ) -> Self {
+        Self::new(
+            driver,
+            config,
+            &mut buffers.config_descriptor,
+            &mut buffers.bos_descriptor,
+            &mut buffers.msos_descriptor,
+            &mut buffers.control,
+        )
+    }
+
     /// Creates the [`UsbDevice`] instance with the configuration in this builder.
-    pub fn build(mut self) -> UsbDevice<'d, D> {
+    ///
+    /// Panics on the errors [`try_build`](Self::try_build) returns.
+    pub fn build(self) -> UsbDevice<'d, D> {
+        match self.try_build() {
+            Ok(device) => device,
+            Err(e) => panic!("embassy-usb: {}", e),
+        }
+    }
+
+    /// Creates the [`UsbDevice`] instance with the configuration in this builder, or returns
+    /// the first error found.
+    ///
+    /// Fails if more handlers were added than fit, if the driver couldn't allocate an endpoint,
+    /// if an endpoint descriptor was written for an endpoint that wasn't allocated, or if an
+    /// allocated endpoint has no endpoint descriptor. All of these depend on the set of classes,
+    /// so applications that can fall back to a smaller set of classes can use this instead of
+    /// [`build`](Self::build). Endpoint allocation failures are only returned with drivers that
+    /// create placeholder endpoints, see [`Driver::placeholder_endpoint_in`], and panic with the
+    /// others. It also fails if
+    /// the [`Config`] has an invalid `max_packet_size_0`, or if the BOS descriptor can't be served
+    /// because of a [`device_descriptor`](Self::device_descriptor) override.
+    pub fn try_build(mut self) -> Result<UsbDevice<'d, D>, BuilderError> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        if !self.alloc_failures.0.is_empty() {
+            return Err(BuilderError::EndpointAllocFailed(self.alloc_failures));
+        }
+        self.check_endpoints()?;
+
         let msos_descriptor = self.msos_descriptor.build(&mut self.bos_descriptor);
 
         self.config_descriptor.end_configuration();
         self.bos_descriptor.end_bos();
 
+        // Hosts only read the BOS descriptor if bcdUSB is at least 0x0201. The BOS always has the
+        // USB 2.0 extension capability, but it only matters if it advertises LPM. Anything beyond
+        // that (WebUSB, MS OS 2.0, ...) needs 2.1 as well.
+        let bos_required = self.config.link_power_management || self.bos_descriptor.num_capabilities() > 1;
+        if bos_required && matches!(self.config.bcd_usb, UsbVersion::Two) {
+            warn!("USB: BOS capabilities require bcdUSB >= 0x0201, using USB 2.1 instead of 2.0");
+            self.config.bcd_usb = UsbVersion::TwoOne;
+        }
+
         // Log the number of allocator bytes actually used in descriptor buffers
         trace!("USB: config_descriptor used: {}", self.config_descriptor.position());
         trace!("USB: bos_descriptor used: {}", self.bos_descriptor.writer.position());
         trace!("USB: msos_descriptor used: {}", msos_descriptor.len());
         trace!("USB: control_buf size: {}", self.control_buf.len());
 
-        UsbDevice::build(
+        let device_descriptor = self
+            .device_descriptor
+            .unwrap_or_else(|| descriptor::device_descriptor(&self.config));
+        let bos_in_use = u16::from_le_bytes([device_descriptor[2], device_descriptor[3]]) >= 0x0201;
+        if bos_required && !bos_in_use {
+            return Err(BuilderError::BosRequiresUsb21);
+        }
+
+        Ok(UsbDevice::build(
             self.driver,
             self.config,
             self.handlers,
+            device_descriptor,
             self.config_descriptor.into_buf(),
-            self.bos_descriptor.writer.into_buf(),
+            if bos_in_use {
+                self.bos_descriptor.writer.into_buf()
+            } else {
+                &mut []
+            },
             msos_descriptor,
             self.interfaces,
             self.control_buf,
-        )
+        ))
+    }
+
+    /// Cross-checks the endpoint descriptors in the configuration descriptor against the
+    /// endpoints that were actually allocated from the driver.
+    fn check_endpoints(&self) -> Result<(), BuilderError> {
+        let config_descriptor = &self.config_descriptor.buf[..self.config_descriptor.position()];
+        let mut described_endpoints = 0;
+        let mut unallocated = None;
+        foreach_endpoint(config_descriptor, |ep| {
+            let bit = endpoint_bit(ep.ep_address);
+            if self.allocated_endpoints & bit == 0 {
+                unallocated.get_or_insert(ep.ep_address);
+            }
+            described_endpoints |= bit;
+        })
+        .unwrap();
+        if let Some(addr) = unallocated {
+            return Err(BuilderError::UnallocatedEndpointDescriptor(addr));
+        }
+
+        let missing = self.allocated_endpoints & !described_endpoints;
+        if missing != 0 {
+            let bit = missing.trailing_zeros() as usize;
+            let dir = if bit >= 16 { Direction::In } else { Direction::Out };
+            return Err(BuilderError::MissingEndpointDescriptor(EndpointAddress::from_parts(
+                bit % 16,
+                dir,
+            )));
+        }
+        Ok(())
     }
 
     /// Returns the size of the control request data buffer. Can be used by
@@ -241,10 +625,45 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
         self.control_buf.len()
     }
 
+    /// Returns the max packet size of the control endpoint, [`Config::max_packet_size_0`].
+    ///
+    /// Classes can store it to reason about multi-packet control transfers. There's no need to
+    /// handle zero-length packets for control IN responses though: if a response is shorter than
+    /// `wLength` and a multiple of this size, the stack appends the ZLP itself.
+    pub fn control_max_packet_size(&self) -> u8 {
+        self.config.max_packet_size_0
+    }
+
+    /// Overrides the device descriptor.
+    ///
+    /// By default, the device descriptor is assembled from the fields in [`Config`], which is
+    /// what you want in almost all cases. This is an escape hatch for advanced users that need
+    /// full control over the raw descriptor bytes.
+    ///
+    /// `bMaxPacketSize0` must match [`Config::max_packet_size_0`], since the latter is what the
+    /// control pipe is started with. The BOS descriptor is only served if `bcdUSB` is at least
+    /// 0x0201, and building fails with [`BuilderError::BosRequiresUsb21`] if it is lower while
+    /// the BOS has capabilities or LPM enabled.
+    pub fn device_descriptor(&mut self, descriptor: [u8; 18]) {
+        assert!(
+            descriptor[0] == 18 && descriptor[1] == descriptor_type::DEVICE,
+            "invalid device descriptor header"
+        );
+        assert!(
+            descriptor[7] == self.config.max_packet_size_0,
+            "device descriptor bMaxPacketSize0 doesn't match `max_packet_size_0`"
+        );
+
+        self.device_descriptor = Some(descriptor);
+    }
+
     /// Add an USB function.
     ///
     /// If [`Config::composite_with_iads`] is set, this will add an IAD descriptor
-    /// with the given class/subclass/protocol, associating all the child interfaces.
+    /// with the given class/subclass/protocol, associating all the child interfaces. Its
+    /// bInterfaceCount is filled in as interfaces are added with [`FunctionBuilder::interface`].
+    /// Devices with IADs must use device class `0xEF`, subclass `0x02` and protocol `0x01`
+    /// (Miscellaneous, Common Class, IAD), which are the [`Config`] defaults.
     ///
     /// If it's not set, no IAD descriptor is added.
     pub fn function(&mut self, class: u8, subclass: u8, protocol: u8) -> FunctionBuilder<'_, 'd, D> {
@@ -263,19 +682,40 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
             iface_count_index,
 
             first_interface,
+            handler: None,
         }
     }
 
-    /// Add a Handler.
+    /// Add a Handler that doesn't own any interfaces.
     ///
     /// The Handler is called on some USB bus events, and to handle all control requests not already
     /// handled by the USB stack.
+    ///
+    /// The [`UsbDevice`] only keeps the `&'d mut dyn Handler`, so classes should be split in two
+    /// parts: a handler for control requests, usually stored in a user-provided `State`, and a typed
+    /// handle owning the endpoints that is returned to the application for I/O. This is how all
+    /// classes in [`class`](crate::class) work, e.g. [`CdcAcmClass::new`](crate::class::cdc_acm::CdcAcmClass::new)
+    /// registers its handler and returns the `CdcAcmClass` used to read and write packets.
+    ///
+    /// Class and vendor requests addressed to an interface are only passed to the handler owning
+    /// it, so a handler added here only sees requests addressed to the device, to endpoints, or to
+    /// interfaces without an owner. Handlers for a function's interfaces are added with
+    /// [`FunctionBuilder::handler`] instead.
+    ///
+    /// If the handler list is full, the handler is dropped and [`try_build`](Self::try_build)
+    /// returns [`BuilderError::TooManyHandlers`].
     pub fn handler(&mut self, handler: &'d mut dyn Handler) {
-        assert!(
-            self.handlers.push(handler).is_ok(),
-            "embassy-usb: handler list full. Increase the `max_handler_count` compile-time setting. Current value: {}",
-            MAX_HANDLER_COUNT
-        );
+        self.push_handler(handler);
+    }
+
+    fn push_handler(&mut self, handler: &'d mut dyn Handler) -> Option<u8> {
+        let index = self.handlers.len() as u8;
+        if self.handlers.push(handler).is_err() {
+            warn!("USB: handler list full, the device will fail to build");
+            self.error.get_or_insert(BuilderError::TooManyHandlers);
+            return None;
+        }
+        Some(index)
     }
 
     /// Allocates a new string index.
@@ -304,6 +744,13 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
     }
 }
 
+/// Bit for an endpoint in `Builder::allocated_endpoints`: OUT endpoints use the low
+/// 16 bits, IN endpoints the high 16 bits.
+fn endpoint_bit(addr: EndpointAddress) -> u32 {
+    let offset = if addr.is_in() { 16 } else { 0 };
+    1 << (offset + (addr.index() & 0x0f))
+}
+
 /// Function builder.
 ///
 /// A function is a logical grouping of interfaces that perform a given USB function.
@@ -314,6 +761,7 @@ pub struct FunctionBuilder<'a, 'd, D: Driver<'d>> {
     iface_count_index: Option<usize>,
 
     first_interface: InterfaceNumber,
+    handler: Option<u8>,
 }
 
 impl<'a, 'd, D: Driver<'d>> Drop for FunctionBuilder<'a, 'd, D> {
@@ -335,6 +783,7 @@ impl<'a, 'd, D: Driver<'d>> FunctionBuilder<'a, 'd, D> {
         let iface = Interface {
             current_alt_setting: 0,
             num_alt_settings: 0,
+            handler: self.handler,
         };
 
         assert!(self.builder.interfaces.push(iface).is_ok(),
@@ -349,7 +798,28 @@ impl<'a, 'd, D: Driver<'d>> FunctionBuilder<'a, 'd, D> {
         }
     }
 
+    /// Add the Handler owning this function's interfaces.
+    ///
+    /// Class and vendor requests addressed to the function's interfaces, whether they were added
+    /// before or after this call, are only passed to this handler. Requests for interfaces no
+    /// handler owns go to the handlers added with [`Builder::handler`].
+    ///
+    /// Panics if the function already has a handler.
+    pub fn handler(&mut self, handler: &'d mut dyn Handler) {
+        assert!(self.handler.is_none(), "embassy-usb: function already has a handler");
+        self.handler = self.builder.push_handler(handler);
+        let first = usize::from(self.first_interface.0);
+        for iface in &mut self.builder.interfaces[first..] {
+            iface.handler = self.handler;
+        }
+    }
+
     /// Add an MS OS 2.0 Function Level Feature Descriptor.
+    ///
+    /// The feature is written to a function subset for this function's first interface, so it
+    /// only applies to this function. Functions without any features get no subset, which lets
+    /// e.g. a vendor function use WinUSB while CDC functions in the same device keep their
+    /// class drivers.
     pub fn msos_feature<T: FunctionLevelDescriptor>(&mut self, desc: T) {
         if !self.builder.msos_descriptor.is_in_config_subset() {
             self.builder.msos_descriptor.configuration(0);
@@ -415,6 +885,13 @@ impl<'a, 'd, D: Driver<'d>> InterfaceBuilder<'a, 'd, D> {
 }
 
 /// Interface alternate setting builder.
+///
+/// The interface descriptor is written when the alternate setting is created. Endpoint and
+/// class-specific descriptors are then appended in exactly the order the builder functions are
+/// called, so a class can emit the sequence its specification requires (for example a CDC
+/// functional descriptor before the notification endpoint). Use
+/// [`InterfaceAltBuilder::alloc_endpoint_in`] and [`InterfaceAltBuilder::endpoint_descriptor`]
+/// when an endpoint has to be allocated before its descriptor can be written.
 pub struct InterfaceAltBuilder<'a, 'd, D: Driver<'d>> {
     builder: &'a mut Builder<'d, D>,
     interface_number: InterfaceNumber,
@@ -440,11 +917,35 @@ impl<'a, 'd, D: Driver<'d>> InterfaceAltBuilder<'a, 'd, D> {
         self.builder.config_descriptor.write(descriptor_type, descriptor, &[]);
     }
 
+    /// Add a class-specific endpoint (CS_ENDPOINT) descriptor to this alternate setting.
+    ///
+    /// Call this right after writing the endpoint descriptor it belongs to, for example the audio
+    /// streaming endpoint descriptor that tells the host the sample rate can be set.
+    pub fn class_endpoint_descriptor(&mut self, descriptor: &[u8]) {
+        self.descriptor(descriptor_type::CS_ENDPOINT, descriptor);
+    }
+
     /// Add a custom Binary Object Store (BOS) descriptor to this alternate setting.
     pub fn bos_capability(&mut self, capability_type: u8, capability: &[u8]) {
         self.builder.bos_descriptor.capability(capability_type, capability);
     }
 
+    /// Add a SuperSpeedPlus device capability to the Binary Object Store (BOS) descriptor.
+    ///
+    /// See [`BosWriter::superspeed_plus_capability`].
+    #[cfg(feature = "superspeed-plus")]
+    pub fn superspeed_plus_capability(
+        &mut self,
+        min_speed_id: u8,
+        min_rx_lanes: u8,
+        min_tx_lanes: u8,
+        attributes: &[descriptor::SublinkSpeedAttribute],
+    ) {
+        self.builder
+            .bos_descriptor
+            .superspeed_plus_capability(min_speed_id, min_rx_lanes, min_tx_lanes, attributes);
+    }
+
     /// Write a custom endpoint descriptor for a certain endpoint.
     ///
     /// This can be necessary, if the endpoint descriptors can only be written
@@ -462,17 +963,61 @@ impl<'a, 'd, D: Driver<'d>> InterfaceAltBuilder<'a, 'd, D> {
             .endpoint(endpoint, synchronization_type, usage_type, extra_fields);
     }
 
+    /// Records a failed endpoint allocation for [`Builder::try_build`], and returns the driver's
+    /// placeholder endpoint so the class can carry on and later allocations are still tried.
+    ///
+    /// If the driver has no placeholder endpoints, this panics with a report of what was requested
+    /// and which endpoints are already in use, to make it easy to see how far a composite device
+    /// is over the hardware's capacity.
+    fn alloc_failed<E>(
+        &mut self,
+        direction: Direction,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        placeholder: Result<E, Unsupported>,
+    ) -> E {
+        let failure = EndpointAllocFailure {
+            direction,
+            ep_type,
+            max_packet_size,
+            interface: self.interface_number,
+        };
+        warn!("USB: failed to allocate {}, the device will fail to build", failure);
+        // Past the capacity, try_build still fails with the failures recorded so far.
+        let _ = self.builder.alloc_failures.0.push(failure);
+
+        let allocated = self.builder.allocated_endpoints;
+        placeholder.unwrap_or_else(|_| panic!(
+            "embassy-usb: failed to allocate {}. Already allocated: {} IN endpoints (mask {:#x}), {} OUT endpoints (mask {:#x}).",
+            failure,
+            (allocated >> 16).count_ones(),
+            allocated >> 16,
+            (allocated & 0xffff).count_ones(),
+            allocated & 0xffff,
+        ))
+    }
+
     /// Allocate an IN endpoint, without writing its descriptor.
     ///
     /// Used for granular control over the order of endpoint and descriptor creation.
     pub fn alloc_endpoint_in(&mut self, ep_type: EndpointType, max_packet_size: u16, interval_ms: u8) -> D::EndpointIn {
-        let ep = self
+        match self
             .builder
             .driver
             .alloc_endpoint_in(ep_type, max_packet_size, interval_ms)
-            .expect("alloc_endpoint_in failed");
-
-        ep
+        {
+            Ok(ep) => {
+                self.builder.allocated_endpoints |= endpoint_bit(ep.info().addr);
+                ep
+            }
+            Err(_) => {
+                let placeholder = self
+                    .builder
+                    .driver
+                    .placeholder_endpoint_in(ep_type, max_packet_size, interval_ms);
+                self.alloc_failed(Direction::In, ep_type, max_packet_size, placeholder)
+            }
+        }
     }
 
     fn endpoint_in(
@@ -499,13 +1044,23 @@ impl<'a, 'd, D: Driver<'d>> InterfaceAltBuilder<'a, 'd, D> {
         max_packet_size: u16,
         interval_ms: u8,
     ) -> D::EndpointOut {
-        let ep = self
+        match self
             .builder
             .driver
             .alloc_endpoint_out(ep_type, max_packet_size, interval_ms)
-            .expect("alloc_endpoint_out failed");
-
-        ep
+        {
+            Ok(ep) => {
+                self.builder.allocated_endpoints |= endpoint_bit(ep.info().addr);
+                ep
+            }
+            Err(_) => {
+                let placeholder = self
+                    .builder
+                    .driver
+                    .placeholder_endpoint_out(ep_type, max_packet_size, interval_ms);
+                self.alloc_failed(Direction::Out, ep_type, max_packet_size, placeholder)
+            }
+        }
     }
 
     fn endpoint_out(
@@ -557,6 +1112,9 @@ impl<'a, 'd, D: Driver<'d>> InterfaceAltBuilder<'a, 'd, D> {
     ///
     /// Descriptors are written in the order builder functions are called. Note that some
     /// classes care about the order.
+    ///
+    /// `interval_ms` is written to `bInterval` as is, which only means milliseconds at full
+    /// speed. Use [`encode_interval`](crate::descriptor::encode_interval) to compute it.
     pub fn endpoint_interrupt_in(&mut self, max_packet_size: u16, interval_ms: u8) -> D::EndpointIn {
         self.endpoint_in(
             EndpointType::Interrupt,
@@ -569,6 +1127,9 @@ impl<'a, 'd, D: Driver<'d>> InterfaceAltBuilder<'a, 'd, D> {
     }
 
     /// Allocate a INTERRUPT OUT endpoint and write its descriptor.
+    ///
+    /// Descriptors are written in the order builder functions are called. Note that some
+    /// classes care about the order.
     pub fn endpoint_interrupt_out(&mut self, max_packet_size: u16, interval_ms: u8) -> D::EndpointOut {
         self.endpoint_out(
             EndpointType::Interrupt,
@@ -603,6 +1164,9 @@ impl<'a, 'd, D: Driver<'d>> InterfaceAltBuilder<'a, 'd, D> {
     }
 
     /// Allocate a ISOCHRONOUS OUT endpoint and write its descriptor.
+    ///
+    /// Descriptors are written in the order builder functions are called. Note that some
+    /// classes care about the order.
     pub fn endpoint_isochronous_out(
         &mut self,
         max_packet_size: u16,
@@ -9,7 +9,7 @@ use core::task::Poll;
 use embassy_sync::blocking_mutex::CriticalSectionMutex;
 use embassy_sync::waitqueue::WakerRegistration;
 
-use crate::control::{self, InResponse, OutResponse, Recipient, Request, RequestType};
+use crate::control::{self, InResponse, OutResponse, Request};
 use crate::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut};
 use crate::types::InterfaceNumber;
 use crate::{Builder, Handler};
@@ -143,9 +143,7 @@ impl<'d> Handler for Control<'d> {
     }
 
     fn control_out(&mut self, req: control::Request, data: &[u8]) -> Option<OutResponse> {
-        if (req.request_type, req.recipient, req.index)
-            != (RequestType::Class, Recipient::Interface, self.comm_if.0 as u16)
-        {
+        if !req.is_class_interface(self.comm_if) {
             return None;
         }
 
@@ -155,7 +153,7 @@ impl<'d> Handler for Control<'d> {
                 // compatibility.
                 Some(OutResponse::Accepted)
             }
-            REQ_SET_LINE_CODING if data.len() >= 7 => {
+            REQ_SET_LINE_CODING if data.len() == 7 => {
                 let coding = LineCoding {
                     data_rate: u32::from_le_bytes(data[0..4].try_into().unwrap()),
                     stop_bits: data[4].into(),
@@ -190,9 +188,7 @@ impl<'d> Handler for Control<'d> {
     }
 
     fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
-        if (req.request_type, req.recipient, req.index)
-            != (RequestType::Class, Recipient::Interface, self.comm_if.0 as u16)
-        {
+        if !req.is_class_interface(self.comm_if) {
             return None;
         }
 
@@ -263,13 +259,12 @@ impl<'d, D: Driver<'d>> CdcAcmClass<'d, D> {
         let read_ep = alt.endpoint_bulk_out(max_packet_size);
         let write_ep = alt.endpoint_bulk_in(max_packet_size);
 
-        drop(func);
-
         let control = state.control.write(Control {
             shared: &state.shared,
             comm_if,
         });
-        builder.handler(control);
+        func.handler(control);
+        drop(func);
 
         let control_shared = &state.shared;
 
@@ -17,7 +17,7 @@
 use core::mem::{size_of, MaybeUninit};
 use core::ptr::{addr_of, copy_nonoverlapping};
 
-use crate::control::{self, InResponse, OutResponse, Recipient, Request, RequestType};
+use crate::control::{self, InResponse, OutResponse, Request};
 use crate::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut};
 use crate::types::{InterfaceNumber, StringIndex};
 use crate::{Builder, Handler};
@@ -165,9 +165,7 @@ impl<'d> Handler for Control<'d> {
     }
 
     fn control_out(&mut self, req: control::Request, _data: &[u8]) -> Option<OutResponse> {
-        if (req.request_type, req.recipient, req.index)
-            != (RequestType::Class, Recipient::Interface, self.comm_if.0 as u16)
-        {
+        if !req.is_class_interface(self.comm_if) {
             return None;
         }
 
@@ -186,9 +184,7 @@ impl<'d> Handler for Control<'d> {
     }
 
     fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
-        if (req.request_type, req.recipient, req.index)
-            != (RequestType::Class, Recipient::Interface, self.comm_if.0 as u16)
-        {
+        if !req.is_class_interface(self.comm_if) {
             return None;
         }
 
@@ -323,8 +319,6 @@ impl<'d, D: Driver<'d>> CdcNcmClass<'d, D> {
         let read_ep = alt.endpoint_bulk_out(max_packet_size);
         let write_ep = alt.endpoint_bulk_in(max_packet_size);
 
-        drop(func);
-
         let control = state.control.write(Control {
             mac_addr_string,
             shared: &state.shared,
@@ -332,7 +326,8 @@ impl<'d, D: Driver<'d>> CdcNcmClass<'d, D> {
             comm_if,
             data_if,
         });
-        builder.handler(control);
+        func.handler(control);
+        drop(func);
 
         CdcNcmClass {
             _comm_if: comm_if,
@@ -68,10 +68,9 @@ impl<'d, D: Driver<'d>> CmsisDapV2Class<'d, D> {
         } else {
             None
         };
+        function.handler(state.control.write(Control { iface_string }));
         drop(function);
 
-        builder.handler(state.control.write(Control { iface_string }));
-
         CmsisDapV2Class {
             read_ep,
             write_ep,
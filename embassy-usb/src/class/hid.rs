@@ -1,8 +1,15 @@
 //! USB HID (Human Interface Device) class implementation.
 
+use core::cell::RefCell;
+use core::future::poll_fn;
 use core::mem::MaybeUninit;
 use core::ops::Range;
 use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::Poll;
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::CriticalSectionMutex;
+use embassy_sync::waitqueue::WakerRegistration;
 
 #[cfg(feature = "usbd-hid")]
 use ssmarshal::serialize;
@@ -22,7 +29,6 @@ const USB_PROTOCOL_NONE: u8 = 0x00;
 const HID_DESC_DESCTYPE_HID: u8 = 0x21;
 const HID_DESC_DESCTYPE_HID_REPORT: u8 = 0x22;
 const HID_DESC_SPEC_1_10: [u8; 2] = [0x10, 0x01];
-const HID_DESC_COUNTRY_UNSPEC: u8 = 0x00;
 
 const HID_REQ_SET_IDLE: u8 = 0x0a;
 const HID_REQ_GET_IDLE: u8 = 0x02;
@@ -34,6 +40,11 @@ const HID_REQ_SET_PROTOCOL: u8 = 0x0b;
 /// Configuration for the HID class.
 pub struct Config<'d> {
     /// HID report descriptor.
+    ///
+    /// This doesn't have to be a constant: the descriptor can be picked among several, or assembled
+    /// into a buffer at boot, for example depending on which peripherals are present. It must be
+    /// chosen before the class is created, because its length is written into the HID descriptor
+    /// in the configuration descriptor, which the host reads during enumeration.
     pub report_descriptor: &'d [u8],
 
     /// Handler for control requests.
@@ -48,6 +59,12 @@ pub struct Config<'d> {
 
     /// Max packet size for both the IN and OUT endpoints.
     pub max_packet_size: u16,
+
+    /// Country code (bCountryCode) of localized hardware, such as a keyboard with a national
+    /// layout. See section 6.2.1 of the HID 1.11 specification for the codes.
+    ///
+    /// Use 0 if the hardware is not localized.
+    pub country_code: u8,
 }
 
 /// Report ID
@@ -77,6 +94,14 @@ impl ReportId {
 pub struct State<'d> {
     control: MaybeUninit<Control<'d>>,
     out_report_offset: AtomicUsize,
+    control_report: CriticalSectionMutex<RefCell<ControlReport<'d>>>,
+}
+
+/// Output report received with SET_REPORT, waiting for [`HidReader::read_output_report`].
+struct ControlReport<'d> {
+    buf: Option<&'d mut [u8]>,
+    len: Option<usize>,
+    waker: WakerRegistration,
 }
 
 impl<'d> Default for State<'d> {
@@ -91,6 +116,30 @@ impl<'d> State<'d> {
         State {
             control: MaybeUninit::uninit(),
             out_report_offset: AtomicUsize::new(0),
+            control_report: CriticalSectionMutex::new(RefCell::new(ControlReport {
+                buf: None,
+                len: None,
+                waker: WakerRegistration::new(),
+            })),
+        }
+    }
+
+    /// Create a new `State` that passes output reports received over the control pipe to
+    /// [`HidReader::read_output_report`].
+    ///
+    /// Output reports sent with SET_REPORT are copied into `buf` until they are read, instead of
+    /// being passed to [`RequestHandler::set_report()`]. Only the latest unread report is kept,
+    /// and reports longer than `buf` are rejected. This is only useful with a
+    /// [`HidReaderWriter`], because a [`HidWriter`] has no reader.
+    pub const fn with_output_report_buffer(buf: &'d mut [u8]) -> Self {
+        State {
+            control: MaybeUninit::uninit(),
+            out_report_offset: AtomicUsize::new(0),
+            control_report: CriticalSectionMutex::new(RefCell::new(ControlReport {
+                buf: Some(buf),
+                len: None,
+                waker: WakerRegistration::new(),
+            })),
         }
     }
 }
@@ -106,7 +155,12 @@ fn build<'d, D: Driver<'d>>(
     state: &'d mut State<'d>,
     config: Config<'d>,
     with_out_endpoint: bool,
-) -> (Option<D::EndpointOut>, D::EndpointIn, &'d AtomicUsize) {
+) -> (
+    Option<D::EndpointOut>,
+    D::EndpointIn,
+    &'d AtomicUsize,
+    &'d CriticalSectionMutex<RefCell<ControlReport<'d>>>,
+) {
     let len = config.report_descriptor.len();
 
     let mut func = builder.function(USB_CLASS_HID, USB_SUBCLASS_NONE, USB_PROTOCOL_NONE);
@@ -121,8 +175,8 @@ fn build<'d, D: Driver<'d>>(
             // HID Class spec version
             HID_DESC_SPEC_1_10[0],
             HID_DESC_SPEC_1_10[1],
-            // Country code not supported
-            HID_DESC_COUNTRY_UNSPEC,
+            // Country code
+            config.country_code,
             // Number of following descriptors
             1,
             // We have a HID report descriptor the host should read
@@ -140,17 +194,18 @@ fn build<'d, D: Driver<'d>>(
         None
     };
 
-    drop(func);
-
     let control = state.control.write(Control::new(
         if_num,
         config.report_descriptor,
         config.request_handler,
+        config.country_code,
         &state.out_report_offset,
+        &state.control_report,
     ));
-    builder.handler(control);
+    func.handler(control);
+    drop(func);
 
-    (ep_out, ep_in, &state.out_report_offset)
+    (ep_out, ep_in, &state.out_report_offset, &state.control_report)
 }
 
 impl<'d, D: Driver<'d>, const READ_N: usize, const WRITE_N: usize> HidReaderWriter<'d, D, READ_N, WRITE_N> {
@@ -160,12 +215,13 @@ impl<'d, D: Driver<'d>, const READ_N: usize, const WRITE_N: usize> HidReaderWrit
     /// HID reports, consider using [`HidWriter::new`] instead, which allocates an IN endpoint only.
     ///
     pub fn new(builder: &mut Builder<'d, D>, state: &'d mut State<'d>, config: Config<'d>) -> Self {
-        let (ep_out, ep_in, offset) = build(builder, state, config, true);
+        let (ep_out, ep_in, offset, control_report) = build(builder, state, config, true);
 
         Self {
             reader: HidReader {
                 ep_out: ep_out.unwrap(),
                 offset,
+                control_report,
             },
             writer: HidWriter { ep_in },
         }
@@ -199,6 +255,13 @@ impl<'d, D: Driver<'d>, const READ_N: usize, const WRITE_N: usize> HidReaderWrit
     pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, ReadError> {
         self.reader.read(buf).await
     }
+
+    /// Reads an output report from either the Interrupt Out pipe or the control pipe.
+    ///
+    /// See [`HidReader::read_output_report`].
+    pub async fn read_output_report<'b>(&mut self, buf: &'b mut [u8]) -> Result<&'b [u8], ReadError> {
+        self.reader.read_output_report(buf).await
+    }
 }
 
 /// USB HID writer.
@@ -214,6 +277,7 @@ pub struct HidWriter<'d, D: Driver<'d>, const N: usize> {
 pub struct HidReader<'d, D: Driver<'d>, const N: usize> {
     ep_out: D::EndpointOut,
     offset: &'d AtomicUsize,
+    control_report: &'d CriticalSectionMutex<RefCell<ControlReport<'d>>>,
 }
 
 /// Error when reading a HID report.
@@ -242,14 +306,20 @@ impl<'d, D: Driver<'d>, const N: usize> HidWriter<'d, D, N> {
     /// Creates a new HidWriter.
     ///
     /// This will allocate one IN endpoint only, so the host won't be able to send
-    /// reports to us. If you need that, consider using [`HidReaderWriter::new`] instead.
+    /// reports to us over an interrupt pipe. If you need that, consider using
+    /// [`HidReaderWriter::new`] instead.
+    ///
+    /// Without an OUT endpoint, the host sends output reports (such as keyboard LED state)
+    /// with SET_REPORT over the control pipe. These are passed to
+    /// [`RequestHandler::set_report()`] with a [`ReportId::Out`] id, which is the same
+    /// callback [`HidReader::run`] delivers Interrupt Out reports to.
     ///
     /// poll_ms configures how frequently the host should poll for reading/writing
     /// HID reports. A lower value means better throughput & latency, at the expense
     /// of CPU on the device & bandwidth on the bus. A value of 10 is reasonable for
     /// high performance uses, and a value of 255 is good for best-effort usecases.
     pub fn new(builder: &mut Builder<'d, D>, state: &'d mut State<'d>, config: Config<'d>) -> Self {
-        let (ep_out, ep_in, _offset) = build(builder, state, config, false);
+        let (ep_out, ep_in, _offset, _control_report) = build(builder, state, config, false);
 
         assert!(ep_out.is_none());
 
@@ -275,18 +345,91 @@ impl<'d, D: Driver<'d>, const N: usize> HidWriter<'d, D, N> {
     pub async fn write(&mut self, report: &[u8]) -> Result<(), EndpointError> {
         assert!(report.len() <= N);
 
-        let max_packet_size = usize::from(self.ep_in.info().max_packet_size);
-        let zlp_needed = report.len() < N && (report.len() % max_packet_size == 0);
-        for chunk in report.chunks(max_packet_size) {
-            self.ep_in.write(chunk).await?;
+        // A full-length report ends the transfer by itself.
+        self.ep_in.write_transfer(report, report.len() < N).await
+    }
+}
+
+/// USB HID writer that batches several input reports into one interrupt transfer.
+///
+/// Reports are queued in an internal `N` byte buffer and sent as a single
+/// (possibly multi-packet) transfer once `max_reports` reports are queued, or when
+/// the next report would not fit. This trades latency for less per-transfer overhead.
+///
+/// There is no timer in this crate, so a time-based flush is up to the caller: call
+/// [`HidBatchWriter::flush`] periodically, for example from a `select` with a timer.
+///
+/// The host sees the queued reports concatenated into one transfer, so only use this
+/// when the host side knows how to split them up again.
+pub struct HidBatchWriter<'d, D: Driver<'d>, const N: usize> {
+    writer: HidWriter<'d, D, N>,
+    buf: [u8; N],
+    len: usize,
+    count: usize,
+    max_reports: usize,
+}
+
+impl<'d, D: Driver<'d>, const N: usize> HidBatchWriter<'d, D, N> {
+    /// Creates a new batching writer that flushes after `max_reports` reports.
+    pub fn new(writer: HidWriter<'d, D, N>, max_reports: usize) -> Self {
+        assert!(max_reports != 0);
+        Self {
+            writer,
+            buf: [0; N],
+            len: 0,
+            count: 0,
+            max_reports,
+        }
+    }
+
+    /// Waits for the interrupt in endpoint to be enabled.
+    pub async fn ready(&mut self) {
+        self.writer.ready().await;
+    }
+
+    /// Queues `report`, flushing the batch first if it would not fit and afterwards
+    /// if it is now full.
+    pub async fn write(&mut self, report: &[u8]) -> Result<(), EndpointError> {
+        assert!(report.len() <= N);
+
+        if self.len + report.len() > N {
+            self.flush().await?;
         }
 
-        if zlp_needed {
-            self.ep_in.write(&[]).await?;
+        self.buf[self.len..self.len + report.len()].copy_from_slice(report);
+        self.len += report.len();
+        self.count += 1;
+
+        if self.count >= self.max_reports {
+            self.flush().await?;
         }
 
         Ok(())
     }
+
+    /// Sends all queued reports as one transfer. Does nothing if the batch is empty.
+    ///
+    /// The batch is discarded even if the write fails.
+    pub async fn flush(&mut self) -> Result<(), EndpointError> {
+        if self.len == 0 {
+            return Ok(());
+        }
+
+        let len = self.len;
+        self.len = 0;
+        self.count = 0;
+        self.writer.write(&self.buf[..len]).await
+    }
+
+    /// Returns the number of reports waiting to be flushed.
+    pub fn pending(&self) -> usize {
+        self.count
+    }
+
+    /// Returns the underlying writer. Reports that were not flushed are dropped.
+    pub fn into_inner(self) -> HidWriter<'d, D, N> {
+        self.writer
+    }
 }
 
 impl<'d, D: Driver<'d>, const N: usize> HidReader<'d, D, N> {
@@ -334,43 +477,82 @@ impl<'d, D: Driver<'d>, const N: usize> HidReader<'d, D, N> {
     /// `read()`. If the dropped future used the same `buf`, then `buf` will
     /// contain the full report.
     pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, ReadError> {
-        assert!(N != 0);
-        assert!(buf.len() >= N);
+        read_report::<D, N>(&mut self.ep_out, self.offset, buf).await
+    }
 
-        // Read packets from the endpoint
-        let max_packet_size = usize::from(self.ep_out.info().max_packet_size);
-        let starting_offset = self.offset.load(Ordering::Acquire);
-        let mut total = starting_offset;
-        loop {
-            for chunk in buf[starting_offset..N].chunks_mut(max_packet_size) {
-                match self.ep_out.read(chunk).await {
-                    Ok(size) => {
-                        total += size;
-                        if size < max_packet_size || total == N {
-                            self.offset.store(0, Ordering::Release);
-                            break;
-                        }
-                        self.offset.store(total, Ordering::Release);
-                    }
-                    Err(err) => {
-                        self.offset.store(0, Ordering::Release);
-                        return Err(err.into());
-                    }
+    /// Reads an output report from either the Interrupt Out pipe or the control pipe.
+    ///
+    /// This behaves like [`HidReader::read`], but also returns output reports the host sent
+    /// with SET_REPORT, if the [`State`] was created with [`State::with_output_report_buffer`].
+    /// Returns the received report.
+    pub async fn read_output_report<'b>(&mut self, buf: &'b mut [u8]) -> Result<&'b [u8], ReadError> {
+        let control_report = poll_fn(|cx| {
+            self.control_report.lock(|report| {
+                let mut report = report.borrow_mut();
+                if report.len.is_some() {
+                    Poll::Ready(())
+                } else {
+                    report.waker.register(cx.waker());
+                    Poll::Pending
                 }
-            }
+            })
+        });
+
+        match select(control_report, read_report::<D, N>(&mut self.ep_out, self.offset, buf)).await {
+            Either::First(()) => self.control_report.lock(|report| {
+                let mut report = report.borrow_mut();
+                let len = report.len.take().unwrap();
+                let data = &report.buf.as_deref().unwrap()[..len];
+                let buf = buf.get_mut(..len).ok_or(ReadError::BufferOverflow)?;
+                buf.copy_from_slice(data);
+                Ok(&*buf)
+            }),
+            Either::Second(len) => Ok(&buf[..len?]),
+        }
+    }
+}
 
-            // Some hosts may send ZLPs even when not required by the HID spec, so we'll loop as long as total == 0.
-            if total > 0 {
-                break;
+async fn read_report<'d, D: Driver<'d>, const N: usize>(
+    ep_out: &mut D::EndpointOut,
+    offset: &AtomicUsize,
+    buf: &mut [u8],
+) -> Result<usize, ReadError> {
+    assert!(N != 0);
+    assert!(buf.len() >= N);
+
+    // Read packets from the endpoint
+    let max_packet_size = usize::from(ep_out.info().max_packet_size);
+    let starting_offset = offset.load(Ordering::Acquire);
+    let mut total = starting_offset;
+    loop {
+        for chunk in buf[starting_offset..N].chunks_mut(max_packet_size) {
+            match ep_out.read(chunk).await {
+                Ok(size) => {
+                    total += size;
+                    if size < max_packet_size || total == N {
+                        offset.store(0, Ordering::Release);
+                        break;
+                    }
+                    offset.store(total, Ordering::Release);
+                }
+                Err(err) => {
+                    offset.store(0, Ordering::Release);
+                    return Err(err.into());
+                }
             }
         }
 
-        if starting_offset > 0 {
-            Err(ReadError::Sync(starting_offset..total))
-        } else {
-            Ok(total)
+        // Some hosts may send ZLPs even when not required by the HID spec, so we'll loop as long as total == 0.
+        if total > 0 {
+            break;
         }
     }
+
+    if starting_offset > 0 {
+        Err(ReadError::Sync(starting_offset..total))
+    } else {
+        Ok(total)
+    }
 }
 
 /// Handler for HID-related control requests.
@@ -413,6 +595,7 @@ struct Control<'d> {
     report_descriptor: &'d [u8],
     request_handler: Option<&'d mut dyn RequestHandler>,
     out_report_offset: &'d AtomicUsize,
+    control_report: &'d CriticalSectionMutex<RefCell<ControlReport<'d>>>,
     hid_descriptor: [u8; 9],
 }
 
@@ -421,13 +604,16 @@ impl<'d> Control<'d> {
         if_num: InterfaceNumber,
         report_descriptor: &'d [u8],
         request_handler: Option<&'d mut dyn RequestHandler>,
+        country_code: u8,
         out_report_offset: &'d AtomicUsize,
+        control_report: &'d CriticalSectionMutex<RefCell<ControlReport<'d>>>,
     ) -> Self {
         Control {
             if_num,
             report_descriptor,
             request_handler,
             out_report_offset,
+            control_report,
             hid_descriptor: [
                 // Length of buf inclusive of size prefix
                 9,
@@ -436,8 +622,8 @@ impl<'d> Control<'d> {
                 // HID Class spec version
                 HID_DESC_SPEC_1_10[0],
                 HID_DESC_SPEC_1_10[1],
-                // Country code not supported
-                HID_DESC_COUNTRY_UNSPEC,
+                // Country code
+                country_code,
                 // Number of following descriptors
                 1,
                 // We have a HID report descriptor the host should read
@@ -450,15 +636,32 @@ impl<'d> Control<'d> {
     }
 }
 
+impl Control<'_> {
+    /// Keeps an output report for [`HidReader::read_output_report`], if there's a buffer for it.
+    fn store_output_report(&mut self, data: &[u8]) -> Option<OutResponse> {
+        self.control_report.lock(|report| {
+            let mut report = report.borrow_mut();
+            let report = &mut *report;
+            let buf = report.buf.as_deref_mut()?;
+            let Some(buf) = buf.get_mut(..data.len()) else {
+                return Some(OutResponse::Rejected);
+            };
+            buf.copy_from_slice(data);
+            report.len = Some(data.len());
+            report.waker.wake();
+            Some(OutResponse::Accepted)
+        })
+    }
+}
+
 impl<'d> Handler for Control<'d> {
     fn reset(&mut self) {
         self.out_report_offset.store(0, Ordering::Release);
+        self.control_report.lock(|report| report.borrow_mut().len = None);
     }
 
     fn control_out(&mut self, req: Request, data: &[u8]) -> Option<OutResponse> {
-        if (req.request_type, req.recipient, req.index)
-            != (RequestType::Class, Recipient::Interface, self.if_num.0 as u16)
-        {
+        if !req.is_class_interface(self.if_num) {
             return None;
         }
 
@@ -477,10 +680,17 @@ impl<'d> Handler for Control<'d> {
                 }
                 Some(OutResponse::Accepted)
             }
-            HID_REQ_SET_REPORT => match (ReportId::try_from(req.value), self.request_handler.as_mut()) {
-                (Ok(id), Some(handler)) => Some(handler.set_report(id, data)),
-                _ => Some(OutResponse::Rejected),
-            },
+            HID_REQ_SET_REPORT => {
+                if let (Ok(ReportId::Out(_)), Some(res)) =
+                    (ReportId::try_from(req.value), self.store_output_report(data))
+                {
+                    return Some(res);
+                }
+                match (ReportId::try_from(req.value), self.request_handler.as_mut()) {
+                    (Ok(id), Some(handler)) => Some(handler.set_report(id, data)),
+                    _ => Some(OutResponse::Rejected),
+                }
+            }
             HID_REQ_SET_PROTOCOL => {
                 if req.value == 1 {
                     Some(OutResponse::Accepted)
@@ -506,7 +716,8 @@ impl<'d> Handler for Control<'d> {
                     _ => Some(InResponse::Rejected),
                 },
 
-                _ => Some(InResponse::Rejected),
+                // Let the stack answer the other standard requests, such as GET_STATUS.
+                _ => None,
             },
             (RequestType::Class, Recipient::Interface) => {
                 trace!("HID control_in {:?}", req);
@@ -13,7 +13,6 @@ const MIDI_OUT_JACK_SUBTYPE: u8 = 0x03;
 const EMBEDDED: u8 = 0x01;
 const EXTERNAL: u8 = 0x02;
 const CS_INTERFACE: u8 = 0x24;
-const CS_ENDPOINT: u8 = 0x25;
 const HEADER_SUBTYPE: u8 = 0x01;
 const MS_HEADER_SUBTYPE: u8 = 0x01;
 const MS_GENERAL: u8 = 0x01;
@@ -130,14 +129,14 @@ impl<'d, D: Driver<'d>> MidiClass<'d, D> {
             endpoint_data[2 + i as usize] = in_jack_id_emb(i);
         }
         let read_ep = alt.endpoint_bulk_out(max_packet_size);
-        alt.descriptor(CS_ENDPOINT, &endpoint_data[0..2 + n_out_jacks as usize]);
+        alt.class_endpoint_descriptor(&endpoint_data[0..2 + n_out_jacks as usize]);
 
         endpoint_data[1] = n_in_jacks;
         for i in 0..n_in_jacks {
             endpoint_data[2 + i as usize] = out_jack_id_emb(i);
         }
         let write_ep = alt.endpoint_bulk_in(max_packet_size);
-        alt.descriptor(CS_ENDPOINT, &endpoint_data[0..2 + n_in_jacks as usize]);
+        alt.class_endpoint_descriptor(&endpoint_data[0..2 + n_in_jacks as usize]);
 
         MidiClass { read_ep, write_ep }
     }
@@ -4,5 +4,7 @@ pub mod cdc_ncm;
 pub mod cmsis_dap_v2;
 pub mod hid;
 pub mod midi;
+pub mod rndis;
 pub mod uac1;
+pub mod uvc;
 pub mod web_usb;
@@ -0,0 +1,665 @@
+//! RNDIS class implementation, aka Ethernet over USB for Windows hosts.
+//!
+//! Remote NDIS encapsulates the NDIS control protocol and Ethernet frames in messages sent over a
+//! CDC-style control interface and a pair of bulk endpoints.
+//!
+//! # Compatibility
+//!
+//! Windows: Supported out of the box since Windows 7. The interfaces use the "Wireless Controller /
+//! RF / RNDIS" class codes, which Windows binds to its in-box RNDIS driver without an INF file.
+//!
+//! Linux: Supported by the `rndis_host` driver.
+//!
+//! macOS: NOT supported. Use [`cdc_ncm`](super::cdc_ncm) instead.
+//!
+//! The class writes an Interface Association Descriptor, so the device should use the
+//! Miscellaneous device class (`device_class = 0xEF`, `device_sub_class = 0x02`,
+//! `device_protocol = 0x01`).
+
+use core::cell::{Cell, RefCell};
+use core::future::poll_fn;
+use core::mem::{self, MaybeUninit};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::CriticalSectionMutex;
+use embassy_sync::waitqueue::WakerRegistration;
+
+use crate::control::{self, InResponse, OutResponse, Request};
+use crate::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut};
+use crate::types::InterfaceNumber;
+use crate::{Builder, Handler};
+
+pub mod embassy_net;
+
+const USB_CLASS_WIRELESS_CONTROLLER: u8 = 0xe0;
+const WIRELESS_SUBCLASS_RF: u8 = 0x01;
+const RF_PROTOCOL_RNDIS: u8 = 0x03;
+
+const USB_CLASS_CDC_DATA: u8 = 0x0a;
+
+const CS_INTERFACE: u8 = 0x24;
+const CDC_TYPE_HEADER: u8 = 0x00;
+const CDC_TYPE_CALL_MANAGEMENT: u8 = 0x01;
+const CDC_TYPE_ACM: u8 = 0x02;
+const CDC_TYPE_UNION: u8 = 0x06;
+
+const REQ_SEND_ENCAPSULATED_COMMAND: u8 = 0x00;
+const REQ_GET_ENCAPSULATED_RESPONSE: u8 = 0x01;
+
+const MSG_PACKET: u32 = 0x0000_0001;
+const MSG_INITIALIZE: u32 = 0x0000_0002;
+const MSG_HALT: u32 = 0x0000_0003;
+const MSG_QUERY: u32 = 0x0000_0004;
+const MSG_SET: u32 = 0x0000_0005;
+const MSG_RESET: u32 = 0x0000_0006;
+const MSG_KEEPALIVE: u32 = 0x0000_0008;
+const MSG_COMPLETION: u32 = 0x8000_0000;
+
+const STATUS_SUCCESS: u32 = 0x0000_0000;
+const STATUS_NOT_SUPPORTED: u32 = 0xc000_00bb;
+const STATUS_INVALID_DATA: u32 = 0xc001_0015;
+
+const OID_GEN_SUPPORTED_LIST: u32 = 0x0001_0101;
+const OID_GEN_HARDWARE_STATUS: u32 = 0x0001_0102;
+const OID_GEN_MEDIA_SUPPORTED: u32 = 0x0001_0103;
+const OID_GEN_MEDIA_IN_USE: u32 = 0x0001_0104;
+const OID_GEN_MAXIMUM_FRAME_SIZE: u32 = 0x0001_0106;
+const OID_GEN_LINK_SPEED: u32 = 0x0001_0107;
+const OID_GEN_TRANSMIT_BLOCK_SIZE: u32 = 0x0001_010a;
+const OID_GEN_RECEIVE_BLOCK_SIZE: u32 = 0x0001_010b;
+const OID_GEN_VENDOR_ID: u32 = 0x0001_010c;
+const OID_GEN_VENDOR_DESCRIPTION: u32 = 0x0001_010d;
+const OID_GEN_CURRENT_PACKET_FILTER: u32 = 0x0001_010e;
+const OID_GEN_MAXIMUM_TOTAL_SIZE: u32 = 0x0001_0111;
+const OID_GEN_MEDIA_CONNECT_STATUS: u32 = 0x0001_0114;
+const OID_GEN_PHYSICAL_MEDIUM: u32 = 0x0001_0202;
+const OID_GEN_RNDIS_CONFIG_PARAMETER: u32 = 0x0001_021b;
+const OID_GEN_XMIT_OK: u32 = 0x0002_0101;
+const OID_GEN_RCV_OK: u32 = 0x0002_0102;
+const OID_GEN_XMIT_ERROR: u32 = 0x0002_0103;
+const OID_GEN_RCV_ERROR: u32 = 0x0002_0104;
+const OID_GEN_RCV_NO_BUFFER: u32 = 0x0002_0105;
+const OID_802_3_PERMANENT_ADDRESS: u32 = 0x0101_0101;
+const OID_802_3_CURRENT_ADDRESS: u32 = 0x0101_0102;
+const OID_802_3_MULTICAST_LIST: u32 = 0x0101_0103;
+const OID_802_3_MAXIMUM_LIST_SIZE: u32 = 0x0101_0104;
+
+const SUPPORTED_OIDS: [u32; 22] = [
+    OID_GEN_SUPPORTED_LIST,
+    OID_GEN_HARDWARE_STATUS,
+    OID_GEN_MEDIA_SUPPORTED,
+    OID_GEN_MEDIA_IN_USE,
+    OID_GEN_MAXIMUM_FRAME_SIZE,
+    OID_GEN_LINK_SPEED,
+    OID_GEN_TRANSMIT_BLOCK_SIZE,
+    OID_GEN_RECEIVE_BLOCK_SIZE,
+    OID_GEN_VENDOR_ID,
+    OID_GEN_VENDOR_DESCRIPTION,
+    OID_GEN_CURRENT_PACKET_FILTER,
+    OID_GEN_MAXIMUM_TOTAL_SIZE,
+    OID_GEN_MEDIA_CONNECT_STATUS,
+    OID_GEN_PHYSICAL_MEDIUM,
+    OID_GEN_XMIT_OK,
+    OID_GEN_RCV_OK,
+    OID_GEN_XMIT_ERROR,
+    OID_GEN_RCV_ERROR,
+    OID_GEN_RCV_NO_BUFFER,
+    OID_802_3_PERMANENT_ADDRESS,
+    OID_802_3_CURRENT_ADDRESS,
+    OID_802_3_MAXIMUM_LIST_SIZE,
+];
+
+const VENDOR_DESCRIPTION: &[u8] = b"embassy-usb RNDIS\0";
+
+/// Ethernet header plus the maximum payload (MTU).
+const MAX_FRAME_SIZE: usize = 1514;
+/// Size of a `REMOTE_NDIS_PACKET_MSG` header.
+const PACKET_HEADER_LEN: usize = 44;
+/// Largest transfer we accept from the host, announced in the INITIALIZE completion.
+const MAX_TRANSFER_SIZE: usize = 2048;
+/// Largest encapsulated response, which is the OID_GEN_SUPPORTED_LIST query completion.
+const RESPONSE_MAX_SIZE: usize = 24 + SUPPORTED_OIDS.len() * 4;
+
+fn read_u32(buf: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(buf.get(offset..offset + 4)?.try_into().unwrap()))
+}
+
+/// Internal state for the RNDIS class.
+pub struct State<'a> {
+    control: MaybeUninit<Control<'a>>,
+    shared: ControlShared,
+}
+
+impl Default for State<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl State<'_> {
+    /// Create a new `State`.
+    pub const fn new() -> Self {
+        Self {
+            control: MaybeUninit::uninit(),
+            shared: ControlShared::new(),
+        }
+    }
+}
+
+/// Shared data between Control and `RndisClass`
+struct ControlShared {
+    mac_addr: CriticalSectionMutex<Cell<[u8; 6]>>,
+    packet_filter: CriticalSectionMutex<Cell<u32>>,
+    response_available: AtomicBool,
+
+    waker: RefCell<WakerRegistration>,
+}
+
+impl Default for ControlShared {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ControlShared {
+    const fn new() -> Self {
+        ControlShared {
+            mac_addr: CriticalSectionMutex::new(Cell::new([0; 6])),
+            packet_filter: CriticalSectionMutex::new(Cell::new(0)),
+            response_available: AtomicBool::new(false),
+            waker: RefCell::new(WakerRegistration::new()),
+        }
+    }
+
+    fn set_packet_filter(&self, filter: u32) {
+        self.packet_filter.lock(|x| x.set(filter));
+        self.waker.borrow_mut().wake();
+    }
+
+    fn set_response_available(&self) {
+        self.response_available.store(true, Ordering::Relaxed);
+        self.waker.borrow_mut().wake();
+    }
+
+    fn poll_response_available(&self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.response_available.swap(false, Ordering::Relaxed) {
+            Poll::Ready(())
+        } else {
+            self.waker.borrow_mut().register(cx.waker());
+            Poll::Pending
+        }
+    }
+
+    fn poll_connected(&self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.packet_filter.lock(|x| x.get()) != 0 {
+            Poll::Ready(())
+        } else {
+            self.waker.borrow_mut().register(cx.waker());
+            Poll::Pending
+        }
+    }
+}
+
+struct Control<'a> {
+    comm_if: InterfaceNumber,
+    shared: &'a ControlShared,
+    link_speed: u32,
+    response: [u8; RESPONSE_MAX_SIZE],
+    response_len: usize,
+}
+
+impl Control<'_> {
+    /// Queue an encapsulated response and tell the host it can fetch it.
+    fn respond(&mut self, msg_type: u32, fields: &[u32], payload: &[u8]) {
+        let len = 8 + fields.len() * 4 + payload.len();
+        let buf = &mut self.response;
+        buf[0..4].copy_from_slice(&(msg_type | MSG_COMPLETION).to_le_bytes());
+        buf[4..8].copy_from_slice(&(len as u32).to_le_bytes());
+        for (i, field) in fields.iter().enumerate() {
+            buf[8 + i * 4..][..4].copy_from_slice(&field.to_le_bytes());
+        }
+        buf[8 + fields.len() * 4..len].copy_from_slice(payload);
+
+        self.response_len = len;
+        self.shared.set_response_available();
+    }
+
+    fn handle_message(&mut self, msg: &[u8]) {
+        let (Some(msg_type), Some(request_id)) = (read_u32(msg, 0), read_u32(msg, 8)) else {
+            warn!("rndis: received too short message");
+            return;
+        };
+
+        match msg_type {
+            MSG_INITIALIZE => {
+                info!("rndis: initialize");
+                self.respond(
+                    msg_type,
+                    &[
+                        request_id,
+                        STATUS_SUCCESS,
+                        1,                        // MajorVersion
+                        0,                        // MinorVersion
+                        1,                        // DeviceFlags = RNDIS_DF_CONNECTIONLESS
+                        0,                        // Medium = 802.3
+                        1,                        // MaxPacketsPerTransfer
+                        MAX_TRANSFER_SIZE as u32, // MaxTransferSize
+                        0,                        // PacketAlignmentFactor
+                        0,                        // AFListOffset
+                        0,                        // AFListSize
+                    ],
+                    &[],
+                );
+            }
+            MSG_HALT => {
+                info!("rndis: halt");
+                self.shared.set_packet_filter(0);
+            }
+            MSG_QUERY => {
+                let oid = read_u32(msg, 12).unwrap_or(0);
+                let mut info = [0; SUPPORTED_OIDS.len() * 4];
+                match self.query(oid, &mut info) {
+                    Some(n) => self.respond(msg_type, &[request_id, STATUS_SUCCESS, n as u32, 16], &info[..n]),
+                    None => {
+                        debug!("rndis: unsupported query oid {:08x}", oid);
+                        self.respond(msg_type, &[request_id, STATUS_NOT_SUPPORTED, 0, 0], &[]);
+                    }
+                }
+            }
+            MSG_SET => {
+                let status = self.set(msg);
+                self.respond(msg_type, &[request_id, status], &[]);
+            }
+            MSG_RESET => {
+                info!("rndis: reset");
+                self.shared.set_packet_filter(0);
+                self.respond(msg_type, &[STATUS_SUCCESS, 1], &[]); // AddressingReset
+            }
+            MSG_KEEPALIVE => self.respond(msg_type, &[request_id, STATUS_SUCCESS], &[]),
+            _ => warn!("rndis: unknown message type {:08x}", msg_type),
+        }
+    }
+
+    fn query(&self, oid: u32, buf: &mut [u8]) -> Option<usize> {
+        let value = match oid {
+            OID_GEN_SUPPORTED_LIST => {
+                for (i, oid) in SUPPORTED_OIDS.iter().enumerate() {
+                    buf[i * 4..][..4].copy_from_slice(&oid.to_le_bytes());
+                }
+                return Some(SUPPORTED_OIDS.len() * 4);
+            }
+            OID_GEN_VENDOR_DESCRIPTION => {
+                buf[..VENDOR_DESCRIPTION.len()].copy_from_slice(VENDOR_DESCRIPTION);
+                return Some(VENDOR_DESCRIPTION.len());
+            }
+            OID_802_3_PERMANENT_ADDRESS | OID_802_3_CURRENT_ADDRESS => {
+                buf[..6].copy_from_slice(&self.shared.mac_addr.lock(|x| x.get()));
+                return Some(6);
+            }
+            OID_GEN_HARDWARE_STATUS => 0,                        // NdisHardwareStatusReady
+            OID_GEN_MEDIA_SUPPORTED | OID_GEN_MEDIA_IN_USE => 0, // NdisMedium802_3
+            OID_GEN_PHYSICAL_MEDIUM => 0,                        // NdisPhysicalMediumUnspecified
+            OID_GEN_MAXIMUM_FRAME_SIZE => (MAX_FRAME_SIZE - 14) as u32,
+            OID_GEN_LINK_SPEED => self.link_speed,
+            OID_GEN_TRANSMIT_BLOCK_SIZE | OID_GEN_RECEIVE_BLOCK_SIZE => MAX_FRAME_SIZE as u32,
+            OID_GEN_VENDOR_ID => 0x00ff_ffff,
+            OID_GEN_CURRENT_PACKET_FILTER => self.shared.packet_filter.lock(|x| x.get()),
+            OID_GEN_MAXIMUM_TOTAL_SIZE => (MAX_FRAME_SIZE + PACKET_HEADER_LEN) as u32,
+            OID_GEN_MEDIA_CONNECT_STATUS => 0, // NdisMediaStateConnected
+            OID_802_3_MAXIMUM_LIST_SIZE => 1,
+            // Statistics aren't tracked.
+            OID_GEN_XMIT_OK | OID_GEN_RCV_OK | OID_GEN_XMIT_ERROR | OID_GEN_RCV_ERROR | OID_GEN_RCV_NO_BUFFER => 0,
+            _ => return None,
+        };
+        buf[..4].copy_from_slice(&value.to_le_bytes());
+        Some(4)
+    }
+
+    fn set(&self, msg: &[u8]) -> u32 {
+        let (Some(oid), Some(len), Some(offset)) = (read_u32(msg, 12), read_u32(msg, 16), read_u32(msg, 20)) else {
+            return STATUS_INVALID_DATA;
+        };
+        // The information buffer offset is relative to the RequestId field.
+        let start = (offset as usize).checked_add(8);
+        let Some(value) = start.and_then(|start| msg.get(start..)?.get(..len as usize)) else {
+            return STATUS_INVALID_DATA;
+        };
+
+        match oid {
+            OID_GEN_CURRENT_PACKET_FILTER => match read_u32(value, 0) {
+                Some(filter) => {
+                    info!("rndis: packet filter {:08x}", filter);
+                    self.shared.set_packet_filter(filter);
+                    STATUS_SUCCESS
+                }
+                None => STATUS_INVALID_DATA,
+            },
+            // We don't filter multicast frames, and have no configuration parameters.
+            OID_802_3_MULTICAST_LIST | OID_GEN_RNDIS_CONFIG_PARAMETER => STATUS_SUCCESS,
+            _ => {
+                debug!("rndis: unsupported set oid {:08x}", oid);
+                STATUS_NOT_SUPPORTED
+            }
+        }
+    }
+}
+
+impl Handler for Control<'_> {
+    fn reset(&mut self) {
+        self.response_len = 0;
+        self.shared.response_available.store(false, Ordering::Relaxed);
+        self.shared.set_packet_filter(0);
+    }
+
+    fn control_out(&mut self, req: control::Request, data: &[u8]) -> Option<OutResponse> {
+        if !req.is_class_interface(self.comm_if) {
+            return None;
+        }
+
+        match req.request {
+            REQ_SEND_ENCAPSULATED_COMMAND => {
+                self.handle_message(data);
+                Some(OutResponse::Accepted)
+            }
+            _ => Some(OutResponse::Rejected),
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if !req.is_class_interface(self.comm_if) {
+            return None;
+        }
+
+        match req.request {
+            REQ_GET_ENCAPSULATED_RESPONSE => match mem::replace(&mut self.response_len, 0) {
+                // With no response pending, the spec requires a single zero byte.
+                0 => {
+                    buf[0] = 0;
+                    Some(InResponse::Accepted(&buf[..1]))
+                }
+                len => Some(InResponse::Accepted(&self.response[..len])),
+            },
+            _ => Some(InResponse::Rejected),
+        }
+    }
+}
+
+/// RNDIS class
+pub struct RndisClass<'d, D: Driver<'d>> {
+    comm_ep: D::EndpointIn,
+    read_ep: D::EndpointOut,
+    write_ep: D::EndpointIn,
+
+    control: &'d ControlShared,
+
+    max_packet_size: usize,
+}
+
+impl<'d, D: Driver<'d>> RndisClass<'d, D> {
+    /// Create a new RNDIS class.
+    ///
+    /// `mac_address` is the address the host will use for its side of the link.
+    pub fn new(
+        builder: &mut Builder<'d, D>,
+        state: &'d mut State<'d>,
+        mac_address: [u8; 6],
+        max_packet_size: u16,
+    ) -> Self {
+        state.shared.mac_addr.lock(|x| x.set(mac_address));
+
+        let mut func = builder.function(USB_CLASS_WIRELESS_CONTROLLER, WIRELESS_SUBCLASS_RF, RF_PROTOCOL_RNDIS);
+
+        // Control interface
+        let mut iface = func.interface();
+        let comm_if = iface.interface_number();
+        let data_if = u8::from(comm_if) + 1;
+        let mut alt = iface.alt_setting(
+            USB_CLASS_WIRELESS_CONTROLLER,
+            WIRELESS_SUBCLASS_RF,
+            RF_PROTOCOL_RNDIS,
+            None,
+        );
+
+        alt.descriptor(
+            CS_INTERFACE,
+            &[
+                CDC_TYPE_HEADER, // bDescriptorSubtype
+                0x10,
+                0x01, // bcdCDC (1.10)
+            ],
+        );
+        alt.descriptor(
+            CS_INTERFACE,
+            &[
+                CDC_TYPE_CALL_MANAGEMENT, // bDescriptorSubtype
+                0x00,                     // bmCapabilities
+                data_if,                  // bDataInterface
+            ],
+        );
+        alt.descriptor(
+            CS_INTERFACE,
+            &[
+                CDC_TYPE_ACM, // bDescriptorSubtype
+                0x00,         // bmCapabilities
+            ],
+        );
+        alt.descriptor(
+            CS_INTERFACE,
+            &[
+                CDC_TYPE_UNION, // bDescriptorSubtype
+                comm_if.into(), // bControlInterface
+                data_if,        // bSubordinateInterface
+            ],
+        );
+
+        let comm_ep = alt.endpoint_interrupt_in(8, 1);
+
+        // Data interface
+        let mut iface = func.interface();
+        let mut alt = iface.alt_setting(USB_CLASS_CDC_DATA, 0x00, 0x00, None);
+        let read_ep = alt.endpoint_bulk_out(max_packet_size);
+        let write_ep = alt.endpoint_bulk_in(max_packet_size);
+
+        let control = state.control.write(Control {
+            comm_if,
+            shared: &state.shared,
+            // In units of 100 bps.
+            link_speed: if max_packet_size >= 512 { 4_800_000 } else { 120_000 },
+            response: [0; RESPONSE_MAX_SIZE],
+            response_len: 0,
+        });
+        func.handler(control);
+        drop(func);
+
+        RndisClass {
+            comm_ep,
+            read_ep,
+            write_ep,
+            control: &state.shared,
+            max_packet_size: max_packet_size as usize,
+        }
+    }
+
+    /// Split the class into a sender and receiver.
+    ///
+    /// This allows concurrently sending and receiving packets from separate tasks. The receiver
+    /// also answers the host's RNDIS control messages, so it must be polled continuously.
+    pub fn split(self) -> (Sender<'d, D>, Receiver<'d, D>) {
+        (
+            Sender {
+                write_ep: self.write_ep,
+                max_packet_size: self.max_packet_size,
+            },
+            Receiver {
+                comm_ep: self.comm_ep,
+                read_ep: self.read_ep,
+                control: self.control,
+            },
+        )
+    }
+}
+
+/// RNDIS class packet sender.
+///
+/// You can obtain a `Sender` with [`RndisClass::split`]
+pub struct Sender<'d, D: Driver<'d>> {
+    write_ep: D::EndpointIn,
+    max_packet_size: usize,
+}
+
+impl<'d, D: Driver<'d>> Sender<'d, D> {
+    /// Write an Ethernet frame.
+    ///
+    /// This waits until the frame is successfully stored in the RNDIS endpoint buffers.
+    pub async fn write_packet(&mut self, data: &[u8]) -> Result<(), EndpointError> {
+        const ABS_MAX_PACKET_SIZE: usize = 512;
+
+        let total_len = PACKET_HEADER_LEN + data.len();
+        let header: [u32; PACKET_HEADER_LEN / 4] = [
+            MSG_PACKET,
+            total_len as u32,
+            (PACKET_HEADER_LEN - 8) as u32, // DataOffset, relative to this field
+            data.len() as u32,              // DataLength
+            0,                              // OOBDataOffset
+            0,                              // OOBDataLength
+            0,                              // NumOOBDataElements
+            0,                              // PerPacketInfoOffset
+            0,                              // PerPacketInfoLength
+            0,                              // VcHandle
+            0,                              // Reserved
+        ];
+
+        // Build first packet on a buffer, send next packets straight from `data`.
+        let mut buf = [0; ABS_MAX_PACKET_SIZE];
+        for (i, field) in header.iter().enumerate() {
+            buf[i * 4..][..4].copy_from_slice(&field.to_le_bytes());
+        }
+
+        if total_len < self.max_packet_size {
+            // First packet is not full, just send it.
+            // No need to send ZLP because it's short for sure.
+            buf[PACKET_HEADER_LEN..total_len].copy_from_slice(data);
+            self.write_ep.write(&buf[..total_len]).await?;
+        } else {
+            let (d1, d2) = data.split_at(self.max_packet_size - PACKET_HEADER_LEN);
+
+            buf[PACKET_HEADER_LEN..self.max_packet_size].copy_from_slice(d1);
+            self.write_ep.write(&buf[..self.max_packet_size]).await?;
+
+            for chunk in d2.chunks(self.max_packet_size) {
+                self.write_ep.write(chunk).await?;
+            }
+
+            // Send ZLP if needed.
+            if d2.len() % self.max_packet_size == 0 {
+                self.write_ep.write(&[]).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// RNDIS class packet receiver.
+///
+/// You can obtain a `Receiver` with [`RndisClass::split`]
+pub struct Receiver<'d, D: Driver<'d>> {
+    comm_ep: D::EndpointIn,
+    read_ep: D::EndpointOut,
+    control: &'d ControlShared,
+}
+
+impl<'d, D: Driver<'d>> Receiver<'d, D> {
+    /// Read an Ethernet frame.
+    ///
+    /// This waits until a frame is successfully received from the endpoint buffers. While
+    /// waiting, it notifies the host of pending RNDIS control responses.
+    pub async fn read_packet(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError> {
+        let control = self.control;
+
+        // Retry loop
+        loop {
+            // read transfer
+            let mut msg = [0u8; MAX_TRANSFER_SIZE];
+            let mut pos = 0;
+            loop {
+                let read = self.read_ep.read(&mut msg[pos..]);
+                match select(read, poll_fn(|cx| control.poll_response_available(cx))).await {
+                    Either::First(n) => {
+                        let n = n?;
+                        pos += n;
+                        if n < self.read_ep.info().max_packet_size as usize || pos == MAX_TRANSFER_SIZE {
+                            break;
+                        }
+                    }
+                    Either::Second(()) => self.notify_response_available().await?,
+                }
+            }
+
+            let msg = &msg[..pos];
+
+            if read_u32(msg, 0) != Some(MSG_PACKET) {
+                warn!("rndis: received non-packet message on the data interface");
+                continue;
+            }
+            let (Some(data_offset), Some(data_len)) = (read_u32(msg, 8), read_u32(msg, 12)) else {
+                warn!("rndis: received too short packet message");
+                continue;
+            };
+
+            // The data offset is relative to the DataOffset field. Offsets that overflow are out of
+            // range too.
+            let start = (data_offset as usize).checked_add(8);
+            let end = start.and_then(|start| start.checked_add(data_len as usize));
+            let Some(frame) = start.zip(end).and_then(|(start, end)| msg.get(start..end)) else {
+                warn!("rndis: packet data out of range");
+                continue;
+            };
+            let Some(buf) = buf.get_mut(..frame.len()) else {
+                warn!("rndis: frame too large for buffer");
+                continue;
+            };
+            buf.copy_from_slice(frame);
+
+            return Ok(frame.len());
+        }
+    }
+
+    /// Waits for the USB host to enable the network link.
+    ///
+    /// The link is up once the host has set a non-zero packet filter. Control messages are
+    /// answered while waiting.
+    pub async fn wait_connection(&mut self) -> Result<(), EndpointError> {
+        let control = self.control;
+
+        self.read_ep.wait_enabled().await;
+        self.comm_ep.wait_enabled().await;
+
+        loop {
+            match select(
+                poll_fn(|cx| control.poll_response_available(cx)),
+                poll_fn(|cx| control.poll_connected(cx)),
+            )
+            .await
+            {
+                Either::First(()) => match self.notify_response_available().await {
+                    Ok(()) => {}
+                    Err(EndpointError::Disabled) => self.comm_ep.wait_enabled().await, // Got disabled, wait again.
+                    Err(e) => return Err(e),
+                },
+                Either::Second(()) => return Ok(()),
+            }
+        }
+    }
+
+    async fn notify_response_available(&mut self) -> Result<(), EndpointError> {
+        self.comm_ep
+            .write(&[
+                0x01, 0x00, 0x00, 0x00, // RESPONSE_AVAILABLE
+                0x00, 0x00, 0x00, 0x00, // Reserved
+            ])
+            .await
+    }
+}
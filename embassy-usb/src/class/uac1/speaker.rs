@@ -25,7 +25,7 @@ use super::terminal_type::TerminalType;
 use super::{Channel, ChannelConfig, FeedbackRefresh, SampleWidth, MAX_AUDIO_CHANNEL_COUNT, MAX_AUDIO_CHANNEL_INDEX};
 use crate::control::{self, InResponse, OutResponse, Recipient, Request, RequestType};
 use crate::descriptor::{SynchronizationType, UsageType};
-use crate::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut, EndpointType};
+use crate::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut, EndpointType, Unsupported};
 use crate::types::InterfaceNumber;
 use crate::{Builder, Handler};
 
@@ -286,16 +286,13 @@ impl<'d, D: Driver<'d>> Speaker<'d, D> {
             ],
         );
 
-        alt.descriptor(
-            CS_ENDPOINT,
-            &[
-                AS_GENERAL,            // bDescriptorSubtype (General)
-                SAMPLING_FREQ_CONTROL, // bmAttributes (support sampling frequency control)
-                0x02,                  // bLockDelayUnits (PCM)
-                0x0000 as u8,
-                (0x0000 >> 8) as u8, // wLockDelay (0)
-            ],
-        );
+        alt.class_endpoint_descriptor(&[
+            AS_GENERAL,            // bDescriptorSubtype (General)
+            SAMPLING_FREQ_CONTROL, // bmAttributes (support sampling frequency control)
+            0x02,                  // bLockDelayUnits (PCM)
+            0x0000 as u8,
+            (0x0000 >> 8) as u8, // wLockDelay (0)
+        ]);
 
         // Write the feedback endpoint descriptor after the streaming endpoint descriptor
         // This is demanded by the USB audio class specification.
@@ -309,9 +306,6 @@ impl<'d, D: Driver<'d>> Speaker<'d, D> {
             ],
         );
 
-        // Free up the builder.
-        drop(func);
-
         // Store channel information
         state.shared.channels = channels;
 
@@ -321,7 +315,10 @@ impl<'d, D: Driver<'d>> Speaker<'d, D> {
             control_interface_number: control_interface,
         });
 
-        builder.handler(state.control.as_mut().unwrap());
+        func.handler(state.control.as_mut().unwrap());
+
+        // Free up the builder.
+        drop(func);
 
         let control = &state.shared;
 
@@ -417,6 +414,14 @@ impl<'d, D: Driver<'d>> Stream<'d, D> {
     pub async fn wait_connection(&mut self) {
         self.streaming_endpoint.wait_enabled().await;
     }
+
+    /// Returns the number of audio packets dropped because they weren't read in time since the
+    /// last call, if the driver can detect it.
+    ///
+    /// See [`Endpoint::take_dropped_packets`].
+    pub fn take_dropped_packets(&mut self) -> Result<u32, Unsupported> {
+        self.streaming_endpoint.take_dropped_packets()
+    }
 }
 
 /// Used for writing sample rate information over the feedback endpoint.
@@ -0,0 +1,709 @@
+//! USB Video Class 1.1 implementation, for simple cameras.
+//!
+//! The class has a VideoControl interface with a camera input terminal connected to a streaming
+//! output terminal, and a VideoStreaming interface with a single format and frame size. Video
+//! frames are sent over an isochronous IN endpoint, framed with UVC payload headers.
+//!
+//! Before streaming, the host negotiates the stream parameters with the probe and commit
+//! controls, then selects the operational alternate setting of the streaming interface. Only one
+//! format and frame size are supported, so the device answers every probe with the same values.
+
+use core::cell::{Cell, RefCell};
+use core::future::poll_fn;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::Poll;
+
+use embassy_sync::blocking_mutex::CriticalSectionMutex;
+use embassy_sync::waitqueue::WakerRegistration;
+
+use crate::control::{InResponse, OutResponse, Request, RequestType};
+use crate::descriptor::{SynchronizationType, UsageType};
+use crate::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointType};
+use crate::types::InterfaceNumber;
+use crate::{Builder, Handler};
+
+/// Video interface class code.
+pub const USB_CLASS_VIDEO: u8 = 0x0e;
+
+const SC_VIDEOCONTROL: u8 = 0x01;
+const SC_VIDEOSTREAMING: u8 = 0x02;
+const SC_VIDEO_INTERFACE_COLLECTION: u8 = 0x03;
+const PC_PROTOCOL_UNDEFINED: u8 = 0x00;
+
+const CS_INTERFACE: u8 = 0x24;
+
+// VideoControl interface descriptor subtypes [UVC A.5]
+const VC_HEADER: u8 = 0x01;
+const VC_INPUT_TERMINAL: u8 = 0x02;
+const VC_OUTPUT_TERMINAL: u8 = 0x03;
+
+// VideoStreaming interface descriptor subtypes [UVC A.6]
+const VS_INPUT_HEADER: u8 = 0x01;
+const VS_FORMAT_UNCOMPRESSED: u8 = 0x04;
+const VS_FRAME_UNCOMPRESSED: u8 = 0x05;
+const VS_FORMAT_MJPEG: u8 = 0x06;
+const VS_FRAME_MJPEG: u8 = 0x07;
+const VS_COLORFORMAT: u8 = 0x0d;
+
+// Class-specific request codes [UVC A.8]
+const SET_CUR: u8 = 0x01;
+const GET_CUR: u8 = 0x81;
+const GET_MIN: u8 = 0x82;
+const GET_MAX: u8 = 0x83;
+const GET_RES: u8 = 0x84;
+const GET_LEN: u8 = 0x85;
+const GET_INFO: u8 = 0x86;
+const GET_DEF: u8 = 0x87;
+
+// VideoStreaming interface control selectors [UVC A.9.7]
+const VS_PROBE_CONTROL: u8 = 0x01;
+const VS_COMMIT_CONTROL: u8 = 0x02;
+
+// Terminal types [UVC B.1, B.2]
+const TT_STREAMING: u16 = 0x0101;
+const ITT_CAMERA: u16 = 0x0201;
+
+const UVC_VERSION: u16 = 0x0110;
+
+/// Arbitrary unique identifier for the camera input terminal.
+const CAMERA_TERMINAL_ID: u8 = 0x01;
+
+/// Arbitrary unique identifier for the streaming output terminal.
+const OUTPUT_TERMINAL_ID: u8 = 0x02;
+
+/// Clock used for the deprecated dwClockFrequency fields, in Hz.
+const CLOCK_FREQUENCY_HZ: u32 = 48_000_000;
+
+// Payload header bmHeaderInfo bits [UVC 2.4.3.3]
+const HEADER_FID: u8 = 0x01;
+const HEADER_EOF: u8 = 0x02;
+const HEADER_EOH: u8 = 0x80;
+const HEADER_LEN: usize = 2;
+
+/// The largest isochronous packet size, for high-speed endpoints.
+const MAX_ISO_PACKET_SIZE: usize = 1024;
+
+/// Length of the UVC 1.1 probe and commit control structure.
+const PROBE_COMMIT_LEN: usize = 34;
+
+/// Length of the UVC 1.0 probe and commit control structure, still sent by some hosts.
+const PROBE_COMMIT_LEN_1_0: usize = 26;
+
+/// GET_INFO response: the control supports GET and SET requests.
+const INFO_GET_SET: u8 = 0x03;
+
+/// bmFramingInfo: payloads use the FID and EOF header bits.
+const FRAMING_FID_EOF: u8 = 0x03;
+
+/// Video frame format.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Format {
+    /// Uncompressed YUY2 (YUV 4:2:2), 16 bits per pixel.
+    Yuy2,
+    /// Uncompressed NV12 (YUV 4:2:0), 12 bits per pixel.
+    Nv12,
+    /// Motion JPEG. Frames may be smaller than the uncompressed size.
+    Mjpeg,
+}
+
+impl Format {
+    const fn bits_per_pixel(self) -> u32 {
+        match self {
+            Format::Yuy2 | Format::Mjpeg => 16,
+            Format::Nv12 => 12,
+        }
+    }
+
+    /// Returns the GUID of uncompressed formats [UVC Payload Uncompressed 2.2].
+    const fn guid(self) -> Option<[u8; 16]> {
+        let fourcc = match self {
+            Format::Yuy2 => *b"YUY2",
+            Format::Nv12 => *b"NV12",
+            Format::Mjpeg => return None,
+        };
+        Some([
+            fourcc[0], fourcc[1], fourcc[2], fourcc[3], 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xaa, 0x00, 0x38,
+            0x9b, 0x71,
+        ])
+    }
+}
+
+/// Configuration for the UVC class.
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Config {
+    /// The frame format.
+    pub format: Format,
+    /// Frame width in pixels.
+    pub width: u16,
+    /// Frame height in pixels.
+    pub height: u16,
+    /// Frame interval in 100 ns units, for example `333_333` for 30 frames per second.
+    pub frame_interval: u32,
+    /// Max packet size of the isochronous endpoint, at most 1023 bytes for full speed and 1024
+    /// bytes for high speed.
+    pub max_packet_size: u16,
+}
+
+impl Config {
+    /// The largest frame size in bytes.
+    const fn max_frame_size(&self) -> u32 {
+        self.width as u32 * self.height as u32 * self.format.bits_per_pixel() / 8
+    }
+
+    /// The bit rate in bits per second at the configured frame interval.
+    const fn bit_rate(&self) -> u32 {
+        (self.max_frame_size() as u64 * 8 * 10_000_000 / self.frame_interval as u64) as u32
+    }
+}
+
+/// Video probe and commit control values [UVC 4.3.1.1].
+///
+/// The host proposes values with SET_CUR on the probe control, reads back what the device
+/// supports with GET_CUR, and finally sets the agreed values on the commit control.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ProbeCommit {
+    /// Bitfield of the fields the host wants kept fixed during negotiation.
+    pub hint: u16,
+    /// Index of the video format.
+    pub format_index: u8,
+    /// Index of the video frame descriptor.
+    pub frame_index: u8,
+    /// Frame interval in 100 ns units.
+    pub frame_interval: u32,
+    /// Key frame rate, for formats that have key frames.
+    pub key_frame_rate: u16,
+    /// P frame rate, for formats that have P frames.
+    pub p_frame_rate: u16,
+    /// Compression quality.
+    pub comp_quality: u16,
+    /// Compression window size.
+    pub comp_window_size: u16,
+    /// Internal video streaming latency in ms.
+    pub delay: u16,
+    /// Maximum video frame size in bytes.
+    pub max_video_frame_size: u32,
+    /// Maximum number of bytes the device can send in a single payload transfer.
+    pub max_payload_transfer_size: u32,
+    /// Device clock frequency in Hz.
+    pub clock_frequency: u32,
+    /// Framing information, bit 0 for FID and bit 1 for EOF.
+    pub framing_info: u8,
+    /// Preferred payload format version.
+    pub preferred_version: u8,
+    /// Minimum payload format version.
+    pub min_version: u8,
+    /// Maximum payload format version.
+    pub max_version: u8,
+}
+
+impl ProbeCommit {
+    fn parse(data: &[u8]) -> Self {
+        let u16_at = |i: usize| u16::from_le_bytes([data[i], data[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+
+        let mut value = ProbeCommit {
+            hint: u16_at(0),
+            format_index: data[2],
+            frame_index: data[3],
+            frame_interval: u32_at(4),
+            key_frame_rate: u16_at(8),
+            p_frame_rate: u16_at(10),
+            comp_quality: u16_at(12),
+            comp_window_size: u16_at(14),
+            delay: u16_at(16),
+            max_video_frame_size: u32_at(18),
+            max_payload_transfer_size: u32_at(22),
+            ..Default::default()
+        };
+
+        // The fields added in UVC 1.1.
+        if data.len() >= PROBE_COMMIT_LEN {
+            value.clock_frequency = u32_at(26);
+            value.framing_info = data[30];
+            value.preferred_version = data[31];
+            value.min_version = data[32];
+            value.max_version = data[33];
+        }
+
+        value
+    }
+
+    fn write(&self, buf: &mut [u8]) {
+        buf[0..2].copy_from_slice(&self.hint.to_le_bytes());
+        buf[2] = self.format_index;
+        buf[3] = self.frame_index;
+        buf[4..8].copy_from_slice(&self.frame_interval.to_le_bytes());
+        buf[8..10].copy_from_slice(&self.key_frame_rate.to_le_bytes());
+        buf[10..12].copy_from_slice(&self.p_frame_rate.to_le_bytes());
+        buf[12..14].copy_from_slice(&self.comp_quality.to_le_bytes());
+        buf[14..16].copy_from_slice(&self.comp_window_size.to_le_bytes());
+        buf[16..18].copy_from_slice(&self.delay.to_le_bytes());
+        buf[18..22].copy_from_slice(&self.max_video_frame_size.to_le_bytes());
+        buf[22..26].copy_from_slice(&self.max_payload_transfer_size.to_le_bytes());
+        buf[26..30].copy_from_slice(&self.clock_frequency.to_le_bytes());
+        buf[30] = self.framing_info;
+        buf[31] = self.preferred_version;
+        buf[32] = self.min_version;
+        buf[33] = self.max_version;
+    }
+}
+
+/// Internal state for the UVC class.
+pub struct State<'a> {
+    control: MaybeUninit<Control<'a>>,
+    shared: ControlShared,
+}
+
+impl Default for State<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl State<'_> {
+    /// Create a new `State`.
+    pub const fn new() -> Self {
+        Self {
+            control: MaybeUninit::uninit(),
+            shared: ControlShared::new(),
+        }
+    }
+}
+
+/// Shared data between Control and UvcClass.
+struct ControlShared {
+    committed: CriticalSectionMutex<Cell<Option<ProbeCommit>>>,
+    streaming: AtomicBool,
+    waker: RefCell<WakerRegistration>,
+}
+
+impl ControlShared {
+    const fn new() -> Self {
+        ControlShared {
+            committed: CriticalSectionMutex::new(Cell::new(None)),
+            streaming: AtomicBool::new(false),
+            waker: RefCell::new(WakerRegistration::new()),
+        }
+    }
+
+    fn set_streaming(&self, streaming: bool) {
+        self.streaming.store(streaming, Ordering::Relaxed);
+        self.waker.borrow_mut().wake();
+    }
+}
+
+struct Control<'a> {
+    control_if: InterfaceNumber,
+    streaming_if: InterfaceNumber,
+    /// The only values the device supports, returned for GET_MIN, GET_MAX and GET_DEF.
+    default: ProbeCommit,
+    probe: ProbeCommit,
+    commit: ProbeCommit,
+    shared: &'a ControlShared,
+}
+
+impl Control<'_> {
+    /// Answers a probe or commit proposal with the closest values the device supports.
+    ///
+    /// There is only one format and frame size, so everything the device decides is replaced by
+    /// the defaults. Only the compression parameters the host chose are kept.
+    fn negotiate(&self, proposal: ProbeCommit) -> ProbeCommit {
+        ProbeCommit {
+            hint: proposal.hint,
+            key_frame_rate: proposal.key_frame_rate,
+            p_frame_rate: proposal.p_frame_rate,
+            comp_quality: proposal.comp_quality,
+            comp_window_size: proposal.comp_window_size,
+            ..self.default
+        }
+    }
+
+    fn streaming_control_out(&mut self, req: Request, data: &[u8]) -> OutResponse {
+        let selector = (req.value >> 8) as u8;
+
+        if req.request != SET_CUR || data.len() < PROBE_COMMIT_LEN_1_0 {
+            return OutResponse::Rejected;
+        }
+
+        let proposal = ProbeCommit::parse(data);
+        match selector {
+            VS_PROBE_CONTROL => {
+                let proposal = self.negotiate(proposal);
+                debug!("uvc: probe {:?}", proposal);
+                self.probe = proposal;
+                OutResponse::Accepted
+            }
+            VS_COMMIT_CONTROL => {
+                // Unlike a probe, a commit can't be negotiated, so a format or frame the device
+                // doesn't have is an error [UVC 4.3.1.1.1].
+                if (proposal.format_index, proposal.frame_index)
+                    != (self.default.format_index, self.default.frame_index)
+                {
+                    warn!(
+                        "uvc: commit of unsupported format {} frame {}",
+                        proposal.format_index, proposal.frame_index
+                    );
+                    return OutResponse::Rejected;
+                }
+                let proposal = self.negotiate(proposal);
+                debug!("uvc: commit {:?}", proposal);
+                self.commit = proposal;
+                self.shared.committed.lock(|x| x.set(Some(proposal)));
+                OutResponse::Accepted
+            }
+            _ => OutResponse::Rejected,
+        }
+    }
+
+    fn streaming_control_in<'r>(&'r mut self, req: Request, buf: &'r mut [u8]) -> InResponse<'r> {
+        let selector = (req.value >> 8) as u8;
+        let current = match selector {
+            VS_PROBE_CONTROL => self.probe,
+            VS_COMMIT_CONTROL => self.commit,
+            _ => return InResponse::Rejected,
+        };
+
+        let value = match req.request {
+            GET_INFO => {
+                buf[0] = INFO_GET_SET;
+                return InResponse::Accepted(&buf[..1]);
+            }
+            GET_LEN => {
+                buf[..2].copy_from_slice(&(PROBE_COMMIT_LEN as u16).to_le_bytes());
+                return InResponse::Accepted(&buf[..2]);
+            }
+            GET_CUR => current,
+            GET_MIN | GET_MAX | GET_DEF => self.default,
+            GET_RES => ProbeCommit::default(),
+            _ => return InResponse::Rejected,
+        };
+
+        value.write(&mut buf[..PROBE_COMMIT_LEN]);
+        InResponse::Accepted(&buf[..PROBE_COMMIT_LEN])
+    }
+}
+
+impl Handler for Control<'_> {
+    fn reset(&mut self) {
+        self.probe = self.default;
+        self.commit = self.default;
+        self.shared.committed.lock(|x| x.set(None));
+        self.shared.set_streaming(false);
+    }
+
+    fn configured(&mut self, configured: bool) {
+        if !configured {
+            self.shared.set_streaming(false);
+        }
+    }
+
+    fn set_alternate_setting(&mut self, iface: InterfaceNumber, alternate_setting: u8) {
+        if iface == self.streaming_if {
+            debug!("uvc: streaming alt setting {}", alternate_setting);
+            self.shared.set_streaming(alternate_setting != 0);
+        }
+    }
+
+    fn control_out(&mut self, req: Request, data: &[u8]) -> Option<OutResponse> {
+        if req.is_class_interface(self.streaming_if) {
+            return Some(self.streaming_control_out(req, data));
+        }
+        // The camera terminal has no controls.
+        if req.request_type == RequestType::Class && req.index as u8 == self.control_if.0 {
+            return Some(OutResponse::Rejected);
+        }
+        None
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if req.is_class_interface(self.streaming_if) {
+            return Some(self.streaming_control_in(req, buf));
+        }
+        if req.request_type == RequestType::Class && req.index as u8 == self.control_if.0 {
+            return Some(InResponse::Rejected);
+        }
+        None
+    }
+}
+
+/// UVC camera with a single isochronous video stream.
+pub struct UvcClass<'d, D: Driver<'d>> {
+    ep: D::EndpointIn,
+    shared: &'d ControlShared,
+    fid: u8,
+}
+
+impl<'d, D: Driver<'d>> UvcClass<'d, D> {
+    /// Creates a new `UvcClass` with the provided UsbBus and configuration.
+    ///
+    /// The control buffer passed to [`Builder::new`] must be at least 34 bytes long, for the
+    /// probe and commit controls.
+    pub fn new(builder: &mut Builder<'d, D>, state: &'d mut State<'d>, config: Config) -> Self {
+        assert!(config.max_packet_size as usize > HEADER_LEN);
+        assert!(config.max_packet_size as usize <= MAX_ISO_PACKET_SIZE);
+        assert!(config.frame_interval != 0);
+        assert!(builder.control_buf_len() >= PROBE_COMMIT_LEN);
+
+        let mut func = builder.function(USB_CLASS_VIDEO, SC_VIDEO_INTERFACE_COLLECTION, PC_PROTOCOL_UNDEFINED);
+
+        // VideoControl interface [UVC 3.7]
+        let mut iface = func.interface();
+        let control_if = iface.interface_number();
+        let streaming_if = InterfaceNumber::new(control_if.0 + 1);
+        let mut alt = iface.alt_setting(USB_CLASS_VIDEO, SC_VIDEOCONTROL, PC_PROTOCOL_UNDEFINED, None);
+
+        const VC_HEADER_LEN: u16 = 13;
+        const CAMERA_TERMINAL_LEN: u16 = 18;
+        const OUTPUT_TERMINAL_LEN: u16 = 9;
+        let vc_total_length = VC_HEADER_LEN + CAMERA_TERMINAL_LEN + OUTPUT_TERMINAL_LEN;
+
+        alt.descriptor(
+            CS_INTERFACE,
+            &[
+                VC_HEADER, // bDescriptorSubtype
+                UVC_VERSION as u8,
+                (UVC_VERSION >> 8) as u8, // bcdUVC
+                vc_total_length as u8,
+                (vc_total_length >> 8) as u8, // wTotalLength
+                CLOCK_FREQUENCY_HZ as u8,
+                (CLOCK_FREQUENCY_HZ >> 8) as u8,
+                (CLOCK_FREQUENCY_HZ >> 16) as u8,
+                (CLOCK_FREQUENCY_HZ >> 24) as u8, // dwClockFrequency
+                0x01,                             // bInCollection
+                streaming_if.into(),              // baInterfaceNr(1)
+            ],
+        );
+        alt.descriptor(
+            CS_INTERFACE,
+            &[
+                VC_INPUT_TERMINAL,  // bDescriptorSubtype
+                CAMERA_TERMINAL_ID, // bTerminalID
+                ITT_CAMERA as u8,
+                (ITT_CAMERA >> 8) as u8, // wTerminalType
+                0x00,                    // bAssocTerminal
+                0x00,                    // iTerminal
+                0x00,
+                0x00, // wObjectiveFocalLengthMin
+                0x00,
+                0x00, // wObjectiveFocalLengthMax
+                0x00,
+                0x00, // wOcularFocalLength
+                0x03, // bControlSize
+                0x00,
+                0x00,
+                0x00, // bmControls (none)
+            ],
+        );
+        alt.descriptor(
+            CS_INTERFACE,
+            &[
+                VC_OUTPUT_TERMINAL, // bDescriptorSubtype
+                OUTPUT_TERMINAL_ID, // bTerminalID
+                TT_STREAMING as u8,
+                (TT_STREAMING >> 8) as u8, // wTerminalType
+                0x00,                      // bAssocTerminal
+                CAMERA_TERMINAL_ID,        // bSourceID
+                0x00,                      // iTerminal
+            ],
+        );
+
+        // VideoStreaming interface, zero-bandwidth alternate setting [UVC 3.9]
+        let mut iface = func.interface();
+        let mut alt = iface.alt_setting(USB_CLASS_VIDEO, SC_VIDEOSTREAMING, PC_PROTOCOL_UNDEFINED, None);
+
+        // The input header needs the endpoint address, but the endpoint descriptor belongs to the
+        // operational alternate setting.
+        let ep = alt.alloc_endpoint_in(EndpointType::Isochronous, config.max_packet_size, 1);
+
+        const INPUT_HEADER_LEN: u16 = 14;
+        const FORMAT_UNCOMPRESSED_LEN: u16 = 27;
+        const FORMAT_MJPEG_LEN: u16 = 11;
+        const FRAME_LEN: u16 = 30;
+        const COLOR_MATCHING_LEN: u16 = 6;
+        let vs_total_length = INPUT_HEADER_LEN
+            + match config.format.guid() {
+                Some(_) => FORMAT_UNCOMPRESSED_LEN,
+                None => FORMAT_MJPEG_LEN,
+            }
+            + FRAME_LEN
+            + COLOR_MATCHING_LEN;
+
+        alt.descriptor(
+            CS_INTERFACE,
+            &[
+                VS_INPUT_HEADER, // bDescriptorSubtype
+                0x01,            // bNumFormats
+                vs_total_length as u8,
+                (vs_total_length >> 8) as u8, // wTotalLength
+                ep.info().addr.into(),        // bEndpointAddress
+                0x00,                         // bmInfo
+                OUTPUT_TERMINAL_ID,           // bTerminalLink
+                0x00,                         // bStillCaptureMethod (none)
+                0x00,                         // bTriggerSupport (none)
+                0x00,                         // bTriggerUsage
+                0x01,                         // bControlSize
+                0x00,                         // bmaControls(1)
+            ],
+        );
+
+        let frame_subtype = match config.format.guid() {
+            Some(guid) => {
+                let mut format = [0; FORMAT_UNCOMPRESSED_LEN as usize - 2];
+                format[0] = VS_FORMAT_UNCOMPRESSED; // bDescriptorSubtype
+                format[1] = 0x01; // bFormatIndex
+                format[2] = 0x01; // bNumFrameDescriptors
+                format[3..19].copy_from_slice(&guid); // guidFormat
+                format[19] = config.format.bits_per_pixel() as u8; // bBitsPerPixel
+                format[20] = 0x01; // bDefaultFrameIndex
+
+                // bAspectRatioX, bAspectRatioY, bmInterlaceFlags and bCopyProtect stay zero.
+                alt.descriptor(CS_INTERFACE, &format);
+                VS_FRAME_UNCOMPRESSED
+            }
+            None => {
+                alt.descriptor(
+                    CS_INTERFACE,
+                    &[
+                        VS_FORMAT_MJPEG, // bDescriptorSubtype
+                        0x01,            // bFormatIndex
+                        0x01,            // bNumFrameDescriptors
+                        0x00,            // bmFlags
+                        0x01,            // bDefaultFrameIndex
+                        0x00,            // bAspectRatioX
+                        0x00,            // bAspectRatioY
+                        0x00,            // bmInterlaceFlags
+                        0x00,            // bCopyProtect
+                    ],
+                );
+                VS_FRAME_MJPEG
+            }
+        };
+
+        let mut frame = [0; FRAME_LEN as usize - 2];
+        frame[0] = frame_subtype; // bDescriptorSubtype
+        frame[1] = 0x01; // bFrameIndex
+        frame[2] = 0x00; // bmCapabilities
+        frame[3..5].copy_from_slice(&config.width.to_le_bytes()); // wWidth
+        frame[5..7].copy_from_slice(&config.height.to_le_bytes()); // wHeight
+        frame[7..11].copy_from_slice(&config.bit_rate().to_le_bytes()); // dwMinBitRate
+        frame[11..15].copy_from_slice(&config.bit_rate().to_le_bytes()); // dwMaxBitRate
+        frame[15..19].copy_from_slice(&config.max_frame_size().to_le_bytes()); // dwMaxVideoFrameBufferSize
+        frame[19..23].copy_from_slice(&config.frame_interval.to_le_bytes()); // dwDefaultFrameInterval
+        frame[23] = 0x01; // bFrameIntervalType (one discrete interval)
+        frame[24..28].copy_from_slice(&config.frame_interval.to_le_bytes()); // dwFrameInterval(1)
+        alt.descriptor(CS_INTERFACE, &frame);
+
+        alt.descriptor(
+            CS_INTERFACE,
+            &[
+                VS_COLORFORMAT, // bDescriptorSubtype
+                0x01,           // bColorPrimaries (BT.709, sRGB)
+                0x01,           // bTransferCharacteristics (BT.709)
+                0x04,           // bMatrixCoefficients (SMPTE 170M)
+            ],
+        );
+
+        // VideoStreaming interface, operational alternate setting
+        let mut alt = iface.alt_setting(USB_CLASS_VIDEO, SC_VIDEOSTREAMING, PC_PROTOCOL_UNDEFINED, None);
+        alt.endpoint_descriptor(
+            ep.info(),
+            SynchronizationType::Asynchronous,
+            UsageType::DataEndpoint,
+            &[],
+        );
+
+        let default = ProbeCommit {
+            format_index: 1,
+            frame_index: 1,
+            frame_interval: config.frame_interval,
+            max_video_frame_size: config.max_frame_size(),
+            max_payload_transfer_size: config.max_packet_size as u32,
+            clock_frequency: CLOCK_FREQUENCY_HZ,
+            framing_info: FRAMING_FID_EOF,
+            preferred_version: 1,
+            min_version: 1,
+            max_version: 1,
+            ..Default::default()
+        };
+
+        let control = state.control.write(Control {
+            control_if,
+            streaming_if,
+            default,
+            probe: default,
+            commit: default,
+            shared: &state.shared,
+        });
+        func.handler(control);
+        drop(func);
+
+        UvcClass {
+            ep,
+            shared: &state.shared,
+            fid: 0,
+        }
+    }
+
+    /// Returns the stream parameters the host committed, or `None` if it hasn't committed any
+    /// since the last bus reset.
+    pub fn committed(&self) -> Option<ProbeCommit> {
+        self.shared.committed.lock(|x| x.get())
+    }
+
+    /// Returns true if the host has selected the operational alternate setting, and frames can
+    /// be sent.
+    pub fn is_streaming(&self) -> bool {
+        self.shared.streaming.load(Ordering::Relaxed)
+    }
+
+    /// Waits for the host to start streaming.
+    pub async fn wait_streaming(&mut self) {
+        poll_fn(|cx| {
+            if self.is_streaming() {
+                Poll::Ready(())
+            } else {
+                self.shared.waker.borrow_mut().register(cx.waker());
+                Poll::Pending
+            }
+        })
+        .await;
+        self.ep.wait_enabled().await;
+    }
+
+    /// Sends a video frame.
+    ///
+    /// The frame is split into isochronous packets of up to the endpoint's max packet size, each
+    /// starting with a 2 byte payload header. The header's FID bit toggles from one frame to the
+    /// next, and EOF is set on the last packet of the frame.
+    ///
+    /// Returns `EndpointError::Disabled` if the host stops streaming, for example by selecting
+    /// the zero-bandwidth alternate setting. The next frame then starts with a new FID.
+    pub async fn send_frame(&mut self, frame: &[u8]) -> Result<(), EndpointError> {
+        let fid = self.fid;
+        self.fid ^= HEADER_FID;
+
+        let max_packet_size = self.ep.info().max_packet_size as usize;
+        let mut packet = [0; MAX_ISO_PACKET_SIZE];
+        let mut chunks = frame.chunks(max_packet_size - HEADER_LEN).peekable();
+
+        // An empty frame is still sent as a header-only packet, so the host sees its end.
+        if chunks.peek().is_none() {
+            packet[..HEADER_LEN].copy_from_slice(&[HEADER_LEN as u8, HEADER_EOH | HEADER_EOF | fid]);
+            return self.ep.write(&packet[..HEADER_LEN]).await;
+        }
+
+        while let Some(chunk) = chunks.next() {
+            let eof = if chunks.peek().is_none() { HEADER_EOF } else { 0 };
+            packet[0] = HEADER_LEN as u8;
+            packet[1] = HEADER_EOH | eof | fid;
+            packet[HEADER_LEN..HEADER_LEN + chunk.len()].copy_from_slice(chunk);
+            self.ep.write(&packet[..HEADER_LEN + chunk.len()]).await?;
+        }
+
+        Ok(())
+    }
+}
@@ -178,9 +178,6 @@ impl<'d, D: Driver<'d>> WebUsb<'d, D> {
         );
 
         let control = state.control.write(Control::new(config));
-
-        drop(func);
-
-        builder.handler(control);
+        func.handler(control);
     }
 }
@@ -2,6 +2,7 @@
 use core::mem;
 
 use crate::driver::Direction;
+use crate::types::InterfaceNumber;
 
 /// Control request type.
 #[repr(u8)]
@@ -99,6 +100,12 @@ impl Request {
     /// Standard USB feature Device Remote Wakeup for Set/Clear Feature
     pub const FEATURE_DEVICE_REMOTE_WAKEUP: u16 = 1;
 
+    /// Standard USB 3 feature U1 Enable for Set/Clear Feature
+    pub const FEATURE_U1_ENABLE: u16 = 48;
+
+    /// Standard USB 3 feature U2 Enable for Set/Clear Feature
+    pub const FEATURE_U2_ENABLE: u16 = 49;
+
     /// Parses a USB control request from a byte array.
     pub fn parse(buf: &[u8; 8]) -> Request {
         let rt = buf[0];
@@ -119,6 +126,38 @@ impl Request {
         }
     }
 
+    /// Gets the bmRequestType byte of the request.
+    ///
+    /// The byte is rebuilt from the parsed fields. Reserved recipient values all parse to
+    /// [`Recipient::Reserved`], so for those the recipient bits read back as 4.
+    pub const fn request_type_byte(&self) -> u8 {
+        let direction = match self.direction {
+            Direction::Out => 0x00,
+            Direction::In => 0x80,
+        };
+        direction | ((self.request_type as u8) << 5) | self.recipient as u8
+    }
+
+    /// Returns true if this is a `request_type` request addressed to interface `iface`.
+    ///
+    /// ```
+    /// # use embassy_usb::control::{Request, RequestType};
+    /// # use embassy_usb::types::InterfaceNumber;
+    /// let req = Request::parse(&[0x21, 0x20, 0, 0, 2, 0, 7, 0]);
+    /// assert!(req.is_interface_request(RequestType::Class, InterfaceNumber(2)));
+    /// assert!(!req.is_interface_request(RequestType::Class, InterfaceNumber(0)));
+    /// ```
+    pub fn is_interface_request(&self, request_type: RequestType, iface: InterfaceNumber) -> bool {
+        self.request_type == request_type && self.recipient == Recipient::Interface && self.index == iface.0 as u16
+    }
+
+    /// Returns true if this is a class request addressed to interface `iface`.
+    ///
+    /// This is the usual check at the start of a class's control handlers.
+    pub fn is_class_interface(&self, iface: InterfaceNumber) -> bool {
+        self.is_interface_request(RequestType::Class, iface)
+    }
+
     /// Gets the descriptor type and index from the value field of a GET_DESCRIPTOR request.
     pub const fn descriptor_type_index(&self) -> (u8, u8) {
         ((self.value >> 8) as u8, self.value as u8)
@@ -133,6 +172,8 @@ pub enum OutResponse {
     Accepted,
     /// The request was rejected.
     Rejected,
+    /// The request will be accepted or rejected later, from [`Handler::poll_deferred`](crate::Handler::poll_deferred).
+    Deferred,
 }
 
 /// Response for a CONTROL IN request.
@@ -140,7 +181,25 @@ pub enum OutResponse {
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum InResponse<'a> {
     /// The request was accepted. The buffer contains the response data.
+    ///
+    /// The data doesn't have to be written to the control buffer passed to the handler: it can
+    /// also be borrowed from the handler itself, or from a `'static` slice in flash. Either way it
+    /// is sent to the host directly, without being copied into RAM first.
     Accepted(&'a [u8]),
     /// The request was rejected.
     Rejected,
 }
+
+impl<'a> InResponse<'a> {
+    /// Accepts `req` with the first `len` bytes of `buf`, padded with zeros to exactly `wLength`
+    /// bytes instead of ending the transfer with a short packet.
+    ///
+    /// This isn't spec compliant, only use it for hosts that insist on always receiving the full
+    /// `wLength`. The padding is written to `buf`, so the response is still cut short if
+    /// `wLength` is larger than `buf`.
+    pub fn accepted_padded(req: Request, buf: &'a mut [u8], len: usize) -> Self {
+        let end = usize::from(req.length).clamp(len, buf.len());
+        buf[len..end].fill(0);
+        InResponse::Accepted(&buf[..end])
+    }
+}
@@ -2,9 +2,9 @@
 use embassy_usb_driver::EndpointType;
 
 use crate::builder::Config;
-use crate::driver::EndpointInfo;
+use crate::driver::{EndpointAddress, EndpointInfo};
 use crate::types::{InterfaceNumber, StringIndex};
-use crate::CONFIGURATION_VALUE;
+use crate::{CONFIGURATION_VALUE, STRING_INDEX_CONFIGURATION};
 
 /// Standard descriptor types
 #[allow(missing_docs)]
@@ -19,6 +19,8 @@ pub mod descriptor_type {
     pub const IAD: u8 = 11;
     pub const BOS: u8 = 15;
     pub const CAPABILITY: u8 = 16;
+    pub const CS_INTERFACE: u8 = 0x24;
+    pub const CS_ENDPOINT: u8 = 0x25;
 }
 
 /// String descriptor language IDs.
@@ -37,6 +39,7 @@ pub mod capability_type {
     pub const SS_USB_DEVICE: u8 = 3;
     pub const CONTAINER_ID: u8 = 4;
     pub const PLATFORM: u8 = 5;
+    pub const SUPERSPEED_PLUS: u8 = 10;
 }
 
 /// USB endpoint synchronization type. The values of this enum can be directly
@@ -73,6 +76,73 @@ pub enum UsageType {
     Reserved = 0b11,
 }
 
+/// USB bus speed, used to encode endpoint intervals with [`encode_interval`].
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Speed {
+    /// Low speed (1.5 Mbit/s), 1 ms frames.
+    Low,
+    /// Full speed (12 Mbit/s), 1 ms frames.
+    Full,
+    /// High speed (480 Mbit/s), 125 us microframes.
+    High,
+}
+
+/// The requested endpoint interval can't be encoded in `bInterval`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct InvalidInterval;
+
+/// Encodes an endpoint polling interval in microseconds into a `bInterval` value.
+///
+/// The encoding depends on bus speed and endpoint type:
+/// - low/full speed interrupt endpoints: the interval in frames, 1 to 255 ms (10 to 255 ms for
+///   low speed).
+/// - full speed isochronous endpoints: `2^(bInterval-1)` frames, 1 ms to 32768 ms.
+/// - high speed interrupt and isochronous endpoints: `2^(bInterval-1)` microframes, 125 us
+///   to 4096 ms.
+/// - bulk and control endpoints: only an interval of 0 is accepted.
+///
+/// Returns an error if the interval isn't exactly representable. The result can be passed
+/// as the `interval_ms` argument of the endpoint allocation functions, which write it to
+/// the descriptor unchanged.
+///
+/// Descriptors are written before the host connects, so `speed` is the speed the device
+/// is designed to run at, not the negotiated one.
+pub const fn encode_interval(speed: Speed, ep_type: EndpointType, interval_us: u32) -> Result<u8, InvalidInterval> {
+    const fn exponent(interval_us: u32, unit_us: u32) -> Result<u8, InvalidInterval> {
+        if interval_us % unit_us != 0 {
+            return Err(InvalidInterval);
+        }
+        let units = interval_us / unit_us;
+        if !units.is_power_of_two() || units.trailing_zeros() > 15 {
+            return Err(InvalidInterval);
+        }
+        Ok(units.trailing_zeros() as u8 + 1)
+    }
+
+    match (ep_type, speed) {
+        (EndpointType::Bulk | EndpointType::Control, _) => match interval_us {
+            0 => Ok(0),
+            _ => Err(InvalidInterval),
+        },
+        (EndpointType::Interrupt, Speed::Low | Speed::Full) => {
+            let min_ms = match speed {
+                Speed::Low => 10,
+                _ => 1,
+            };
+            let ms = interval_us / 1000;
+            if interval_us % 1000 != 0 || ms < min_ms || ms > 255 {
+                return Err(InvalidInterval);
+            }
+            Ok(ms as u8)
+        }
+        (EndpointType::Isochronous, Speed::Low) => Err(InvalidInterval),
+        (EndpointType::Isochronous, Speed::Full) => exponent(interval_us, 1000),
+        (EndpointType::Interrupt | EndpointType::Isochronous, Speed::High) => exponent(interval_us, 125),
+    }
+}
+
 /// A writer for USB descriptors.
 pub(crate) struct DescriptorWriter<'a> {
     pub buf: &'a mut [u8],
@@ -125,14 +195,16 @@ impl<'a> DescriptorWriter<'a> {
     pub(crate) fn configuration(&mut self, config: &Config) {
         self.num_interfaces_mark = Some(self.position + 4);
 
+        let configuration_string = config.configuration_string.map_or(0, |_| STRING_INDEX_CONFIGURATION);
+
         self.write(
             descriptor_type::CONFIGURATION,
             &[
                 0,
-                0,                   // wTotalLength
-                0,                   // bNumInterfaces
-                CONFIGURATION_VALUE, // bConfigurationValue
-                0,                   // iConfiguration
+                0,                    // wTotalLength
+                0,                    // bNumInterfaces
+                CONFIGURATION_VALUE,  // bConfigurationValue
+                configuration_string, // iConfiguration
                 0x80 | if config.self_powered { 0x40 } else { 0x00 }
                     | if config.supports_remote_wakeup { 0x20 } else { 0x00 }, // bmAttributes
                 (config.max_power / 2) as u8, // bMaxPower
@@ -147,8 +219,18 @@ impl<'a> DescriptorWriter<'a> {
     }
 
     pub(crate) fn end_configuration(&mut self) {
+        assert!(
+            self.position <= u16::MAX as usize,
+            "embassy-usb: configuration descriptor longer than wTotalLength can express"
+        );
         let position = self.position as u16;
         self.buf[2..4].copy_from_slice(&position.to_le_bytes());
+
+        // The host walks the descriptors by their bLength, which has to end exactly at wTotalLength.
+        debug_assert!(
+            lengths_add_up(&self.buf[..self.position]),
+            "embassy-usb: descriptor lengths don't add up to wTotalLength"
+        );
     }
 
     /// Writes a interface association descriptor. Call from `UsbClass::get_configuration_descriptors`
@@ -241,10 +323,12 @@ impl<'a> DescriptorWriter<'a> {
 
     /// Writes an endpoint descriptor.
     ///
+    /// The transfer type bits of `bmAttributes` are taken from the type the endpoint was
+    /// allocated with, so the descriptor always matches the hardware allocation.
+    ///
     /// # Arguments
     ///
-    /// * `endpoint` - Endpoint previously allocated with
-    ///   [`UsbDeviceBuilder`](crate::bus::UsbDeviceBuilder).
+    /// * `endpoint` - Endpoint previously allocated with [`Builder`](crate::Builder).
     /// * `synchronization_type` - The synchronization type of the endpoint.
     /// * `usage_type` - The usage type of the endpoint.
     /// * `extra_fields` - Additional, class-specific entries at the end of the endpoint descriptor.
@@ -322,26 +406,246 @@ impl<'a> DescriptorWriter<'a> {
 /// All device descriptors are always 18 bytes, so there's no need for
 /// a variable-length buffer or DescriptorWriter.
 pub(crate) fn device_descriptor(config: &Config) -> [u8; 18] {
-    [
-        18,   // bLength
-        0x01, // bDescriptorType
-        config.bcd_usb as u8,
-        (config.bcd_usb as u16 >> 8) as u8, // bcdUSB
-        config.device_class,                // bDeviceClass
-        config.device_sub_class,            // bDeviceSubClass
-        config.device_protocol,             // bDeviceProtocol
-        config.max_packet_size_0,           // bMaxPacketSize0
-        config.vendor_id as u8,
-        (config.vendor_id >> 8) as u8, // idVendor
-        config.product_id as u8,
-        (config.product_id >> 8) as u8, // idProduct
-        config.device_release as u8,
-        (config.device_release >> 8) as u8,    // bcdDevice
-        config.manufacturer.map_or(0, |_| 1),  // iManufacturer
-        config.product.map_or(0, |_| 2),       // iProduct
-        config.serial_number.map_or(0, |_| 3), // iSerialNumber
-        1,                                     // bNumConfigurations
-    ]
+    DeviceDescriptor {
+        bcd_usb: config.bcd_usb as u16,
+        device_class: config.device_class,
+        device_sub_class: config.device_sub_class,
+        device_protocol: config.device_protocol,
+        max_packet_size_0: config.max_packet_size_0,
+        vendor_id: config.vendor_id,
+        product_id: config.product_id,
+        device_release: config.device_release,
+        manufacturer: config.manufacturer.map(|_| StringIndex::new(1)),
+        product: config.product.map(|_| StringIndex::new(2)),
+        serial_number: config.serial_number.map(|_| StringIndex::new(3)),
+        num_configurations: 1,
+    }
+    .to_bytes()
+}
+
+/// Returns true if `buf` is a sequence of descriptors whose bLength fields cover it exactly.
+fn lengths_add_up(buf: &[u8]) -> bool {
+    let mut i = 0;
+    while i < buf.len() {
+        match buf[i] {
+            0 | 1 => return false,
+            len => i += len as usize,
+        }
+    }
+    i == buf.len()
+}
+
+fn string_index(index: Option<StringIndex>) -> u8 {
+    index.map_or(0, u8::from)
+}
+
+/// A standard device descriptor.
+///
+/// The typed descriptor structs serialize to the exact byte layout of the USB specification,
+/// with multi-byte fields in little-endian order. They can be used to write descriptors that
+/// the builder doesn't generate, e.g. with [`Builder::device_descriptor`](crate::Builder::device_descriptor).
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DeviceDescriptor {
+    /// bcdUSB, the USB specification release, e.g. `0x0210` for USB 2.1.
+    pub bcd_usb: u16,
+    /// bDeviceClass
+    pub device_class: u8,
+    /// bDeviceSubClass
+    pub device_sub_class: u8,
+    /// bDeviceProtocol
+    pub device_protocol: u8,
+    /// bMaxPacketSize0
+    pub max_packet_size_0: u8,
+    /// idVendor
+    pub vendor_id: u16,
+    /// idProduct
+    pub product_id: u16,
+    /// bcdDevice
+    pub device_release: u16,
+    /// iManufacturer
+    pub manufacturer: Option<StringIndex>,
+    /// iProduct
+    pub product: Option<StringIndex>,
+    /// iSerialNumber
+    pub serial_number: Option<StringIndex>,
+    /// bNumConfigurations
+    pub num_configurations: u8,
+}
+
+impl DeviceDescriptor {
+    /// Serializes the descriptor.
+    pub fn to_bytes(&self) -> [u8; 18] {
+        let [bcd_usb_lo, bcd_usb_hi] = self.bcd_usb.to_le_bytes();
+        let [vendor_id_lo, vendor_id_hi] = self.vendor_id.to_le_bytes();
+        let [product_id_lo, product_id_hi] = self.product_id.to_le_bytes();
+        let [device_release_lo, device_release_hi] = self.device_release.to_le_bytes();
+        [
+            18, // bLength
+            descriptor_type::DEVICE,
+            bcd_usb_lo,
+            bcd_usb_hi,
+            self.device_class,
+            self.device_sub_class,
+            self.device_protocol,
+            self.max_packet_size_0,
+            vendor_id_lo,
+            vendor_id_hi,
+            product_id_lo,
+            product_id_hi,
+            device_release_lo,
+            device_release_hi,
+            string_index(self.manufacturer),
+            string_index(self.product),
+            string_index(self.serial_number),
+            self.num_configurations,
+        ]
+    }
+}
+
+/// A standard configuration descriptor header.
+///
+/// See [`DeviceDescriptor`].
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ConfigurationDescriptor {
+    /// wTotalLength, the length of this and all following interface, endpoint and class descriptors.
+    pub total_length: u16,
+    /// bNumInterfaces
+    pub num_interfaces: u8,
+    /// bConfigurationValue
+    pub configuration_value: u8,
+    /// iConfiguration
+    pub configuration: Option<StringIndex>,
+    /// bmAttributes. Bit 7 is reserved and always written as 1.
+    pub attributes: u8,
+    /// bMaxPower, in units of 2 mA.
+    pub max_power: u8,
+}
+
+impl ConfigurationDescriptor {
+    /// Serializes the descriptor.
+    pub fn to_bytes(&self) -> [u8; 9] {
+        let [total_length_lo, total_length_hi] = self.total_length.to_le_bytes();
+        [
+            9, // bLength
+            descriptor_type::CONFIGURATION,
+            total_length_lo,
+            total_length_hi,
+            self.num_interfaces,
+            self.configuration_value,
+            string_index(self.configuration),
+            self.attributes | 0x80,
+            self.max_power,
+        ]
+    }
+}
+
+/// A standard interface descriptor.
+///
+/// See [`DeviceDescriptor`].
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct InterfaceDescriptor {
+    /// bInterfaceNumber
+    pub interface_number: InterfaceNumber,
+    /// bAlternateSetting
+    pub alternate_setting: u8,
+    /// bNumEndpoints
+    pub num_endpoints: u8,
+    /// bInterfaceClass
+    pub interface_class: u8,
+    /// bInterfaceSubClass
+    pub interface_sub_class: u8,
+    /// bInterfaceProtocol
+    pub interface_protocol: u8,
+    /// iInterface
+    pub interface: Option<StringIndex>,
+}
+
+impl InterfaceDescriptor {
+    /// Serializes the descriptor.
+    pub fn to_bytes(&self) -> [u8; 9] {
+        [
+            9, // bLength
+            descriptor_type::INTERFACE,
+            self.interface_number.into(),
+            self.alternate_setting,
+            self.num_endpoints,
+            self.interface_class,
+            self.interface_sub_class,
+            self.interface_protocol,
+            string_index(self.interface),
+        ]
+    }
+}
+
+/// A standard endpoint descriptor.
+///
+/// See [`DeviceDescriptor`].
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct EndpointDescriptor {
+    /// bEndpointAddress
+    pub address: EndpointAddress,
+    /// bmAttributes: transfer type, and synchronization and usage type for isochronous endpoints.
+    pub attributes: u8,
+    /// wMaxPacketSize
+    pub max_packet_size: u16,
+    /// bInterval. See [`encode_interval`].
+    pub interval: u8,
+}
+
+impl EndpointDescriptor {
+    /// Serializes the descriptor.
+    pub fn to_bytes(&self) -> [u8; 7] {
+        let [max_packet_size_lo, max_packet_size_hi] = self.max_packet_size.to_le_bytes();
+        [
+            7, // bLength
+            descriptor_type::ENDPOINT,
+            self.address.into(),
+            self.attributes,
+            max_packet_size_lo,
+            max_packet_size_hi,
+            self.interval,
+        ]
+    }
+}
+
+/// A standard interface association descriptor (IAD).
+///
+/// See [`DeviceDescriptor`].
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct InterfaceAssociationDescriptor {
+    /// bFirstInterface
+    pub first_interface: InterfaceNumber,
+    /// bInterfaceCount
+    pub interface_count: u8,
+    /// bFunctionClass
+    pub function_class: u8,
+    /// bFunctionSubClass
+    pub function_sub_class: u8,
+    /// bFunctionProtocol
+    pub function_protocol: u8,
+    /// iFunction
+    pub function: Option<StringIndex>,
+}
+
+impl InterfaceAssociationDescriptor {
+    /// Serializes the descriptor.
+    pub fn to_bytes(&self) -> [u8; 8] {
+        [
+            8, // bLength
+            descriptor_type::IAD,
+            self.first_interface.into(),
+            self.interface_count,
+            self.function_class,
+            self.function_sub_class,
+            self.function_protocol,
+            string_index(self.function),
+        ]
+    }
 }
 
 /// Create a new Device Qualifier Descriptor array.
@@ -363,6 +667,44 @@ pub(crate) fn device_qualifier_descriptor(config: &Config) -> [u8; 10] {
     ]
 }
 
+/// Lane speed exponent of a [`SublinkSpeedAttribute`].
+#[cfg(feature = "superspeed-plus")]
+#[repr(u8)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LaneSpeedExponent {
+    /// Bits per second.
+    BitsPerSecond = 0,
+    /// Kilobits per second.
+    KilobitsPerSecond = 1,
+    /// Megabits per second.
+    MegabitsPerSecond = 2,
+    /// Gigabits per second.
+    GigabitsPerSecond = 3,
+}
+
+/// A sublink speed attribute of a SuperSpeedPlus device capability descriptor.
+///
+/// See [`BosWriter::superspeed_plus_capability`].
+#[cfg(feature = "superspeed-plus")]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SublinkSpeedAttribute {
+    /// Sublink speed attribute ID (SSID), 0 to 15.
+    pub id: u8,
+    /// Lane speed exponent.
+    pub exponent: LaneSpeedExponent,
+    /// Whether the sublink is asymmetric.
+    pub asymmetric: bool,
+    /// Whether this attribute describes the transmit (and not receive) direction of an
+    /// asymmetric sublink.
+    pub transmit: bool,
+    /// Whether the link protocol is SuperSpeedPlus. If `false`, it is SuperSpeed.
+    pub superspeed_plus: bool,
+    /// Lane speed mantissa.
+    pub mantissa: u16,
+}
+
 /// A writer for Binary Object Store descriptor.
 pub struct BosWriter<'a> {
     pub(crate) writer: DescriptorWriter<'a>,
@@ -377,7 +719,7 @@ impl<'a> BosWriter<'a> {
         }
     }
 
-    pub(crate) fn bos(&mut self) {
+    pub(crate) fn bos(&mut self, lpm: bool) {
         if (self.writer.buf.len() - self.writer.position) < 5 {
             return;
         }
@@ -391,7 +733,17 @@ impl<'a> BosWriter<'a> {
             &[],
         );
 
-        self.capability(capability_type::USB_2_0_EXTENSION, &[0; 4]);
+        self.usb_2_0_extension(lpm);
+    }
+
+    /// Writes a USB 2.0 extension capability descriptor to a BOS
+    ///
+    /// # Arguments
+    ///
+    /// * `lpm` - Whether the device supports Link Power Management
+    pub fn usb_2_0_extension(&mut self, lpm: bool) {
+        let attributes: u32 = if lpm { 1 << 1 } else { 0 };
+        self.capability(capability_type::USB_2_0_EXTENSION, &attributes.to_le_bytes());
     }
 
     /// Writes capability descriptor to a BOS
@@ -423,6 +775,71 @@ impl<'a> BosWriter<'a> {
         self.writer.position = start + blen;
     }
 
+    /// Writes a SuperSpeedPlus device capability descriptor to a BOS
+    ///
+    /// # Arguments
+    ///
+    /// * `min_speed_id` - Sublink speed attribute ID of the minimum speed at which the device
+    ///   is fully functional
+    /// * `min_rx_lanes` - Minimum receive lane count at which the device is fully functional
+    /// * `min_tx_lanes` - Minimum transmit lane count at which the device is fully functional
+    /// * `attributes` - Sublink speed attributes, 1 to 32 entries
+    #[cfg(feature = "superspeed-plus")]
+    pub fn superspeed_plus_capability(
+        &mut self,
+        min_speed_id: u8,
+        min_rx_lanes: u8,
+        min_tx_lanes: u8,
+        attributes: &[SublinkSpeedAttribute],
+    ) {
+        assert!(
+            !attributes.is_empty() && attributes.len() <= 32,
+            "SuperSpeedPlus capability needs 1 to 32 sublink speed attributes"
+        );
+        assert!(min_speed_id < 16 && min_rx_lanes < 16 && min_tx_lanes < 16);
+
+        let mut ids: u16 = 0;
+        for attr in attributes {
+            assert!(attr.id < 16, "sublink speed attribute ID out of range");
+            ids |= 1 << attr.id;
+        }
+
+        let ssac = (attributes.len() - 1) as u32;
+        let ssic = ids.count_ones() - 1;
+        let bm_attributes = ssac | (ssic << 5);
+        let functionality_support =
+            (min_speed_id as u16) | ((min_rx_lanes as u16) << 8) | ((min_tx_lanes as u16) << 12);
+
+        let mut data = [0u8; 9 + 4 * 32];
+        // data[0] is bReserved
+        data[1..5].copy_from_slice(&bm_attributes.to_le_bytes());
+        data[5..7].copy_from_slice(&functionality_support.to_le_bytes());
+        // data[7..9] is wReserved
+
+        let mut pos = 9;
+        for attr in attributes {
+            let sublink_attr = (attr.id as u32)
+                | ((attr.exponent as u32) << 4)
+                | ((attr.asymmetric as u32) << 6)
+                | ((attr.transmit as u32) << 7)
+                | ((attr.superspeed_plus as u32) << 14)
+                | ((attr.mantissa as u32) << 16);
+            data[pos..pos + 4].copy_from_slice(&sublink_attr.to_le_bytes());
+            pos += 4;
+        }
+
+        self.capability(capability_type::SUPERSPEED_PLUS, &data[..pos]);
+    }
+
+    /// Returns the number of capabilities written to the BOS so far.
+    pub(crate) fn num_capabilities(&self) -> u8 {
+        if self.writer.position == 0 {
+            0
+        } else {
+            self.writer.buf[4]
+        }
+    }
+
     pub(crate) fn end_bos(&mut self) {
         if self.writer.position == 0 {
             return;
@@ -20,13 +20,27 @@ mod config {
     include!(concat!(env!("OUT_DIR"), "/config.rs"));
 }
 
-use embassy_futures::select::{select, Either};
+use core::cell::RefCell;
+use core::convert::Infallible;
+use core::future::{poll_fn, Future};
+use core::pin::pin;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+
+use embassy_futures::select::{select, select3, select4, Either, Either3, Either4};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::CriticalSectionMutex;
+use embassy_sync::signal::Signal;
+use embassy_sync::waitqueue::MultiWakerRegistration;
 use heapless::Vec;
 
-pub use crate::builder::{Builder, Config, FunctionBuilder, InterfaceAltBuilder, InterfaceBuilder, UsbVersion};
+pub use crate::builder::{
+    Buffers, Builder, BuilderError, Config, EndpointAllocFailure, EndpointAllocFailures, FunctionBuilder,
+    InterfaceAltBuilder, InterfaceBuilder, UnhandledRequests, UsbVersion,
+};
 use crate::config::{MAX_HANDLER_COUNT, MAX_INTERFACE_COUNT};
 use crate::control::{InResponse, OutResponse, Recipient, Request, RequestType};
-use crate::descriptor::{descriptor_type, lang_id};
+use crate::descriptor::descriptor_type;
 use crate::descriptor_reader::foreach_endpoint;
 use crate::driver::{Bus, ControlPipe, Direction, Driver, EndpointAddress, Event};
 use crate::types::{InterfaceNumber, StringIndex};
@@ -54,6 +68,24 @@ pub enum UsbDeviceState {
     Configured,
 }
 
+/// USB 2.0 link power state, returned by [`UsbDevice::link_power_state`].
+///
+/// This is a finer view of the bus than [`UsbDeviceState`], for applications that adjust clocks
+/// or power domains to what the link is doing.
+///
+/// Only L0, L2 and L3 are tracked. Drivers don't report LPM transitions, so a link in L1 sleep
+/// shows up as [`On`](Self::On).
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LinkPowerState {
+    /// L0: the link is active.
+    On,
+    /// L2: the bus is suspended.
+    Suspend,
+    /// L3: the device is unpowered or disabled.
+    Off,
+}
+
 /// Error returned by [`UsbDevice::remote_wakeup`].
 #[derive(PartialEq, Eq, Copy, Clone, Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -80,11 +112,18 @@ const STRING_INDEX_MANUFACTURER: u8 = 1;
 const STRING_INDEX_PRODUCT: u8 = 2;
 const STRING_INDEX_SERIAL_NUMBER: u8 = 3;
 const STRING_INDEX_CUSTOM_START: u8 = 4;
+/// Only allocated if [`Config::configuration_string`] is set, so custom strings keep starting at 4.
+const STRING_INDEX_CONFIGURATION: u8 = STRING_INDEX_CUSTOM_START;
 
 /// Handler for device events and control requests.
 ///
 /// All methods are optional callbacks that will be called by
 /// [`UsbDevice::run()`](crate::UsbDevice::run)
+///
+/// Control transfers on endpoint 0 are strictly serial. The control pipe is owned by the
+/// [`UsbDevice`] and only used from its run loop, which handles one transfer at a time and calls
+/// handlers one after another. Handlers only answer requests, they can't start control transfers
+/// themselves, so classes in a composite device can't interleave transfers on endpoint 0.
 pub trait Handler {
     /// Called when the USB device has been enabled or disabled.
     fn enabled(&mut self, _enabled: bool) {}
@@ -99,11 +138,41 @@ pub trait Handler {
     fn configured(&mut self, _configured: bool) {}
 
     /// Called when the bus has entered or exited the suspend state.
+    ///
+    /// Classes can use this to quiesce timers and buffers while suspended, and re-arm them on
+    /// resume. It is called after the device has recorded the new state, and after endpoints
+    /// have been disabled or re-enabled when [`Config::disable_endpoints_on_suspend`] is set.
+    /// The device state (e.g. `Configured`) is kept across a suspend, so on resume classes
+    /// continue where they left off unless a bus reset follows.
     fn suspended(&mut self, _suspended: bool) {}
 
     /// Called when remote wakeup feature is enabled or disabled.
+    ///
+    /// This is called when the host sets or clears the `DEVICE_REMOTE_WAKEUP` feature, and
+    /// with `false` if a bus reset or disabling the device clears a previously enabled feature.
+    /// Use it to arm or disarm the hardware that triggers [`UsbDevice::remote_wakeup`].
     fn remote_wakeup_enabled(&mut self, _enabled: bool) {}
 
+    /// Called when the host clears the halt feature of an endpoint with
+    /// CLEAR_FEATURE(ENDPOINT_HALT).
+    ///
+    /// The stall has already been cleared when this is called. Hosts send this to recover a
+    /// halted endpoint, and it also resets the endpoint's data toggle, so classes that keep
+    /// per-endpoint protocol state should check whether `ep_addr` is theirs and reset it.
+    fn endpoint_unhalted(&mut self, ep_addr: EndpointAddress) {
+        let _ = ep_addr;
+    }
+
+    /// Called whenever the task running the [`UsbDevice`] is polled, while the bus isn't suspended.
+    ///
+    /// This lets a class do periodic or background work outside of control requests, such as
+    /// flushing batched data. Register `cx`'s waker with whatever signals new work, and the run
+    /// loop is woken to call this again. It is polled after bus events and control requests, and
+    /// must not block, so control traffic isn't starved.
+    fn poll(&mut self, cx: &mut Context<'_>) {
+        let _ = cx;
+    }
+
     /// Called when a "set alternate setting" control request is done on the interface.
     fn set_alternate_setting(&mut self, iface: InterfaceNumber, alternate_setting: u8) {
         let _ = iface;
@@ -117,6 +186,11 @@ pub trait Handler {
     /// * `req` - The request from the SETUP packet.
     /// * `data` - The data from the request.
     ///
+    /// `data` is never truncated: requests with a `req.length` larger than the control buffer
+    /// passed to [`Builder::new`] are rejected before reaching any handler. If the host ends the
+    /// data stage early with a short packet, `data` is shorter than `req.length`, so handlers
+    /// that need the full payload should compare the two.
+    ///
     /// # Returns
     ///
     /// If you didn't handle this request (for example if it's for the wrong interface), return
@@ -125,11 +199,31 @@ pub trait Handler {
     ///
     /// If you did, return `Some` with either `Accepted` or `Rejected`. This will make the USB stack
     /// respond to the control request, and stop calling other handlers.
+    ///
+    /// Requests are passed to handlers regardless of the device state, so this can be called before
+    /// the device is configured.
     fn control_out(&mut self, req: Request, data: &[u8]) -> Option<OutResponse> {
         let _ = (req, data);
         None
     }
 
+    /// Called after [`Handler::control_out`] returned `Some(OutResponse::Deferred)`, until it
+    /// returns `Ready`.
+    ///
+    /// This lets a handler pass a request on to another task and finish it later. Until then the
+    /// status stage is held off (NAKed), and the device handles no other control requests, so
+    /// complete deferred requests quickly. Hosts usually give class and vendor requests 5 seconds,
+    /// but the device appears hung for that long. Wake `cx` when the result is ready.
+    ///
+    /// If the host gives up on the request first, by sending another SETUP packet or with a bus
+    /// event such as a reset, this isn't called again and the result is never reported.
+    ///
+    /// Returning `Ready(OutResponse::Deferred)` rejects the request.
+    fn poll_deferred(&mut self, cx: &mut Context<'_>) -> Poll<OutResponse> {
+        let _ = cx;
+        Poll::Ready(OutResponse::Rejected)
+    }
+
     /// Called when a control request is received with direction DeviceToHost.
     ///
     /// You should write the response somewhere (usually to `buf`, but you may use another buffer
@@ -147,12 +241,77 @@ pub trait Handler {
     ///
     /// If you did, return `Some` with either `Accepted` or `Rejected`. This will make the USB stack
     /// respond to the control request, and stop calling other handlers.
+    ///
+    /// Requests are passed to handlers regardless of the device state. Hosts fetch class-specific
+    /// descriptors such as HID report descriptors while the device is only addressed, so those
+    /// must not depend on [`Handler::configured`] having been called.
+    ///
+    /// Standard GET_STATUS requests for an interface are offered to the handler owning it, so
+    /// classes can report interface-specific status bits. If it returns `None`, the stack replies
+    /// with all status bits cleared.
     fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
         let _ = (req, buf);
         None
     }
 
+    /// Called when a control request is received, in either direction.
+    ///
+    /// This is an alternative to implementing [`Handler::control_out`] and
+    /// [`Handler::control_in`] separately, for protocols where it's easier to handle both
+    /// directions in one place, such as reading and writing device registers. The default
+    /// implementation calls those two methods, so only override one or the other.
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - The request from the SETUP packet. `req.direction` tells which kind it is.
+    /// * `data` - For `Out` requests, the data from the request. Empty for `In` requests.
+    /// * `buf` - For `In` requests, a buffer to write the response to. Empty for `Out` requests.
+    ///
+    /// # Returns
+    ///
+    /// `None` if you didn't handle the request, like the split methods. Otherwise, for `In`
+    /// requests return the response data as for [`Handler::control_in`]. For `Out` requests,
+    /// return `InResponse::Accepted` (the data is ignored) or `InResponse::Rejected`. Responses
+    /// can't be deferred from here: return [`OutResponse::Deferred`] from
+    /// [`Handler::control_out`] instead, which the stack asks first for `Out` requests.
+    fn control_transfer<'a>(&'a mut self, req: Request, data: &[u8], buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        match req.direction {
+            Direction::In => self.control_in(req, buf),
+            Direction::Out => self.control_out(req, data).map(|res| match res {
+                OutResponse::Accepted => InResponse::Accepted(&[]),
+                OutResponse::Rejected | OutResponse::Deferred => InResponse::Rejected,
+            }),
+        }
+    }
+
+    /// Called when a device GET_DESCRIPTOR control request is received for a descriptor type the
+    /// USB stack doesn't serve itself.
+    ///
+    /// This lets a handler serve vendor-specific descriptor types through the standard request.
+    /// `descriptor_type` and `index` are the high and low bytes of `wValue`. Return the
+    /// descriptor like in [`Handler::control_in`], or `None` if it isn't yours. Requests no
+    /// handler answers are rejected.
+    fn get_descriptor<'a>(&'a mut self, descriptor_type: u8, index: u8, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        let _ = (descriptor_type, index, buf);
+        None
+    }
+
+    /// Called when a device SET_DESCRIPTOR control request is received.
+    ///
+    /// `descriptor_type` and `index` are the high and low bytes of `wValue`, `lang_id` is
+    /// `wIndex` (only meaningful for string descriptors) and `data` is the descriptor sent by the
+    /// host. Return `Some` with the response if you handled it, or `None` if it isn't yours.
+    /// Requests no handler answers are rejected, which is the standard behavior for devices
+    /// that don't support SET_DESCRIPTOR.
+    fn set_descriptor(&mut self, descriptor_type: u8, index: u8, lang_id: u16, data: &[u8]) -> Option<OutResponse> {
+        let _ = (descriptor_type, index, lang_id, data);
+        None
+    }
+
     /// Called when a GET_DESCRIPTOR STRING control request is received.
+    ///
+    /// `lang_id` is one of [`Config::supported_lang_ids`](crate::Config::supported_lang_ids);
+    /// requests for other languages are stalled before reaching handlers.
     fn get_string(&mut self, index: StringIndex, lang_id: u16) -> Option<&str> {
         let _ = (index, lang_id);
         None
@@ -162,13 +321,33 @@ pub trait Handler {
 struct Interface {
     current_alt_setting: u8,
     num_alt_settings: u8,
+    /// Index of the handler owning this interface, see [`Builder::handler`].
+    handler: Option<u8>,
+}
+
+/// Which handlers a delegated control request is passed to.
+enum Route {
+    All,
+    Handler(usize),
+    /// Handlers owning no interface, for requests to an interface without an owner.
+    Unowned,
+}
+
+impl Route {
+    fn includes(&self, handler: usize, interfaces: &[Interface]) -> bool {
+        match self {
+            Route::All => true,
+            Route::Handler(owner) => *owner == handler,
+            Route::Unowned => !interfaces.iter().any(|iface| iface.handler == Some(handler as u8)),
+        }
+    }
 }
 
 /// A report of the used size of the runtime allocated buffers
 #[derive(PartialEq, Eq, Copy, Clone, Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct UsbBufferReport {
-    /// Number of config descriptor bytes used
+    /// Number of config descriptor bytes used, which is also its wTotalLength
     pub config_descriptor_used: usize,
     /// Number of bos descriptor bytes used
     pub bos_descriptor_used: usize,
@@ -178,11 +357,154 @@ pub struct UsbBufferReport {
     pub control_buffer_size: usize,
 }
 
+/// A snapshot of the status of the device, returned by [`UsbDevice::status`].
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct UsbDeviceStatus {
+    /// Current device state.
+    pub state: UsbDeviceState,
+    /// Device address assigned by the host, or 0 if none.
+    pub address: u8,
+    /// Current bConfigurationValue, or [`CONFIGURATION_NONE`] if not configured.
+    pub configuration: u8,
+    /// Whether the device reports itself as self-powered.
+    pub self_powered: bool,
+    /// Whether the host has enabled remote wakeup.
+    pub remote_wakeup_enabled: bool,
+    /// Whether the bus is suspended.
+    pub suspended: bool,
+}
+
+impl UsbDeviceStatus {
+    /// Returns the link power state matching this status.
+    pub fn link_power_state(&self) -> LinkPowerState {
+        match self.state {
+            UsbDeviceState::Unpowered | UsbDeviceState::Disabled => LinkPowerState::Off,
+            _ if self.suspended => LinkPowerState::Suspend,
+            _ => LinkPowerState::On,
+        }
+    }
+}
+
+/// Number of tasks that can wait on a [`ConfiguredWatch`] at the same time.
+///
+/// This is unrelated to the number of handlers: the waiters are whatever tasks the application
+/// shares the watch with.
+const CONFIGURED_WATCH_WAITERS: usize = 4;
+
+/// Lets other tasks wait for the device to be configured, set with
+/// [`UsbDevice::set_configured_watch`].
+///
+/// The run loop owns the `UsbDevice`, so classes can't check its state directly. Share a
+/// `ConfiguredWatch` with them instead, and have them wait before starting bulk or interrupt I/O.
+///
+/// Up to 4 tasks can wait at the same time. More still work, but when a fifth task starts
+/// waiting, the others are woken early to check again, so keep to 4 to avoid spurious wakeups.
+pub struct ConfiguredWatch {
+    configured: AtomicBool,
+    wakers: CriticalSectionMutex<RefCell<MultiWakerRegistration<CONFIGURED_WATCH_WAITERS>>>,
+}
+
+impl Default for ConfiguredWatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConfiguredWatch {
+    /// Create a new `ConfiguredWatch`.
+    pub const fn new() -> Self {
+        Self {
+            configured: AtomicBool::new(false),
+            wakers: CriticalSectionMutex::new(RefCell::new(MultiWakerRegistration::new())),
+        }
+    }
+
+    /// Returns whether the device is currently configured.
+    pub fn is_configured(&self) -> bool {
+        self.configured.load(Ordering::Relaxed)
+    }
+
+    /// Waits until the host has configured the device with SET_CONFIGURATION.
+    ///
+    /// Returns immediately if the device is already configured. After a bus reset or a
+    /// SET_CONFIGURATION(0), the device is no longer configured, and calling this again waits for
+    /// the next configuration.
+    ///
+    /// This future is cancel-safe: it only observes the state, so it can be dropped at any time,
+    /// for example in a `select` with a timeout.
+    pub async fn wait_configured(&self) {
+        poll_fn(|cx| {
+            if self.is_configured() {
+                return Poll::Ready(());
+            }
+            self.wakers.lock(|w| w.borrow_mut().register(cx.waker()));
+            // Check again, the run loop may have configured the device in the meantime.
+            if self.is_configured() {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    fn set_configured(&self, configured: bool) {
+        self.configured.store(configured, Ordering::Relaxed);
+        if configured {
+            self.wakers.lock(|w| w.borrow_mut().wake());
+        }
+    }
+}
+
+/// Number of GET_DESCRIPTOR requests received per descriptor type, returned by
+/// [`UsbDevice::take_descriptor_fetch_counts`].
+#[cfg(feature = "descriptor-stats")]
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DescriptorFetchCounts {
+    /// Device descriptor requests.
+    pub device: u32,
+    /// Configuration descriptor requests.
+    pub configuration: u32,
+    /// String descriptor requests, including the language ID list.
+    pub string: u32,
+    /// BOS descriptor requests.
+    pub bos: u32,
+    /// Device qualifier descriptor requests.
+    pub device_qualifier: u32,
+    /// Requests for any other descriptor type.
+    pub other: u32,
+}
+
+#[cfg(feature = "descriptor-stats")]
+impl DescriptorFetchCounts {
+    fn count(&mut self, descriptor_type: u8) {
+        let counter = match descriptor_type {
+            descriptor_type::DEVICE => &mut self.device,
+            descriptor_type::CONFIGURATION => &mut self.configuration,
+            descriptor_type::STRING => &mut self.string,
+            descriptor_type::BOS => &mut self.bos,
+            descriptor_type::DEVICE_QUALIFIER => &mut self.device_qualifier,
+            _ => &mut self.other,
+        };
+        *counter = counter.wrapping_add(1);
+    }
+}
+
 /// Main struct for the USB device stack.
+///
+/// Everything that uses the control pipe takes `&mut self`, so nothing else can use it while a
+/// control transfer is in progress.
 pub struct UsbDevice<'d, D: Driver<'d>> {
     control_buf: &'d mut [u8],
     control: D::ControlPipe,
     inner: Inner<'d, D>,
+    liveness_counter: Option<&'d AtomicUsize>,
+    state_signal: Option<&'d Signal<CriticalSectionRawMutex, UsbDeviceStatus>>,
+    configured_watch: Option<&'d ConfiguredWatch>,
+    /// State and suspend flag last sent to `state_signal`.
+    signaled_state: (UsbDeviceState, bool),
 }
 
 struct Inner<'d, D: Driver<'d>> {
@@ -199,9 +521,17 @@ struct Inner<'d, D: Driver<'d>> {
     suspended: bool,
     remote_wakeup_enabled: bool,
     self_powered: bool,
+    #[cfg(feature = "superspeed")]
+    u1_enabled: bool,
+    #[cfg(feature = "superspeed")]
+    u2_enabled: bool,
 
     /// Our device address, or 0 if none.
     address: u8,
+    #[cfg(feature = "descriptor-stats")]
+    descriptor_fetches: DescriptorFetchCounts,
+    /// Index of the handler that deferred the current control OUT request.
+    deferred_handler: Option<usize>,
     /// SET_ADDRESS requests have special handling depending on the driver.
     /// This flag indicates that requests must be handled by `ControlPipe::accept_set_address()`
     /// instead of regular `accept()`.
@@ -216,6 +546,7 @@ impl<'d, D: Driver<'d>> UsbDevice<'d, D> {
         driver: D,
         config: Config<'d>,
         handlers: Vec<&'d mut dyn Handler, MAX_HANDLER_COUNT>,
+        device_descriptor: [u8; 18],
         config_descriptor: &'d [u8],
         bos_descriptor: &'d [u8],
         msos_descriptor: crate::msos::MsOsDescriptorSet<'d>,
@@ -225,8 +556,8 @@ impl<'d, D: Driver<'d>> UsbDevice<'d, D> {
         // Start the USB bus.
         // This prevent further allocation by consuming the driver.
         let (bus, control) = driver.start(config.max_packet_size_0 as u16);
-        let device_descriptor = descriptor::device_descriptor(&config);
         let device_qualifier_descriptor = descriptor::device_qualifier_descriptor(&config);
+        let self_powered = config.self_powered;
 
         Self {
             control_buf,
@@ -243,12 +574,23 @@ impl<'d, D: Driver<'d>> UsbDevice<'d, D> {
                 device_state: UsbDeviceState::Unpowered,
                 suspended: false,
                 remote_wakeup_enabled: false,
-                self_powered: false,
+                self_powered,
+                #[cfg(feature = "superspeed")]
+                u1_enabled: false,
+                #[cfg(feature = "superspeed")]
+                u2_enabled: false,
                 address: 0,
+                #[cfg(feature = "descriptor-stats")]
+                descriptor_fetches: DescriptorFetchCounts::default(),
                 set_address_pending: false,
+                deferred_handler: None,
                 interfaces,
                 handlers,
             },
+            liveness_counter: None,
+            state_signal: None,
+            configured_watch: None,
+            signaled_state: (UsbDeviceState::Unpowered, false),
         }
     }
 
@@ -264,8 +606,105 @@ impl<'d, D: Driver<'d>> UsbDevice<'d, D> {
         }
     }
 
+    /// Returns the current device state.
+    pub fn state(&self) -> UsbDeviceState {
+        self.inner.device_state
+    }
+
+    /// Returns the current link power state.
+    ///
+    /// Changes between `On`, `Suspend` and `Off` are also reported through the signal set with
+    /// [`UsbDevice::set_state_signal`], see [`UsbDeviceStatus::link_power_state`].
+    pub fn link_power_state(&self) -> LinkPowerState {
+        self.status().link_power_state()
+    }
+
+    /// Returns a snapshot of the current device status.
+    ///
+    /// Useful for diagnostics and logging.
+    pub fn status(&self) -> UsbDeviceStatus {
+        UsbDeviceStatus {
+            state: self.inner.device_state,
+            address: self.inner.address,
+            configuration: match self.inner.device_state {
+                UsbDeviceState::Configured => CONFIGURATION_VALUE,
+                _ => CONFIGURATION_NONE,
+            },
+            self_powered: self.inner.self_powered,
+            remote_wakeup_enabled: self.inner.remote_wakeup_enabled,
+            suspended: self.inner.suspended,
+        }
+    }
+
+    /// Sets a counter that is incremented every time the run loop has handled a bus event
+    /// or control request.
+    ///
+    /// Another task, for example one feeding a watchdog, can watch the counter to detect a
+    /// USB task that got stuck inside a driver. Note that the counter only moves when there is
+    /// bus activity: an idle or suspended device legitimately leaves it unchanged, so only
+    /// treat a counter that stopped moving as a failure while traffic is expected.
+    pub fn set_liveness_counter(&mut self, counter: &'d AtomicUsize) {
+        self.liveness_counter = Some(counter);
+    }
+
+    /// Returns the number of GET_DESCRIPTOR requests received per descriptor type since the
+    /// last call, and resets the counts.
+    ///
+    /// Calling this periodically gives the fetch rate, which helps detect hosts that fetch
+    /// descriptors in a tight loop. Requests are still answered as usual, this only observes
+    /// them.
+    #[cfg(feature = "descriptor-stats")]
+    pub fn take_descriptor_fetch_counts(&mut self) -> DescriptorFetchCounts {
+        core::mem::take(&mut self.inner.descriptor_fetches)
+    }
+
+    /// Sets a signal that is signaled with the device status every time the device state changes
+    /// or the bus is suspended or resumed.
+    ///
+    /// The run loop owns the `UsbDevice`, so this is how other tasks can wait for the device to
+    /// be configured before starting class I/O, or stop when it is suspended. A `Signal` only
+    /// keeps the latest value, so a slow task may miss intermediate states but always sees the
+    /// current one.
+    pub fn set_state_signal(&mut self, signal: &'d Signal<CriticalSectionRawMutex, UsbDeviceStatus>) {
+        self.state_signal = Some(signal);
+    }
+
+    /// Sets a [`ConfiguredWatch`] that is kept up to date with whether the device is configured.
+    pub fn set_configured_watch(&mut self, watch: &'d ConfiguredWatch) {
+        watch.set_configured(self.inner.device_state == UsbDeviceState::Configured);
+        self.configured_watch = Some(watch);
+    }
+
+    fn signal_state_change(&mut self) {
+        let state = (self.inner.device_state, self.inner.suspended);
+        if state != self.signaled_state {
+            self.signaled_state = state;
+            if let Some(signal) = self.state_signal {
+                signal.signal(self.status());
+            }
+            if let Some(watch) = self.configured_watch {
+                watch.set_configured(self.inner.device_state == UsbDeviceState::Configured);
+            }
+        }
+    }
+
+    fn feed_liveness_counter(&self) {
+        if let Some(counter) = self.liveness_counter {
+            // Only this task writes the counter, so load + store is enough and
+            // also works on targets without atomic read-modify-write.
+            counter.store(counter.load(Ordering::Relaxed).wrapping_add(1), Ordering::Relaxed);
+        }
+    }
+
     /// Runs the `UsbDevice` forever.
     ///
+    /// This only services EP0 and bus events. Class endpoints are read and written by the
+    /// tasks owning the class handles, so a class task that stops servicing its endpoints (for
+    /// example during a flash write) just makes the host see NAKs, while control requests keep
+    /// being answered. For this to work, the blocking operation must not run in the task that
+    /// runs the `UsbDevice`, nor inside a [`Handler`]. Handlers that start such an operation from
+    /// a control request can return [`OutResponse::Deferred`] and complete it from another task.
+    ///
     /// This future may leave the bus in an invalid state if it is dropped.
     /// After dropping the future, [`UsbDevice::disable()`] should be called
     /// before calling any other `UsbDevice` methods to fully reset the
@@ -287,11 +726,50 @@ impl<'d, D: Driver<'d>> UsbDevice<'d, D> {
         while !self.inner.suspended {
             let control_fut = self.control.setup();
             let bus_fut = self.inner.bus.poll();
-            match select(bus_fut, control_fut).await {
-                Either::First(evt) => self.inner.handle_bus_event(evt).await,
-                Either::Second(req) => self.handle_control(req).await,
+            let handlers_fut = poll_handlers(&mut self.inner.handlers);
+            match select3(bus_fut, control_fut, handlers_fut).await {
+                Either3::First(evt) => self.inner.handle_bus_event(evt).await,
+                Either3::Second(req) => self.handle_control(req).await,
+                Either3::Third(never) => match never {},
+            }
+            self.feed_liveness_counter();
+            self.signal_state_change();
+        }
+    }
+
+    /// Runs the `UsbDevice` until `stop` resolves, then disables it.
+    ///
+    /// Use this to tear down USB at a safe point, for example before entering DFU or
+    /// reconfiguring the PHY for host mode. `stop` is only checked between control transfers
+    /// and bus events, so a control transfer that has already started is completed first.
+    /// Afterwards the device is detached from the bus as if by [`UsbDevice::disable()`].
+    ///
+    /// Disabling the device cancels outstanding class transfers: pending endpoint reads and
+    /// writes return [`EndpointError::Disabled`](crate::driver::EndpointError::Disabled).
+    pub async fn run_until<F: Future>(&mut self, stop: F) {
+        let mut stop = pin!(stop);
+        loop {
+            if self.inner.suspended {
+                match select(self.inner.bus.poll(), stop.as_mut()).await {
+                    Either::First(evt) => self.inner.handle_bus_event(evt).await,
+                    Either::Second(_) => break,
+                }
+            } else {
+                let control_fut = self.control.setup();
+                let bus_fut = self.inner.bus.poll();
+                let handlers_fut = poll_handlers(&mut self.inner.handlers);
+                match select4(bus_fut, control_fut, handlers_fut, stop.as_mut()).await {
+                    Either4::First(evt) => self.inner.handle_bus_event(evt).await,
+                    Either4::Second(req) => self.handle_control(req).await,
+                    Either4::Third(never) => match never {},
+                    Either4::Fourth(_) => break,
+                }
             }
+            self.feed_liveness_counter();
+            self.signal_state_change();
         }
+
+        self.disable().await;
     }
 
     /// Disables the USB peripheral.
@@ -300,11 +778,14 @@ impl<'d, D: Driver<'d>> UsbDevice<'d, D> {
             self.inner.bus.disable().await;
             self.inner.device_state = UsbDeviceState::Disabled;
             self.inner.suspended = false;
-            self.inner.remote_wakeup_enabled = false;
+            if self.inner.remote_wakeup_enabled {
+                self.inner.set_remote_wakeup_enabled(false);
+            }
 
             for h in &mut self.inner.handlers {
                 h.enabled(false);
             }
+            self.signal_state_change();
         }
     }
 
@@ -315,6 +796,8 @@ impl<'d, D: Driver<'d>> UsbDevice<'d, D> {
         while self.inner.suspended {
             let evt = self.inner.bus.poll().await;
             self.inner.handle_bus_event(evt).await;
+            self.feed_liveness_counter();
+            self.signal_state_change();
         }
     }
 
@@ -334,6 +817,7 @@ impl<'d, D: Driver<'d>> UsbDevice<'d, D> {
             for h in &mut self.inner.handlers {
                 h.suspended(false);
             }
+            self.signal_state_change();
 
             Ok(())
         } else {
@@ -341,14 +825,35 @@ impl<'d, D: Driver<'d>> UsbDevice<'d, D> {
         }
     }
 
+    /// Sets the STALL condition on all endpoints of all classes.
+    ///
+    /// This can be used to quiesce all class traffic, for example while switching operating
+    /// modes. The host sees a STALL handshake on every transfer to these endpoints and will
+    /// usually try to recover with CLEAR_FEATURE(ENDPOINT_HALT), which clears the stall again.
+    ///
+    /// The control endpoint (EP0) is not affected, so control requests keep working.
+    pub fn stall_all_endpoints(&mut self) {
+        self.inner.set_all_endpoints_stalled(true);
+    }
+
+    /// Clears the STALL condition on all endpoints of all classes.
+    ///
+    /// See [`UsbDevice::stall_all_endpoints`].
+    pub fn clear_all_stalls(&mut self) {
+        self.inner.set_all_endpoints_stalled(false);
+    }
+
     async fn handle_control(&mut self, req: [u8; 8]) {
-        let req = Request::parse(&req);
+        let mut next = Some(req);
+        while let Some(req) = next.take() {
+            let req = Request::parse(&req);
 
-        trace!("control request: {:?}", req);
+            trace!("control request: {:?}", req);
 
-        match req.direction {
-            Direction::In => self.handle_control_in(req).await,
-            Direction::Out => self.handle_control_out(req).await,
+            match req.direction {
+                Direction::In => self.handle_control_in(req).await,
+                Direction::Out => next = self.handle_control_out(req).await,
+            }
         }
     }
 
@@ -368,7 +873,11 @@ impl<'d, D: Driver<'d>> UsbDevice<'d, D> {
         }
 
         match self.inner.handle_control_in(req, self.control_buf) {
+            // With wLength = 0 there is no data stage, only the status stage.
+            InResponse::Accepted(_) if resp_length == 0 => self.control.accept().await,
             InResponse::Accepted(data) => {
+                // This is the only place IN data is sent, so responses from both the stack
+                // and handlers never exceed wLength.
                 let len = data.len().min(resp_length);
                 let need_zlp = len != resp_length && (len % max_packet_size) == 0;
 
@@ -390,7 +899,8 @@ impl<'d, D: Driver<'d>> UsbDevice<'d, D> {
         }
     }
 
-    async fn handle_control_out(&mut self, req: Request) {
+    /// Returns the next SETUP packet if the host sent one while the response was deferred.
+    async fn handle_control_out(&mut self, req: Request) -> Option<[u8; 8]> {
         let req_length = req.length as usize;
         let max_packet_size = self.control.max_packet_size();
         let mut total = 0;
@@ -402,7 +912,7 @@ impl<'d, D: Driver<'d>> UsbDevice<'d, D> {
                 self.control_buf.len()
             );
             self.control.reject().await;
-            return;
+            return None;
         }
 
         let chunks = self.control_buf[..req_length].chunks_mut(max_packet_size);
@@ -411,7 +921,7 @@ impl<'d, D: Driver<'d>> UsbDevice<'d, D> {
                 Ok(x) => x,
                 Err(e) => {
                     warn!("usb: failed to read CONTROL OUT data stage: {:?}", e);
-                    return;
+                    return None;
                 }
             };
             total += size;
@@ -426,7 +936,25 @@ impl<'d, D: Driver<'d>> UsbDevice<'d, D> {
         #[cfg(not(feature = "defmt"))]
         trace!("  control out data: {:02x?}", data);
 
-        match self.inner.handle_control_out(req, data) {
+        let mut res = self.inner.handle_control_out(req, data);
+        if res == OutResponse::Deferred {
+            // The host abandons the request by resetting the bus or starting another control
+            // transfer. Neither should wait for the handler, and there is nothing left to answer.
+            match self.inner.wait_deferred(&mut self.control).await {
+                Either3::First(r) => res = r,
+                Either3::Second(evt) => {
+                    debug!("usb: bus event while a control OUT response was deferred, abandoning it");
+                    self.inner.handle_bus_event(evt).await;
+                    return None;
+                }
+                Either3::Third(setup) => {
+                    debug!("usb: SETUP while a control OUT response was deferred, abandoning it");
+                    return Some(setup);
+                }
+            }
+        }
+
+        match res {
             OutResponse::Accepted => {
                 if self.inner.set_address_pending {
                     self.control.accept_set_address(self.inner.address).await;
@@ -435,19 +963,58 @@ impl<'d, D: Driver<'d>> UsbDevice<'d, D> {
                     self.control.accept().await;
                 }
             }
-            OutResponse::Rejected => self.control.reject().await,
+            OutResponse::Rejected | OutResponse::Deferred => self.control.reject().await,
         }
+        None
     }
 }
 
 impl<'d, D: Driver<'d>> Inner<'d, D> {
+    fn set_all_endpoints_stalled(&mut self, stalled: bool) {
+        foreach_endpoint(self.config_descriptor, |ep| {
+            self.bus.endpoint_set_stalled(ep.ep_address, stalled);
+        })
+        .unwrap();
+    }
+
+    /// Enables all endpoints of the selected alt settings, and disables the others.
+    fn enable_configured_endpoints(&mut self) {
+        foreach_endpoint(self.config_descriptor, |ep| {
+            let iface = &self.interfaces[ep.interface.0 as usize];
+            self.bus
+                .endpoint_set_enabled(ep.ep_address, iface.current_alt_setting == ep.interface_alt);
+        })
+        .unwrap();
+    }
+
+    fn disable_all_endpoints(&mut self) {
+        foreach_endpoint(self.config_descriptor, |ep| {
+            self.bus.endpoint_set_enabled(ep.ep_address, false);
+        })
+        .unwrap();
+    }
+
+    fn set_remote_wakeup_enabled(&mut self, enabled: bool) {
+        self.remote_wakeup_enabled = enabled;
+        for h in &mut self.handlers {
+            h.remote_wakeup_enabled(enabled);
+        }
+    }
+
     async fn handle_bus_event(&mut self, evt: Event) {
         match evt {
             Event::Reset => {
                 trace!("usb: reset");
                 self.device_state = UsbDeviceState::Default;
                 self.suspended = false;
-                self.remote_wakeup_enabled = false;
+                if self.remote_wakeup_enabled {
+                    self.set_remote_wakeup_enabled(false);
+                }
+                #[cfg(feature = "superspeed")]
+                {
+                    self.u1_enabled = false;
+                    self.u2_enabled = false;
+                }
                 self.address = 0;
 
                 for h in &mut self.handlers {
@@ -463,8 +1030,13 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
                 }
             }
             Event::Resume => {
+                // Suspend doesn't change `device_state`, so a resume without a bus reset
+                // continues in the state the device was in before.
                 trace!("usb: resume");
                 self.suspended = false;
+                if self.config.disable_endpoints_on_suspend && self.device_state == UsbDeviceState::Configured {
+                    self.enable_configured_endpoints();
+                }
                 for h in &mut self.handlers {
                     h.suspended(false);
                 }
@@ -472,6 +1044,9 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
             Event::Suspend => {
                 trace!("usb: suspend");
                 self.suspended = true;
+                if self.config.disable_endpoints_on_suspend {
+                    self.disable_all_endpoints();
+                }
                 for h in &mut self.handlers {
                     h.suspended(true);
                 }
@@ -504,19 +1079,38 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
         match (req.request_type, req.recipient) {
             (RequestType::Standard, Recipient::Device) => match (req.request, req.value) {
                 (Request::CLEAR_FEATURE, Request::FEATURE_DEVICE_REMOTE_WAKEUP) => {
-                    self.remote_wakeup_enabled = false;
-                    for h in &mut self.handlers {
-                        h.remote_wakeup_enabled(false);
-                    }
+                    self.set_remote_wakeup_enabled(false);
                     OutResponse::Accepted
                 }
                 (Request::SET_FEATURE, Request::FEATURE_DEVICE_REMOTE_WAKEUP) => {
-                    self.remote_wakeup_enabled = true;
-                    for h in &mut self.handlers {
-                        h.remote_wakeup_enabled(true);
-                    }
+                    self.set_remote_wakeup_enabled(true);
                     OutResponse::Accepted
                 }
+                #[cfg(feature = "superspeed")]
+                (
+                    request @ (Request::SET_FEATURE | Request::CLEAR_FEATURE),
+                    feature @ (Request::FEATURE_U1_ENABLE | Request::FEATURE_U2_ENABLE),
+                ) => {
+                    // Only valid in the Configured state (USB 3.2 spec 9.4.9).
+                    if self.device_state != UsbDeviceState::Configured {
+                        return OutResponse::Rejected;
+                    }
+                    let enabled = request == Request::SET_FEATURE;
+                    let res = match feature {
+                        Request::FEATURE_U1_ENABLE => self.bus.set_u1_enabled(enabled),
+                        _ => self.bus.set_u2_enabled(enabled),
+                    };
+                    match res {
+                        Ok(()) => {
+                            match feature {
+                                Request::FEATURE_U1_ENABLE => self.u1_enabled = enabled,
+                                _ => self.u2_enabled = enabled,
+                            }
+                            OutResponse::Accepted
+                        }
+                        Err(_) => OutResponse::Rejected,
+                    }
+                }
                 (Request::SET_ADDRESS, addr @ 1..=127) => {
                     self.address = addr as u8;
                     self.set_address_pending = true;
@@ -526,17 +1120,30 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
                     }
                     OutResponse::Accepted
                 }
+                (Request::SET_ADDRESS, 0) => {
+                    // Address 0 takes an addressed device back to the Default state.
+                    // USB 2.0 leaves this unspecified for configured devices, treat them the same
+                    // after tearing down the configuration like SET_CONFIGURATION(0) does.
+                    if self.device_state == UsbDeviceState::Configured {
+                        debug!("SET_ADDRESS(0): unconfigured");
+                        self.disable_all_endpoints();
+                        for h in &mut self.handlers {
+                            h.configured(false);
+                        }
+                    }
+                    self.address = 0;
+                    self.set_address_pending = true;
+                    self.device_state = UsbDeviceState::Default;
+                    for h in &mut self.handlers {
+                        h.addressed(0);
+                    }
+                    OutResponse::Accepted
+                }
                 (Request::SET_CONFIGURATION, CONFIGURATION_VALUE_U16) => {
                     debug!("SET_CONFIGURATION: configured");
                     self.device_state = UsbDeviceState::Configured;
 
-                    // Enable all endpoints of selected alt settings.
-                    foreach_endpoint(self.config_descriptor, |ep| {
-                        let iface = &self.interfaces[ep.interface.0 as usize];
-                        self.bus
-                            .endpoint_set_enabled(ep.ep_address, iface.current_alt_setting == ep.interface_alt);
-                    })
-                    .unwrap();
+                    self.enable_configured_endpoints();
 
                     // Notify handlers.
                     for h in &mut self.handlers {
@@ -550,11 +1157,7 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
                         debug!("SET_CONFIGURATION: unconfigured");
                         self.device_state = UsbDeviceState::Addressed;
 
-                        // Disable all endpoints.
-                        foreach_endpoint(self.config_descriptor, |ep| {
-                            self.bus.endpoint_set_enabled(ep.ep_address, false);
-                        })
-                        .unwrap();
+                        self.disable_all_endpoints();
 
                         // Notify handlers.
                         for h in &mut self.handlers {
@@ -563,6 +1166,13 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
                     }
                     OutResponse::Accepted
                 }
+                (Request::SET_DESCRIPTOR, _) => {
+                    let (dtype, index) = req.descriptor_type_index();
+                    self.handlers
+                        .iter_mut()
+                        .find_map(|h| h.set_descriptor(dtype, index, req.index, data))
+                        .unwrap_or(OutResponse::Rejected)
+                }
                 _ => OutResponse::Rejected,
             },
             (RequestType::Standard, Recipient::Interface) => {
@@ -591,8 +1201,6 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
                         })
                         .unwrap();
 
-                        // TODO check it is valid (not out of range)
-
                         for h in &mut self.handlers {
                             h.set_alternate_setting(iface_num, new_altsetting);
                         }
@@ -610,6 +1218,9 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
                 (Request::CLEAR_FEATURE, Request::FEATURE_ENDPOINT_HALT) => {
                     let ep_addr = ((req.index as u8) & 0x8f).into();
                     self.bus.endpoint_set_stalled(ep_addr, false);
+                    for h in &mut self.handlers {
+                        h.endpoint_unhalted(ep_addr);
+                    }
                     OutResponse::Accepted
                 }
                 _ => OutResponse::Rejected,
@@ -619,6 +1230,11 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
     }
 
     fn handle_control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> InResponse<'a> {
+        // GET_STATUS always returns two bytes, any other wLength is a malformed request.
+        if req.request_type == RequestType::Standard && req.request == Request::GET_STATUS && req.length != 2 {
+            return InResponse::Rejected;
+        }
+
         match (req.request_type, req.recipient) {
             (RequestType::Standard, Recipient::Device) => match req.request {
                 Request::GET_STATUS => {
@@ -629,6 +1245,15 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
                     if self.remote_wakeup_enabled {
                         status |= 0x0002;
                     }
+                    #[cfg(feature = "superspeed")]
+                    {
+                        if self.u1_enabled {
+                            status |= 0x0004;
+                        }
+                        if self.u2_enabled {
+                            status |= 0x0008;
+                        }
+                    }
                     buf[..2].copy_from_slice(&status.to_le_bytes());
                     InResponse::Accepted(&buf[..2])
                 }
@@ -649,11 +1274,8 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
                 };
 
                 match req.request {
-                    Request::GET_STATUS => {
-                        let status: u16 = 0;
-                        buf[..2].copy_from_slice(&status.to_le_bytes());
-                        InResponse::Accepted(&buf[..2])
-                    }
+                    // Offered to the owning class first, see `handle_control_in_delegated`.
+                    Request::GET_STATUS => self.handle_control_in_delegated(req, buf),
                     Request::GET_INTERFACE => {
                         buf[0] = iface.current_alt_setting;
                         InResponse::Accepted(&buf[..1])
@@ -689,13 +1311,68 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
         }
     }
 
+    /// Routes class and vendor requests, and GET_STATUS, for an interface to the handler owning it.
+    /// Requests for an interface without an owner go to the handlers that own no interface, as
+    /// they did before handlers could own interfaces.
+    fn route(&self, req: Request) -> Route {
+        if req.recipient != Recipient::Interface
+            || (req.request_type == RequestType::Standard && req.request != Request::GET_STATUS)
+        {
+            return Route::All;
+        }
+        match self.interfaces.get(usize::from(req.index as u8)) {
+            Some(Interface { handler: Some(i), .. }) => Route::Handler(usize::from(*i)),
+            _ => Route::Unowned,
+        }
+    }
+
     fn handle_control_out_delegated(&mut self, req: Request, data: &[u8]) -> OutResponse {
-        for h in &mut self.handlers {
-            if let Some(res) = h.control_out(req, data) {
+        let route = self.route(req);
+        for (i, h) in self.handlers.iter_mut().enumerate() {
+            if !route.includes(i, &self.interfaces) {
+                continue;
+            }
+            // `control_out` is asked first, since only it can defer the response. Handlers that
+            // don't implement it fall through to `control_transfer`.
+            let res = h.control_out(req, data).or_else(|| {
+                h.control_transfer(req, data, &mut []).map(|res| match res {
+                    InResponse::Accepted(_) => OutResponse::Accepted,
+                    InResponse::Rejected => OutResponse::Rejected,
+                })
+            });
+            if let Some(res) = res {
+                if res == OutResponse::Deferred {
+                    self.deferred_handler = Some(i);
+                }
                 return res;
             }
         }
-        OutResponse::Rejected
+        if self.accept_unhandled(req) {
+            OutResponse::Accepted
+        } else {
+            OutResponse::Rejected
+        }
+    }
+
+    /// Whether a request no handler took should be accepted, see [`Config::unhandled_requests`].
+    fn accept_unhandled(&self, req: Request) -> bool {
+        self.config.unhandled_requests == UnhandledRequests::AcceptZeroLength
+            && matches!(req.request_type, RequestType::Class | RequestType::Vendor)
+            && req.length == 0
+    }
+
+    /// Waits for the deferred response, a bus event or a new SETUP packet, whichever comes first.
+    async fn wait_deferred(&mut self, control: &mut D::ControlPipe) -> Either3<OutResponse, Event, [u8; 8]> {
+        let Some(i) = self.deferred_handler.take() else {
+            return Either3::First(OutResponse::Rejected);
+        };
+        let handler = &mut self.handlers[i];
+        select3(
+            poll_fn(|cx| handler.poll_deferred(cx)),
+            self.bus.poll(),
+            control.setup(),
+        )
+        .await
     }
 
     fn handle_control_in_delegated<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> InResponse<'a> {
@@ -703,8 +1380,12 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
             core::mem::transmute(r)
         }
 
-        for h in &mut self.handlers {
-            if let Some(res) = h.control_in(req, buf) {
+        let route = self.route(req);
+        for (i, h) in self.handlers.iter_mut().enumerate() {
+            if !route.includes(i, &self.interfaces) {
+                continue;
+            }
+            if let Some(res) = h.control_transfer(req, &[], buf) {
                 // safety: the borrow checker isn't smart enough to know this pattern (returning a
                 // borrowed value from inside the loop) is sound. Workaround by unsafely extending lifetime.
                 // Also, Polonius (the WIP new borrow checker) does accept it.
@@ -712,28 +1393,50 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
                 return unsafe { extend_lifetime(res) };
             }
         }
-        InResponse::Rejected
+        if (req.request_type, req.recipient, req.request)
+            == (RequestType::Standard, Recipient::Interface, Request::GET_STATUS)
+        {
+            // Interface status bits are all reserved unless a class defines them.
+            buf[..2].fill(0);
+            InResponse::Accepted(&buf[..2])
+        } else if self.accept_unhandled(req) {
+            InResponse::Accepted(&[])
+        } else {
+            InResponse::Rejected
+        }
     }
 
     fn handle_get_descriptor<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> InResponse<'a> {
         let (dtype, index) = req.descriptor_type_index();
 
+        #[cfg(feature = "descriptor-stats")]
+        self.descriptor_fetches.count(dtype);
+
         match dtype {
-            descriptor_type::BOS => InResponse::Accepted(self.bos_descriptor),
+            descriptor_type::BOS if !self.bos_descriptor.is_empty() => InResponse::Accepted(self.bos_descriptor),
             descriptor_type::DEVICE => InResponse::Accepted(&self.device_descriptor),
             descriptor_type::CONFIGURATION => InResponse::Accepted(self.config_descriptor),
             descriptor_type::STRING => {
                 if index == 0 {
-                    buf[0] = 4; // len
+                    let len = 2 + 2 * self.config.supported_lang_ids.len();
+                    assert!(buf.len() >= len, "control buffer too small");
+
+                    buf[0] = len as u8;
                     buf[1] = descriptor_type::STRING;
-                    buf[2] = lang_id::ENGLISH_US as u8;
-                    buf[3] = (lang_id::ENGLISH_US >> 8) as u8;
-                    InResponse::Accepted(&buf[..4])
+                    for (i, lang_id) in self.config.supported_lang_ids.iter().enumerate() {
+                        buf[2 + 2 * i..4 + 2 * i].copy_from_slice(&lang_id.to_le_bytes());
+                    }
+                    InResponse::Accepted(&buf[..len])
+                } else if !self.config.supported_lang_ids.contains(&req.index) {
+                    InResponse::Rejected
                 } else {
                     let s = match index {
                         STRING_INDEX_MANUFACTURER => self.config.manufacturer,
                         STRING_INDEX_PRODUCT => self.config.product,
                         STRING_INDEX_SERIAL_NUMBER => self.config.serial_number,
+                        STRING_INDEX_CONFIGURATION if self.config.configuration_string.is_some() => {
+                            self.config.configuration_string
+                        }
                         _ => {
                             let mut s = None;
                             for handler in &mut self.handlers {
@@ -767,12 +1470,43 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
                     }
                 }
             }
+            // Full-speed only devices must stall both (USB 2.0 9.6.2 and 9.6.4).
+            descriptor_type::DEVICE_QUALIFIER | descriptor_type::OTHER_SPEED_CONFIGURATION
+                if self.config.full_speed_only =>
+            {
+                InResponse::Rejected
+            }
             descriptor_type::DEVICE_QUALIFIER => InResponse::Accepted(&self.device_qualifier_descriptor),
-            _ => InResponse::Rejected,
+            _ => self.handle_get_descriptor_delegated(dtype, index, buf),
+        }
+    }
+
+    fn handle_get_descriptor_delegated<'a>(&'a mut self, dtype: u8, index: u8, buf: &'a mut [u8]) -> InResponse<'a> {
+        unsafe fn extend_lifetime<'y>(r: InResponse<'_>) -> InResponse<'y> {
+            core::mem::transmute(r)
+        }
+
+        for h in &mut self.handlers {
+            if let Some(res) = h.get_descriptor(dtype, index, buf) {
+                // safety: see handle_control_in_delegated.
+                return unsafe { extend_lifetime(res) };
+            }
         }
+        InResponse::Rejected
     }
 }
 
+/// Calls [`Handler::poll`] on all handlers every time it is polled. Never completes.
+async fn poll_handlers(handlers: &mut [&mut dyn Handler]) -> Infallible {
+    poll_fn(|cx| {
+        for h in handlers.iter_mut() {
+            h.poll(cx);
+        }
+        Poll::Pending
+    })
+    .await
+}
+
 fn first_last<T: Iterator>(iter: T) -> impl Iterator<Item = (bool, bool, T::Item)> {
     let mut iter = iter.peekable();
     let mut first = true;
@@ -784,3 +1518,2212 @@ fn first_last<T: Iterator>(iter: T) -> impl Iterator<Item = (bool, bool, T::Item
         Some((is_first, is_last, val))
     })
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::boxed::Box;
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+    use std::vec;
+    use std::vec::Vec;
+
+    use embassy_futures::block_on;
+
+    use super::*;
+    use crate::driver::test::{ControlEvent, Shared, TestDriver};
+    use crate::driver::Endpoint;
+
+    fn leak<T>(x: T) -> &'static mut T {
+        Box::leak(Box::new(x))
+    }
+
+    fn device(
+        build: impl FnOnce(&mut Builder<'static, TestDriver>),
+    ) -> (UsbDevice<'static, TestDriver>, Rc<RefCell<Shared>>) {
+        device_with_config(Config::new(0xc0de, 0xcafe), build)
+    }
+
+    fn device_with_config(
+        config: Config<'static>,
+        build: impl FnOnce(&mut Builder<'static, TestDriver>),
+    ) -> (UsbDevice<'static, TestDriver>, Rc<RefCell<Shared>>) {
+        let (driver, shared) = TestDriver::new();
+        let mut builder = Builder::new(
+            driver,
+            config,
+            leak([0; 256]),
+            leak([0; 256]),
+            leak([0; 256]),
+            leak([0; 128]),
+        );
+        build(&mut builder);
+        (builder.build(), shared)
+    }
+
+    fn setup(request_type: u8, request: u8, value: u16, index: u16, length: u16) -> [u8; 8] {
+        let [v0, v1] = value.to_le_bytes();
+        let [i0, i1] = index.to_le_bytes();
+        let [l0, l1] = length.to_le_bytes();
+        [request_type, request, v0, v1, i0, i1, l0, l1]
+    }
+
+    /// HID config with no request handler, polled every 10ms with 8-byte reports.
+    fn hid_config(report_descriptor: &'static [u8]) -> crate::class::hid::Config<'static> {
+        crate::class::hid::Config {
+            report_descriptor,
+            request_handler: None,
+            poll_ms: 10,
+            max_packet_size: 8,
+            country_code: 0,
+        }
+    }
+
+    /// Runs a single control transfer and returns what the stack did on the control pipe.
+    fn control(dev: &mut UsbDevice<'static, TestDriver>, shared: &RefCell<Shared>, req: [u8; 8]) -> Vec<ControlEvent> {
+        block_on(dev.handle_control(req));
+        core::mem::take(&mut shared.borrow_mut().control_log)
+    }
+
+    struct VendorIn(&'static [u8]);
+
+    impl Handler for VendorIn {
+        fn control_in<'a>(&'a mut self, req: Request, _buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+            (req.request_type == RequestType::Vendor).then_some(InResponse::Accepted(self.0))
+        }
+    }
+
+    /// A vendor protocol exposing a single register, using the combined control hook.
+    struct Register(u8);
+
+    impl Handler for Register {
+        fn control_transfer<'a>(&'a mut self, req: Request, data: &[u8], buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+            if req.request_type != RequestType::Vendor {
+                return None;
+            }
+            match req.direction {
+                Direction::Out => {
+                    self.0 = data[0];
+                    Some(InResponse::Accepted(&[]))
+                }
+                Direction::In => {
+                    buf[0] = self.0;
+                    Some(InResponse::Accepted(&buf[..1]))
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn zero_byte_data_stages() {
+        struct Empty(Rc<Cell<Option<usize>>>);
+
+        impl Handler for Empty {
+            fn control_out(&mut self, req: Request, data: &[u8]) -> Option<OutResponse> {
+                if req.request_type != RequestType::Vendor {
+                    return None;
+                }
+                self.0.set(Some(data.len()));
+                Some(OutResponse::Accepted)
+            }
+
+            fn control_in<'a>(&'a mut self, req: Request, _buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+                (req.request_type == RequestType::Vendor).then_some(InResponse::Accepted(&[]))
+            }
+        }
+
+        let received = Rc::new(Cell::new(None));
+        let (mut dev, shared) = device(|b| b.handler(leak(Empty(received.clone()))));
+
+        // An empty IN response to a request with wLength > 0 ends the data stage with a ZLP.
+        let log = control(&mut dev, &shared, setup(0xc0, 0x01, 0, 0, 64));
+        assert_eq!(
+            log,
+            vec![ControlEvent::DataIn {
+                data: vec![],
+                first: true,
+                last: true
+            }]
+        );
+
+        // A host ending the OUT data stage with a ZLP hands the handler empty data.
+        shared.borrow_mut().control_out_packets.push_back(vec![]);
+        let log = control(&mut dev, &shared, setup(0x40, 0x01, 0, 0, 8));
+        assert_eq!(log, vec![ControlEvent::DataOut { len: 0 }, ControlEvent::Accept]);
+        assert_eq!(received.get(), Some(0));
+    }
+
+    #[test]
+    fn data_stage_error_aborts_transfer() {
+        let (mut dev, shared) = device(|b| b.handler(leak(Register(0))));
+
+        // The test driver reports `Disabled` when the host sends no data, as after a bus reset.
+        let log = control(&mut dev, &shared, setup(0x40, 0x01, 0, 0, 1));
+        assert_eq!(log, vec![]);
+
+        // The handler never saw the aborted write, and the next transfer goes through.
+        let log = control(&mut dev, &shared, setup(0xc0, 0x01, 0, 0, 1));
+        assert_eq!(
+            log,
+            vec![ControlEvent::DataIn {
+                data: vec![0],
+                first: true,
+                last: true
+            }]
+        );
+    }
+
+    #[test]
+    fn combined_control_transfer() {
+        let (mut dev, shared) = device(|b| b.handler(leak(Register(0))));
+
+        shared.borrow_mut().control_out_packets.push_back(vec![0x42]);
+        let log = control(&mut dev, &shared, setup(0x40, 0x01, 0, 0, 1));
+        assert_eq!(log, vec![ControlEvent::DataOut { len: 1 }, ControlEvent::Accept]);
+
+        let log = control(&mut dev, &shared, setup(0xc0, 0x01, 0, 0, 1));
+        assert_eq!(
+            log,
+            vec![ControlEvent::DataIn {
+                data: vec![0x42],
+                first: true,
+                last: true
+            }]
+        );
+    }
+
+    #[test]
+    fn class_request_before_configured() {
+        const REPORT_DESCRIPTOR: &[u8] = &[0x06, 0x00, 0xff, 0x09, 0x01, 0xa1, 0x01, 0xc0];
+
+        let (mut dev, shared) = device(|b| {
+            let config = hid_config(REPORT_DESCRIPTOR);
+            crate::class::hid::HidWriter::<_, 8>::new(b, leak(crate::class::hid::State::new()), config);
+        });
+
+        control(&mut dev, &shared, setup(0x00, Request::SET_ADDRESS, 5, 0, 0));
+        assert_eq!(dev.status().state, UsbDeviceState::Addressed);
+
+        let log = control(&mut dev, &shared, setup(0x81, Request::GET_DESCRIPTOR, 0x2200, 0, 64));
+        assert_eq!(
+            log,
+            vec![ControlEvent::DataIn {
+                data: REPORT_DESCRIPTOR.to_vec(),
+                first: true,
+                last: true
+            }]
+        );
+    }
+
+    #[test]
+    fn web_usb_landing_page() {
+        use crate::class::web_usb::{Config, State, Url, WebUsb};
+
+        let (mut dev, shared) = device(|b| {
+            let config = leak(Config {
+                max_packet_size: 64,
+                landing_url: Some(Url::new("https://embassy.dev")),
+                vendor_code: 0x42,
+            });
+            WebUsb::configure(b, leak(State::new()), config);
+        });
+
+        // GET_URL for iLandingPage 1.
+        let log = control(&mut dev, &shared, setup(0xc0, 0x42, 1, 2, 255));
+        let mut expected = vec![14, 0x03, 1];
+        expected.extend_from_slice(b"embassy.dev");
+        assert_eq!(
+            log,
+            vec![ControlEvent::DataIn {
+                data: expected,
+                first: true,
+                last: true
+            }]
+        );
+    }
+
+    #[test]
+    fn hid_descriptor_precedes_endpoints() {
+        let (mut dev, shared) = device(|b| {
+            let config = hid_config(&[0x06, 0x00, 0xff, 0x09, 0x01, 0xa1, 0x01, 0xc0]);
+            crate::class::hid::HidReaderWriter::<_, 8, 8>::new(b, leak(crate::class::hid::State::new()), config);
+        });
+
+        let log = control(&mut dev, &shared, setup(0x80, Request::GET_DESCRIPTOR, 0x0200, 0, 255));
+        let mut config_descriptor = Vec::new();
+        for event in log {
+            if let ControlEvent::DataIn { data, .. } = event {
+                config_descriptor.extend(data);
+            }
+        }
+
+        let mut layout = Vec::new();
+        let mut pos = 0;
+        while pos < config_descriptor.len() {
+            layout.push((config_descriptor[pos], config_descriptor[pos + 1]));
+            pos += config_descriptor[pos] as usize;
+        }
+        assert_eq!(
+            layout,
+            vec![
+                (9, descriptor_type::CONFIGURATION),
+                (8, descriptor_type::IAD),
+                (9, descriptor_type::INTERFACE),
+                (9, 0x21), // HID
+                (7, descriptor_type::ENDPOINT),
+                (7, descriptor_type::ENDPOINT),
+            ]
+        );
+    }
+
+    #[test]
+    fn hid_set_report_reaches_reader() {
+        use crate::class::hid::{HidReaderWriter, State};
+
+        let mut hid = None;
+        let (mut dev, shared) = device(|b| {
+            let config = hid_config(&[0x06, 0x00, 0xff, 0x09, 0x01, 0xa1, 0x01, 0xc0]);
+            let state = leak(State::with_output_report_buffer(leak([0; 2])));
+            hid = Some(HidReaderWriter::<_, 8, 8>::new(b, state, config));
+        });
+        let mut hid = hid.unwrap();
+
+        // SET_REPORT(Output) with the Caps Lock LED on.
+        shared.borrow_mut().control_out_packets.push_back(vec![0x02]);
+        let log = control(&mut dev, &shared, setup(0x21, 0x09, 0x0200, 0, 1));
+        assert_eq!(log, vec![ControlEvent::DataOut { len: 1 }, ControlEvent::Accept]);
+        let mut buf = [0; 8];
+        assert_eq!(block_on(hid.read_output_report(&mut buf)), Ok(&[0x02][..]));
+
+        // Reports that don't fit the buffer are rejected.
+        shared.borrow_mut().control_out_packets.push_back(vec![1, 2, 3]);
+        let log = control(&mut dev, &shared, setup(0x21, 0x09, 0x0200, 0, 3));
+        assert_eq!(log, vec![ControlEvent::DataOut { len: 3 }, ControlEvent::Reject]);
+    }
+
+    #[test]
+    fn hid_runtime_report_descriptor() {
+        // Vendor defined collection, extended with an extra item when a module is detected.
+        let mut report_descriptor = vec![0x06, 0x00, 0xff, 0x09, 0x01, 0xa1, 0x01];
+        let module_present = true;
+        if module_present {
+            report_descriptor.extend_from_slice(&[0x09, 0x30]);
+        }
+        report_descriptor.push(0xc0);
+        let report_descriptor = Vec::leak(report_descriptor);
+
+        let (mut dev, shared) = device(|b| {
+            let config = hid_config(report_descriptor);
+            crate::class::hid::HidWriter::<_, 8>::new(b, leak(crate::class::hid::State::new()), config);
+        });
+
+        let log = control(&mut dev, &shared, setup(0x81, Request::GET_DESCRIPTOR, 0x2100, 0, 9));
+        assert_eq!(
+            log,
+            vec![ControlEvent::DataIn {
+                data: vec![9, 0x21, 0x10, 0x01, 0, 1, 0x22, 10, 0],
+                first: true,
+                last: true
+            }]
+        );
+        let log = control(&mut dev, &shared, setup(0x81, Request::GET_DESCRIPTOR, 0x2200, 0, 64));
+        assert_eq!(
+            log,
+            vec![ControlEvent::DataIn {
+                data: report_descriptor.to_vec(),
+                first: true,
+                last: true
+            }]
+        );
+    }
+
+    #[test]
+    fn try_build_errors() {
+        struct Nothing;
+        impl Handler for Nothing {}
+
+        let builder = || {
+            let (driver, _shared) = TestDriver::new();
+            let buffers = leak(Buffers::<256, 64, 0, 64>::new());
+            Builder::with_buffers(driver, Config::new(0xc0de, 0xcafe), buffers)
+        };
+
+        let mut b = builder();
+        for _ in 0..=MAX_HANDLER_COUNT {
+            b.handler(leak(Nothing));
+        }
+        assert_eq!(b.try_build().err(), Some(BuilderError::TooManyHandlers));
+
+        let mut b = builder();
+        let mut func = b.function(0xff, 0, 0);
+        let mut iface = func.interface();
+        let mut alt = iface.alt_setting(0xff, 0, 0, None);
+        alt.alloc_endpoint_in(crate::driver::EndpointType::Bulk, 64, 0);
+        drop(func);
+        let addr = EndpointAddress::from_parts(1, Direction::In);
+        assert_eq!(b.try_build().err(), Some(BuilderError::MissingEndpointDescriptor(addr)));
+    }
+
+    #[test]
+    fn builder_with_buffers() {
+        let (driver, shared) = TestDriver::new();
+        let buffers = leak(Buffers::<64, 32, 0, 64>::new());
+        let mut dev = Builder::with_buffers(driver, Config::new(0xc0de, 0xcafe), buffers).build();
+
+        let report = dev.buffer_usage();
+        assert_eq!(report.msos_descriptor_used, 0);
+        assert_eq!(report.control_buffer_size, 64);
+        let log = control(&mut dev, &shared, setup(0x80, Request::GET_DESCRIPTOR, 0x0200, 0, 9));
+        assert!(matches!(&log[..], [ControlEvent::DataIn { data, .. }] if data[1] == descriptor_type::CONFIGURATION));
+    }
+
+    #[test]
+    fn bos_usb_2_0_extension_lpm() {
+        let mut config = Config::new(0xc0de, 0xcafe);
+        config.link_power_management = true;
+        let (mut dev, shared) = device_with_config(config, |b| {
+            let mut func = b.function(0xff, 0, 0);
+            let mut iface = func.interface();
+            let mut alt = iface.alt_setting(0xff, 0, 0, None);
+            alt.bos_capability(descriptor::capability_type::PLATFORM, &[0; 20]);
+        });
+
+        let log = control(&mut dev, &shared, setup(0x80, Request::GET_DESCRIPTOR, 0x0f00, 0, 64));
+        let [ControlEvent::DataIn { data, .. }] = &log[..] else {
+            panic!("unexpected control log: {:?}", log);
+        };
+        assert_eq!(u16::from_le_bytes([data[2], data[3]]) as usize, data.len());
+        assert_eq!(data[4], 2);
+        assert_eq!(&data[5..12], &[7, descriptor_type::CAPABILITY, 2, 0x02, 0, 0, 0]);
+        assert_eq!(&data[12..15], &[23, descriptor_type::CAPABILITY, 5]);
+    }
+
+    #[test]
+    fn lpm_bumps_usb_2_0() {
+        let mut config = Config::new(0xc0de, 0xcafe);
+        config.bcd_usb = UsbVersion::Two;
+        config.link_power_management = true;
+        let (mut dev, shared) = device_with_config(config, |_| {});
+
+        let log = control(&mut dev, &shared, setup(0x80, Request::GET_DESCRIPTOR, 0x0100, 0, 18));
+        let [ControlEvent::DataIn { data, .. }] = &log[..] else {
+            panic!("unexpected control log: {:?}", log);
+        };
+        assert_eq!(&data[2..4], &[0x10, 0x02]);
+
+        let log = control(&mut dev, &shared, setup(0x80, Request::GET_DESCRIPTOR, 0x0f00, 0, 64));
+        let [ControlEvent::DataIn { data, .. }] = &log[..] else {
+            panic!("unexpected control log: {:?}", log);
+        };
+        assert_eq!(
+            &data[..],
+            &[
+                5,
+                descriptor_type::BOS,
+                12,
+                0,
+                1,
+                7,
+                descriptor_type::CAPABILITY,
+                2,
+                0x02,
+                0,
+                0,
+                0
+            ]
+        );
+    }
+
+    #[test]
+    fn lpm_with_usb_2_0_device_descriptor_override() {
+        let mut config = Config::new(0xc0de, 0xcafe);
+        config.link_power_management = true;
+        let (driver, _shared) = TestDriver::new();
+        let buffers = leak(Buffers::<64, 32, 0, 64>::new());
+        let mut builder = Builder::with_buffers(driver, config, buffers);
+        let mut descriptor = descriptor::device_descriptor(&Config::new(0xc0de, 0xcafe));
+        descriptor[2..4].copy_from_slice(&0x0200u16.to_le_bytes());
+        builder.device_descriptor(descriptor);
+
+        assert_eq!(builder.try_build().err(), Some(BuilderError::BosRequiresUsb21));
+    }
+
+    #[cfg(feature = "superspeed-plus")]
+    #[test]
+    fn superspeed_plus_capability() {
+        use crate::descriptor::{capability_type, LaneSpeedExponent, SublinkSpeedAttribute};
+
+        // A symmetric 10 Gbps sublink, with a 20 Gbps sublink as the second attribute.
+        let attribute = |id, mantissa| SublinkSpeedAttribute {
+            id,
+            exponent: LaneSpeedExponent::GigabitsPerSecond,
+            asymmetric: false,
+            transmit: false,
+            superspeed_plus: true,
+            mantissa,
+        };
+        let (mut dev, shared) = device(|b| {
+            let mut func = b.function(0xff, 0, 0);
+            let mut iface = func.interface();
+            let mut alt = iface.alt_setting(0xff, 0, 0, None);
+            alt.superspeed_plus_capability(0, 1, 1, &[attribute(0, 10), attribute(1, 20)]);
+        });
+
+        let log = control(&mut dev, &shared, setup(0x80, Request::GET_DESCRIPTOR, 0x0f00, 0, 64));
+        let [ControlEvent::DataIn { data, .. }] = &log[..] else {
+            panic!("unexpected control log: {:?}", log);
+        };
+        assert_eq!(&data[2..5], &[data.len() as u8, 0, 2]);
+        assert_eq!(
+            &data[12..],
+            &[
+                20,
+                descriptor_type::CAPABILITY,
+                capability_type::SUPERSPEED_PLUS,
+                0,
+                // bmAttributes: 2 attributes (SSAC = 1), 2 speed IDs (SSIC = 1)
+                0x21,
+                0,
+                0,
+                0,
+                // wFunctionalitySupport: speed ID 0, 1 RX and 1 TX lane
+                0x00,
+                0x11,
+                0,
+                0,
+                // SSID 0, Gbps, SuperSpeedPlus, mantissa 10
+                0x30,
+                0x40,
+                10,
+                0,
+                // SSID 1, Gbps, SuperSpeedPlus, mantissa 20
+                0x31,
+                0x40,
+                20,
+                0,
+            ]
+        );
+    }
+
+    #[test]
+    fn resume_keeps_configuration() {
+        let mut config = Config::new(0xc0de, 0xcafe);
+        config.disable_endpoints_on_suspend = true;
+        let (mut dev, shared) = device_with_config(config, |b| {
+            let mut func = b.function(0xff, 0, 0);
+            let mut iface = func.interface();
+            let mut alt = iface.alt_setting(0xff, 0, 0, None);
+            alt.endpoint_bulk_in(64);
+            alt.endpoint_bulk_out(64);
+        });
+        let endpoints = [
+            EndpointAddress::from_parts(1, Direction::In),
+            EndpointAddress::from_parts(1, Direction::Out),
+        ];
+
+        control(&mut dev, &shared, setup(0x00, Request::SET_ADDRESS, 5, 0, 0));
+        control(&mut dev, &shared, setup(0x00, Request::SET_CONFIGURATION, 1, 0, 0));
+        assert_eq!(dev.status().state, UsbDeviceState::Configured);
+        assert!(endpoints.iter().all(|ep| shared.borrow().enabled.contains(ep)));
+
+        block_on(dev.inner.handle_bus_event(Event::Suspend));
+        assert!(dev.status().suspended);
+        assert!(shared.borrow().enabled.is_empty());
+
+        block_on(dev.inner.handle_bus_event(Event::Resume));
+        assert!(!dev.status().suspended);
+        assert_eq!(dev.status().state, UsbDeviceState::Configured);
+        assert!(endpoints.iter().all(|ep| shared.borrow().enabled.contains(ep)));
+    }
+
+    #[test]
+    fn handlers_polled_by_run_loop() {
+        struct Worker(Rc<Cell<u32>>);
+
+        impl Handler for Worker {
+            fn poll(&mut self, cx: &mut Context<'_>) {
+                // Pretend there's more work until it has run three times.
+                self.0.set(self.0.get() + 1);
+                if self.0.get() < 3 {
+                    cx.waker().wake_by_ref();
+                }
+            }
+        }
+
+        let polls = Rc::new(Cell::new(0));
+        let (mut dev, _shared) = device(|b| b.handler(leak(Worker(polls.clone()))));
+
+        block_on(dev.run_until(poll_fn(|_| match polls.get() {
+            3 => Poll::Ready(()),
+            _ => Poll::Pending,
+        })));
+        assert_eq!(polls.get(), 3);
+    }
+
+    #[test]
+    fn suspend_resume_hooks() {
+        struct Suspended(Rc<RefCell<Vec<bool>>>);
+
+        impl Handler for Suspended {
+            fn suspended(&mut self, suspended: bool) {
+                self.0.borrow_mut().push(suspended);
+            }
+        }
+
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let (mut dev, shared) = device(|b| b.handler(leak(Suspended(calls.clone()))));
+        control(&mut dev, &shared, setup(0x00, Request::SET_ADDRESS, 5, 0, 0));
+        control(&mut dev, &shared, setup(0x00, Request::SET_CONFIGURATION, 1, 0, 0));
+
+        block_on(dev.inner.handle_bus_event(Event::Suspend));
+        assert_eq!(*calls.borrow(), [true]);
+        assert!(dev.status().suspended);
+
+        block_on(dev.inner.handle_bus_event(Event::Resume));
+        assert_eq!(*calls.borrow(), [true, false]);
+        assert_eq!(dev.status().state, UsbDeviceState::Configured);
+    }
+
+    #[test]
+    fn msos_function_subsets() {
+        use crate::msos::{windows_version, CompatibleIdFeatureDescriptor};
+
+        let (dev, _shared) = device(|b| {
+            b.msos_descriptor(windows_version::WIN8_1, 0x20);
+            for winusb in [false, true, true] {
+                let mut func = b.function(0xff, 0, 0);
+                if winusb {
+                    func.msos_feature(CompatibleIdFeatureDescriptor::new("WINUSB", ""));
+                }
+                func.interface().alt_setting(0xff, 0, 0, None);
+            }
+        });
+
+        let desc = dev.inner.msos_descriptor.descriptor();
+        let u16_at = |i: usize| u16::from_le_bytes([desc[i], desc[i + 1]]);
+        assert_eq!(desc.len(), 10 + 8 + 2 * (8 + 20));
+        // Descriptor set header, configuration subset header.
+        assert_eq!(u16_at(8) as usize, desc.len());
+        assert_eq!(u16_at(10 + 6) as usize, desc.len() - 10);
+        // One function subset per WinUSB function, scoped to its first interface.
+        for (offset, first_interface) in [(18, 1), (46, 2)] {
+            assert_eq!(desc[offset + 4], first_interface);
+            assert_eq!(u16_at(offset + 6), 8 + 20);
+        }
+    }
+
+    #[test]
+    fn typed_endpoint_descriptor_matches_builder() {
+        let (dev, _shared) = device(|b| {
+            let mut func = b.function(0xff, 0, 0);
+            let mut iface = func.interface();
+            let mut alt = iface.alt_setting(0xff, 0, 0, None);
+            alt.endpoint_interrupt_in(0x0123, 4);
+        });
+
+        let expected = descriptor::EndpointDescriptor {
+            address: EndpointAddress::from_parts(1, Direction::In),
+            attributes: 0x03,
+            max_packet_size: 0x0123,
+            interval: 4,
+        }
+        .to_bytes();
+        assert_eq!(expected, [7, 5, 0x81, 0x03, 0x23, 0x01, 4]);
+        assert!(dev.inner.config_descriptor.windows(7).any(|w| w == expected));
+    }
+
+    #[test]
+    fn clamped_max_packet_size() {
+        let mut ep = None;
+        let (dev, _shared) = device(|b| {
+            let mut func = b.function(0xff, 0, 0);
+            let mut iface = func.interface();
+            let mut alt = iface.alt_setting(0xff, 0, 0, None);
+            ep = Some(alt.endpoint_bulk_in(512));
+        });
+
+        // The test driver only grants 64 bytes, which is also what the descriptor reports.
+        assert_eq!(ep.unwrap().max_packet_size(), 64);
+        assert!(dev
+            .inner
+            .config_descriptor
+            .windows(7)
+            .any(|w| w == [7, 5, 0x81, 0x02, 64, 0, 0]));
+    }
+
+    #[test]
+    fn setup_preempts_control_transfer() {
+        let (mut dev, shared) = device(|b| b.handler(leak(Register(7))));
+
+        // The host sends a new SETUP instead of the data stage of the first one: the write is
+        // abandoned without a status stage and doesn't reach the handler, and the run loop goes
+        // on to answer the second request.
+        shared
+            .borrow_mut()
+            .setups
+            .extend([setup(0x40, 0x01, 0, 0, 1), setup(0xc0, 0x01, 0, 0, 1)]);
+        let done = poll_fn(|_| match shared.borrow().setups.is_empty() {
+            true => Poll::Ready(()),
+            false => Poll::Pending,
+        });
+        block_on(select(dev.run(), done));
+
+        let log = core::mem::take(&mut shared.borrow_mut().control_log);
+        assert_eq!(
+            log,
+            vec![ControlEvent::DataIn {
+                data: vec![7],
+                first: true,
+                last: true
+            }]
+        );
+    }
+
+    #[test]
+    fn config_descriptor_header_only() {
+        let (mut dev, shared) = device(|b| {
+            let mut func = b.function(0xff, 0, 0);
+            let mut iface = func.interface();
+            let mut alt = iface.alt_setting(0xff, 0, 0, None);
+            alt.endpoint_bulk_in(64);
+        });
+        let config_descriptor = dev.inner.config_descriptor.to_vec();
+        assert!(config_descriptor.len() > 9);
+
+        // Hosts first fetch only the header to learn wTotalLength.
+        let log = control(&mut dev, &shared, setup(0x80, Request::GET_DESCRIPTOR, 0x0200, 0, 9));
+        assert_eq!(
+            log,
+            vec![ControlEvent::DataIn {
+                data: config_descriptor[..9].to_vec(),
+                first: true,
+                last: true
+            }]
+        );
+    }
+
+    /// Finishes vendor OUT requests on the second poll, as if another task did the work.
+    struct Deferring {
+        polls: Rc<Cell<usize>>,
+    }
+
+    impl Handler for Deferring {
+        fn control_out(&mut self, req: Request, _data: &[u8]) -> Option<OutResponse> {
+            (req.request_type == RequestType::Vendor).then_some(OutResponse::Deferred)
+        }
+
+        fn poll_deferred(&mut self, cx: &mut Context<'_>) -> Poll<OutResponse> {
+            self.polls.set(self.polls.get() + 1);
+            if self.polls.get() == 1 {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            } else {
+                Poll::Ready(OutResponse::Accepted)
+            }
+        }
+    }
+
+    #[test]
+    fn deferred_control_out() {
+        let polls = Rc::new(Cell::new(0));
+        let (mut dev, shared) = device(|b| b.handler(leak(Deferring { polls: polls.clone() })));
+
+        shared.borrow_mut().control_out_packets.push_back(vec![1, 2]);
+        let log = control(&mut dev, &shared, setup(0x40, 0x01, 0, 0, 2));
+        assert_eq!(log, vec![ControlEvent::DataOut { len: 2 }, ControlEvent::Accept]);
+        assert_eq!(polls.get(), 2);
+    }
+
+    /// Defers vendor OUT requests and never finishes them.
+    struct Stuck {
+        resets: Rc<Cell<usize>>,
+    }
+
+    impl Handler for Stuck {
+        fn reset(&mut self) {
+            self.resets.set(self.resets.get() + 1);
+        }
+
+        fn control_out(&mut self, req: Request, _data: &[u8]) -> Option<OutResponse> {
+            (req.request_type == RequestType::Vendor).then_some(OutResponse::Deferred)
+        }
+
+        fn poll_deferred(&mut self, _cx: &mut Context<'_>) -> Poll<OutResponse> {
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn deferred_control_out_abandoned_on_reset() {
+        let resets = Rc::new(Cell::new(0));
+        let (mut dev, shared) = device(|b| b.handler(leak(Stuck { resets: resets.clone() })));
+        dev.inner.device_state = UsbDeviceState::Configured;
+
+        shared.borrow_mut().control_out_packets.push_back(vec![1, 2]);
+        shared.borrow_mut().bus_events.push_back(Event::Reset);
+        let log = control(&mut dev, &shared, setup(0x40, 0x01, 0, 0, 2));
+        assert_eq!(log, vec![ControlEvent::DataOut { len: 2 }]);
+        assert_eq!(resets.get(), 1);
+        assert_eq!(dev.inner.device_state, UsbDeviceState::Default);
+    }
+
+    #[test]
+    fn deferred_control_out_abandoned_on_setup() {
+        let resets = Rc::new(Cell::new(0));
+        let (mut dev, shared) = device(|b| b.handler(leak(Stuck { resets })));
+
+        shared.borrow_mut().control_out_packets.push_back(vec![1, 2]);
+        shared
+            .borrow_mut()
+            .setups
+            .push_back(setup(0x80, Request::GET_STATUS, 0, 0, 2));
+        let log = control(&mut dev, &shared, setup(0x40, 0x01, 0, 0, 2));
+        assert_eq!(
+            log,
+            vec![
+                ControlEvent::DataOut { len: 2 },
+                ControlEvent::DataIn {
+                    data: vec![0, 0],
+                    first: true,
+                    last: true
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn unhandled_requests() {
+        let (mut dev, shared) = device(|_| {});
+        let log = control(&mut dev, &shared, setup(0x40, 0x01, 0, 0, 0));
+        assert_eq!(log, vec![ControlEvent::Reject]);
+
+        let mut config = Config::new(0xc0de, 0xcafe);
+        config.unhandled_requests = UnhandledRequests::AcceptZeroLength;
+        let (mut dev, shared) = device_with_config(config, |_| {});
+        let log = control(&mut dev, &shared, setup(0x40, 0x01, 0, 0, 0));
+        assert_eq!(log, vec![ControlEvent::Accept]);
+        let log = control(&mut dev, &shared, setup(0xc1, 0x01, 0, 0, 0));
+        assert_eq!(log, vec![ControlEvent::Accept]);
+        let log = control(&mut dev, &shared, setup(0xc0, 0x01, 0, 0, 4));
+        assert_eq!(log, vec![ControlEvent::Reject]);
+    }
+
+    #[test]
+    fn max_packet_size_0_validated() {
+        let try_build = |max_packet_size_0, full_speed_only| {
+            let (driver, _shared) = TestDriver::new();
+            let mut config = Config::new(0xc0de, 0xcafe);
+            config.max_packet_size_0 = max_packet_size_0;
+            config.full_speed_only = full_speed_only;
+            let buffers = leak(Buffers::<64, 32, 0, 64>::new());
+            Builder::with_buffers(driver, config, buffers).try_build().err()
+        };
+
+        assert_eq!(try_build(48, true), Some(BuilderError::InvalidMaxPacketSize));
+        // High-speed devices must use 64.
+        assert_eq!(try_build(8, false), Some(BuilderError::InvalidMaxPacketSize));
+        assert_eq!(try_build(8, true), None);
+        assert_eq!(try_build(64, false), None);
+    }
+
+    #[test]
+    fn endpoint_allocation_failure() {
+        use crate::class::cdc_acm::{CdcAcmClass, State};
+        use crate::driver::EndpointType;
+
+        let (driver, _shared) = TestDriver::new();
+        let buffers = leak(Buffers::<512, 32, 0, 64>::new());
+        let mut b = Builder::with_buffers(driver, Config::new(0xc0de, 0xcafe), buffers);
+        {
+            let mut func = b.function(0xff, 0, 0);
+            let mut iface = func.interface();
+            let mut alt = iface.alt_setting(0xff, 0, 0, None);
+            for _ in 0..15 {
+                alt.endpoint_bulk_in(64);
+            }
+        }
+        // CDC-ACM needs two more IN endpoints. It still gets built, on placeholder endpoints, and
+        // its OUT endpoint is allocated.
+        CdcAcmClass::new(&mut b, leak(State::new()), 64);
+        let Some(BuilderError::EndpointAllocFailed(failures)) = b.try_build().err() else {
+            panic!("expected an allocation failure");
+        };
+        assert_eq!(
+            failures.as_slice(),
+            [
+                EndpointAllocFailure {
+                    direction: Direction::In,
+                    ep_type: EndpointType::Interrupt,
+                    max_packet_size: 8,
+                    interface: InterfaceNumber(1),
+                },
+                EndpointAllocFailure {
+                    direction: Direction::In,
+                    ep_type: EndpointType::Bulk,
+                    max_packet_size: 64,
+                    interface: InterfaceNumber(2),
+                },
+            ]
+        );
+        assert_eq!(
+            std::format!("{}", failures),
+            "Interrupt IN endpoint with max packet size 8 for interface 1, \
+             Bulk IN endpoint with max packet size 64 for interface 2"
+        );
+    }
+
+    #[test]
+    fn bos_descriptor_two_step_fetch() {
+        let (mut dev, shared) = device(|_| {});
+        let bos = dev.inner.bos_descriptor.to_vec();
+        let total_length = u16::from_le_bytes([bos[2], bos[3]]);
+        assert_eq!(total_length as usize, bos.len());
+        assert!(bos.len() > 5);
+
+        let log = control(&mut dev, &shared, setup(0x80, Request::GET_DESCRIPTOR, 0x0f00, 0, 5));
+        assert_eq!(
+            log,
+            vec![ControlEvent::DataIn {
+                data: bos[..5].to_vec(),
+                first: true,
+                last: true
+            }]
+        );
+
+        let log = control(
+            &mut dev,
+            &shared,
+            setup(0x80, Request::GET_DESCRIPTOR, 0x0f00, 0, total_length),
+        );
+        assert_eq!(
+            log,
+            vec![ControlEvent::DataIn {
+                data: bos,
+                first: true,
+                last: true
+            }]
+        );
+    }
+
+    #[test]
+    fn optional_function_at_boot() {
+        for codec_detected in [false, true] {
+            let (dev, _shared) = device(|b| {
+                b.function(0xff, 0, 0).interface().alt_setting(0xff, 0, 0, None);
+                if codec_detected {
+                    b.function(0x01, 0x01, 0).interface().alt_setting(0x01, 0x01, 0, None);
+                }
+            });
+
+            let config_descriptor = dev.inner.config_descriptor;
+            // bNumInterfaces and wTotalLength only cover the functions that were added.
+            assert_eq!(config_descriptor[4], 1 + codec_detected as u8);
+            assert_eq!(
+                u16::from_le_bytes([config_descriptor[2], config_descriptor[3]]) as usize,
+                config_descriptor.len()
+            );
+        }
+    }
+
+    #[test]
+    fn set_address_zero() {
+        let (mut dev, shared) = device(|_| {});
+
+        let log = control(&mut dev, &shared, setup(0x00, Request::SET_ADDRESS, 5, 0, 0));
+        assert_eq!(log, vec![ControlEvent::AcceptSetAddress(5)]);
+        assert_eq!(dev.status().state, UsbDeviceState::Addressed);
+
+        let log = control(&mut dev, &shared, setup(0x00, Request::SET_ADDRESS, 0, 0, 0));
+        assert_eq!(log, vec![ControlEvent::AcceptSetAddress(0)]);
+        assert_eq!(dev.status().state, UsbDeviceState::Default);
+        assert_eq!(dev.status().address, 0);
+    }
+
+    #[test]
+    fn set_address_zero_while_configured() {
+        struct Configured(Rc<Cell<bool>>);
+
+        impl Handler for Configured {
+            fn configured(&mut self, configured: bool) {
+                self.0.set(configured);
+            }
+        }
+
+        let configured = Rc::new(Cell::new(false));
+        let watch = leak(ConfiguredWatch::new());
+        let (mut dev, shared) = device(|b| {
+            let mut func = b.function(0xff, 0, 0);
+            let mut iface = func.interface();
+            let mut alt = iface.alt_setting(0xff, 0, 0, None);
+            alt.endpoint_bulk_in(64);
+            drop(func);
+            b.handler(leak(Configured(configured.clone())));
+        });
+        dev.set_configured_watch(watch);
+        let ep = EndpointAddress::from_parts(1, Direction::In);
+
+        control(&mut dev, &shared, setup(0x00, Request::SET_ADDRESS, 5, 0, 0));
+        control(&mut dev, &shared, setup(0x00, Request::SET_CONFIGURATION, 1, 0, 0));
+        dev.signal_state_change();
+        assert!(configured.get() && watch.is_configured());
+        assert!(shared.borrow().enabled.contains(&ep));
+
+        let log = control(&mut dev, &shared, setup(0x00, Request::SET_ADDRESS, 0, 0, 0));
+        assert_eq!(log, vec![ControlEvent::AcceptSetAddress(0)]);
+        dev.signal_state_change();
+        assert_eq!(dev.status().state, UsbDeviceState::Default);
+        assert!(!configured.get());
+        assert!(!watch.is_configured());
+        assert!(!shared.borrow().enabled.contains(&ep));
+    }
+
+    #[test]
+    fn control_in_clamped_to_length() {
+        let (mut dev, shared) = device(|b| b.handler(leak(VendorIn(&[0xaa; 64]))));
+
+        let log = control(&mut dev, &shared, setup(0xc0, 0x01, 0, 0, 8));
+        assert_eq!(
+            log,
+            vec![ControlEvent::DataIn {
+                data: vec![0xaa; 8],
+                first: true,
+                last: true
+            }]
+        );
+    }
+
+    #[test]
+    fn control_in_without_data_stage() {
+        let (mut dev, shared) = device(|b| b.handler(leak(VendorIn(&[1, 2, 3]))));
+
+        let log = control(&mut dev, &shared, setup(0xc0, 0x01, 0, 0, 0));
+        assert_eq!(log, vec![ControlEvent::Accept]);
+    }
+
+    #[test]
+    fn alternate_settings() {
+        use crate::descriptor::{SynchronizationType, UsageType};
+
+        let (mut dev, shared) = device(|b| {
+            let mut func = b.function(0x01, 0x02, 0);
+            let mut iface = func.interface();
+            iface.alt_setting(0x01, 0x02, 0, None);
+            let mut alt = iface.alt_setting(0x01, 0x02, 0, None);
+            alt.endpoint_isochronous_in(192, 1, SynchronizationType::Asynchronous, UsageType::DataEndpoint, &[]);
+        });
+        let iso_in = EndpointAddress::from_parts(1, Direction::In);
+        let current_alt = |dev: &mut UsbDevice<'static, TestDriver>| {
+            control(dev, &shared, setup(0x81, Request::GET_INTERFACE, 0, 0, 1))
+        };
+        let alt_is = |alt: u8| {
+            vec![ControlEvent::DataIn {
+                data: vec![alt],
+                first: true,
+                last: true,
+            }]
+        };
+
+        // Both alternate settings share bInterfaceNumber 0, after the configuration descriptor and IAD.
+        let desc = dev.inner.config_descriptor;
+        assert_eq!(&desc[17..21], &[9, descriptor_type::INTERFACE, 0, 0]);
+        assert_eq!(&desc[26..30], &[9, descriptor_type::INTERFACE, 0, 1]);
+        // Isochronous transfer type with the asynchronous synchronization type bits.
+        assert_eq!(
+            &desc[35..42],
+            &[7, descriptor_type::ENDPOINT, 0x81, 0b0000_0101, 192, 0, 1]
+        );
+
+        control(&mut dev, &shared, setup(0x00, Request::SET_ADDRESS, 5, 0, 0));
+        control(&mut dev, &shared, setup(0x00, Request::SET_CONFIGURATION, 1, 0, 0));
+        assert_eq!(current_alt(&mut dev), alt_is(0));
+        assert!(!shared.borrow().enabled.contains(&iso_in));
+
+        let log = control(&mut dev, &shared, setup(0x01, Request::SET_INTERFACE, 1, 0, 0));
+        assert_eq!(log, vec![ControlEvent::Accept]);
+        assert_eq!(current_alt(&mut dev), alt_is(1));
+        assert!(shared.borrow().enabled.contains(&iso_in));
+
+        let log = control(&mut dev, &shared, setup(0x01, Request::SET_INTERFACE, 2, 0, 0));
+        assert_eq!(log, vec![ControlEvent::Reject]);
+        assert_eq!(current_alt(&mut dev), alt_is(1));
+
+        control(&mut dev, &shared, setup(0x01, Request::SET_INTERFACE, 0, 0, 0));
+        assert_eq!(current_alt(&mut dev), alt_is(0));
+        assert!(!shared.borrow().enabled.contains(&iso_in));
+    }
+
+    #[test]
+    fn interface_get_status_delegated() {
+        struct Status;
+        struct Nothing;
+
+        impl Handler for Nothing {}
+
+        impl Handler for Status {
+            fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+                (req.request == Request::GET_STATUS).then(|| {
+                    buf[..2].copy_from_slice(&[0x03, 0x00]);
+                    InResponse::Accepted(&buf[..2])
+                })
+            }
+        }
+
+        let (mut dev, shared) = device(|b| {
+            let mut func = b.function(0xff, 0, 0);
+            func.interface();
+            func.interface();
+            func.handler(leak(Status));
+            drop(func);
+            let mut func = b.function(0xff, 0, 0);
+            func.interface();
+            func.handler(leak(Nothing));
+        });
+        let status = |data: Vec<u8>| {
+            vec![ControlEvent::DataIn {
+                data,
+                first: true,
+                last: true,
+            }]
+        };
+
+        let log = control(&mut dev, &shared, setup(0x81, Request::GET_STATUS, 0, 1, 2));
+        assert_eq!(log, status(vec![0x03, 0x00]));
+        // Interfaces the handler doesn't own fall back to all bits cleared.
+        let log = control(&mut dev, &shared, setup(0x81, Request::GET_STATUS, 0, 2, 2));
+        assert_eq!(log, status(vec![0x00, 0x00]));
+        let log = control(&mut dev, &shared, setup(0x81, Request::GET_STATUS, 0, 3, 2));
+        assert_eq!(log, vec![ControlEvent::Reject]);
+    }
+
+    #[test]
+    fn control_in_ends_full_packet_with_zlp() {
+        let (mut dev, shared) = device(|b| b.handler(leak(VendorIn(&[7; 64]))));
+
+        // A response that ends on a packet boundary before wLength is terminated by a ZLP.
+        let log = control(&mut dev, &shared, setup(0xc0, 0x01, 0, 0, 255));
+        assert_eq!(
+            log,
+            vec![
+                ControlEvent::DataIn {
+                    data: vec![7; 64],
+                    first: true,
+                    last: false
+                },
+                ControlEvent::DataIn {
+                    data: vec![],
+                    first: false,
+                    last: true
+                },
+            ]
+        );
+
+        // When the host asked for exactly that much, no ZLP is needed.
+        let log = control(&mut dev, &shared, setup(0xc0, 0x01, 0, 0, 64));
+        assert_eq!(
+            log,
+            vec![ControlEvent::DataIn {
+                data: vec![7; 64],
+                first: true,
+                last: true
+            }]
+        );
+    }
+
+    #[test]
+    fn control_in_padded_to_length() {
+        struct Padded;
+
+        impl Handler for Padded {
+            fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+                buf[..3].copy_from_slice(&[1, 2, 3]);
+                Some(InResponse::accepted_padded(req, buf, 3))
+            }
+        }
+
+        let (mut dev, shared) = device(|b| b.handler(leak(Padded)));
+
+        let log = control(&mut dev, &shared, setup(0xc0, 0x01, 0, 0, 70));
+        let mut first = vec![0; 64];
+        first[..3].copy_from_slice(&[1, 2, 3]);
+        assert_eq!(
+            log,
+            vec![
+                ControlEvent::DataIn {
+                    data: first,
+                    first: true,
+                    last: false
+                },
+                ControlEvent::DataIn {
+                    data: vec![0; 6],
+                    first: false,
+                    last: true
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn run_until_stop_disables_device() {
+        struct Enabled(Rc<Cell<Option<bool>>>);
+
+        impl Handler for Enabled {
+            fn enabled(&mut self, enabled: bool) {
+                self.0.set(Some(enabled));
+            }
+        }
+
+        let enabled = Rc::new(Cell::new(None));
+        let (mut dev, _shared) = device(|b| b.handler(leak(Enabled(enabled.clone()))));
+
+        block_on(dev.run_until(core::future::ready(())));
+        assert_eq!(dev.status().state, UsbDeviceState::Disabled);
+        assert_eq!(enabled.get(), Some(false));
+    }
+
+    #[test]
+    fn handler_string_descriptor() {
+        struct Strings(StringIndex);
+
+        impl Handler for Strings {
+            fn get_string(&mut self, index: StringIndex, lang_id: u16) -> Option<&str> {
+                (index == self.0 && lang_id == descriptor::lang_id::ENGLISH_US).then_some("Hi")
+            }
+        }
+
+        let (mut dev, shared) = device(|b| {
+            let index = b.string();
+            b.handler(leak(Strings(index)));
+        });
+
+        let log = control(
+            &mut dev,
+            &shared,
+            setup(0x80, Request::GET_DESCRIPTOR, 0x0304, 0x0409, 255),
+        );
+        assert_eq!(
+            log,
+            vec![ControlEvent::DataIn {
+                data: vec![6, descriptor_type::STRING, b'H', 0, b'i', 0],
+                first: true,
+                last: true
+            }]
+        );
+
+        // Unknown indexes and languages stall the request.
+        let log = control(
+            &mut dev,
+            &shared,
+            setup(0x80, Request::GET_DESCRIPTOR, 0x0305, 0x0409, 255),
+        );
+        assert_eq!(log, vec![ControlEvent::Reject]);
+        let log = control(
+            &mut dev,
+            &shared,
+            setup(0x80, Request::GET_DESCRIPTOR, 0x0304, 0x0407, 255),
+        );
+        assert_eq!(log, vec![ControlEvent::Reject]);
+    }
+
+    #[test]
+    fn configuration_string() {
+        let mut config = Config::new(0xc0de, 0xcafe);
+        config.configuration_string = Some("Cfg");
+        let mut index = None;
+        let (mut dev, shared) = device_with_config(config, |b| index = Some(b.string()));
+        // Custom strings only move up when the configuration string is set.
+        assert_eq!(index, Some(StringIndex::new(5)));
+
+        let log = control(&mut dev, &shared, setup(0x80, Request::GET_DESCRIPTOR, 0x0200, 0, 9));
+        assert!(matches!(&log[..], [ControlEvent::DataIn { data, .. }] if data[6] == 4));
+        let log = control(
+            &mut dev,
+            &shared,
+            setup(0x80, Request::GET_DESCRIPTOR, 0x0304, 0x0409, 255),
+        );
+        assert_eq!(
+            log,
+            vec![ControlEvent::DataIn {
+                data: vec![8, descriptor_type::STRING, b'C', 0, b'f', 0, b'g', 0],
+                first: true,
+                last: true
+            }]
+        );
+    }
+
+    #[test]
+    fn device_qualifier_and_other_speed() {
+        struct OtherSpeed;
+
+        impl Handler for OtherSpeed {
+            fn get_descriptor<'a>(&'a mut self, dtype: u8, _index: u8, _buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+                (dtype == descriptor_type::OTHER_SPEED_CONFIGURATION)
+                    .then_some(InResponse::Accepted(&[9, descriptor_type::OTHER_SPEED_CONFIGURATION]))
+            }
+        }
+
+        // The device qualifier is served by default, the other-speed configuration is left to handlers.
+        let (mut dev, shared) = device(|b| b.handler(leak(OtherSpeed)));
+        let log = control(&mut dev, &shared, setup(0x80, Request::GET_DESCRIPTOR, 0x0600, 0, 255));
+        assert!(matches!(&log[..], [ControlEvent::DataIn { data, .. }]
+            if data.len() == 10 && data[1] == descriptor_type::DEVICE_QUALIFIER && data[7] == 64));
+        let log = control(&mut dev, &shared, setup(0x80, Request::GET_DESCRIPTOR, 0x0700, 0, 255));
+        assert!(matches!(&log[..], [ControlEvent::DataIn { data, .. }]
+            if data[1] == descriptor_type::OTHER_SPEED_CONFIGURATION));
+
+        // Full-speed only devices stall both.
+        let mut config = Config::new(0xc0de, 0xcafe);
+        config.full_speed_only = true;
+        let (mut dev, shared) = device_with_config(config, |b| b.handler(leak(OtherSpeed)));
+        for value in [0x0600, 0x0700] {
+            let log = control(&mut dev, &shared, setup(0x80, Request::GET_DESCRIPTOR, value, 0, 255));
+            assert_eq!(log, vec![ControlEvent::Reject]);
+        }
+    }
+
+    #[test]
+    fn string_descriptor_languages() {
+        let mut config = Config::new(0xc0de, 0xcafe);
+        config.product = Some("P");
+        config.supported_lang_ids = &[descriptor::lang_id::ENGLISH_US, 0x0407];
+        let (mut dev, shared) = device_with_config(config, |_| {});
+
+        let log = control(&mut dev, &shared, setup(0x80, Request::GET_DESCRIPTOR, 0x0300, 0, 255));
+        assert!(matches!(&log[..], [ControlEvent::DataIn { data, .. }] if data[..] == [6, 3, 0x09, 0x04, 0x07, 0x04]));
+
+        let log = control(
+            &mut dev,
+            &shared,
+            setup(0x80, Request::GET_DESCRIPTOR, 0x0302, 0x0407, 255),
+        );
+        assert!(matches!(&log[..], [ControlEvent::DataIn { data, .. }] if data[..] == [4, 3, b'P', 0]));
+
+        // Built-in strings are only served in the supported languages.
+        let log = control(
+            &mut dev,
+            &shared,
+            setup(0x80, Request::GET_DESCRIPTOR, 0x0302, 0x040c, 255),
+        );
+        assert_eq!(log, vec![ControlEvent::Reject]);
+    }
+
+    #[test]
+    fn cdc_acm_rejects_truncated_line_coding() {
+        let (mut dev, shared) = device(|b| {
+            crate::class::cdc_acm::CdcAcmClass::new(b, leak(crate::class::cdc_acm::State::new()), 64);
+        });
+
+        shared
+            .borrow_mut()
+            .control_out_packets
+            .push_back(vec![0x00, 0xc2, 0x01]);
+        let log = control(&mut dev, &shared, setup(0x21, 0x20, 0, 0, 3));
+        assert_eq!(log, vec![ControlEvent::DataOut { len: 3 }, ControlEvent::Reject]);
+
+        shared
+            .borrow_mut()
+            .control_out_packets
+            .push_back(vec![0x00, 0xc2, 0x01, 0x00, 0x00, 0x00, 0x08]);
+        let log = control(&mut dev, &shared, setup(0x21, 0x20, 0, 0, 7));
+        assert_eq!(log, vec![ControlEvent::DataOut { len: 7 }, ControlEvent::Accept]);
+    }
+
+    #[test]
+    fn enumeration_and_loopback() {
+        use crate::class::cdc_acm::{CdcAcmClass, State};
+
+        let mut class = None;
+        let (mut dev, shared) = device(|b| class = Some(CdcAcmClass::new(b, leak(State::new()), 64)));
+        let mut class = class.unwrap();
+
+        let log = control(&mut dev, &shared, setup(0x80, Request::GET_DESCRIPTOR, 0x0100, 0, 64));
+        assert!(matches!(&log[..], [ControlEvent::DataIn { data, .. }]
+            if data.len() == 18 && data[1] == descriptor_type::DEVICE));
+        let log = control(&mut dev, &shared, setup(0x00, Request::SET_ADDRESS, 5, 0, 0));
+        assert_eq!(log, vec![ControlEvent::AcceptSetAddress(5)]);
+        assert_eq!(dev.status().state, UsbDeviceState::Addressed);
+        let log = control(&mut dev, &shared, setup(0x00, Request::SET_CONFIGURATION, 1, 0, 0));
+        assert_eq!(log, vec![ControlEvent::Accept]);
+        assert_eq!(dev.status().state, UsbDeviceState::Configured);
+
+        // Data written by the class reaches the host, and packets from the host reach the class.
+        block_on(class.write_packet(b"ping")).unwrap();
+        assert_eq!(
+            shared.borrow().in_packets.last().map(|(_, p)| &p[..]),
+            Some(&b"ping"[..])
+        );
+        let out = EndpointAddress::from_parts(1, Direction::Out);
+        shared.borrow_mut().out_packets.push_back((out, b"pong".to_vec()));
+        let mut buf = [0; 64];
+        let n = block_on(class.read_packet(&mut buf)).unwrap();
+        assert_eq!(&buf[..n], b"pong");
+    }
+
+    #[test]
+    fn enumeration_through_run_loop() {
+        let (mut dev, shared) = device(|_| {});
+
+        shared.borrow_mut().bus_events.push_back(Event::Reset);
+        shared.borrow_mut().setups.extend([
+            setup(0x80, Request::GET_DESCRIPTOR, 0x0100, 0, 64),
+            setup(0x00, Request::SET_ADDRESS, 5, 0, 0),
+            setup(0x00, Request::SET_CONFIGURATION, 1, 0, 0),
+        ]);
+        let done = poll_fn(|_| match shared.borrow().setups.is_empty() {
+            true => Poll::Ready(()),
+            false => Poll::Pending,
+        });
+        block_on(select(dev.run(), done));
+        assert_eq!(dev.status().state, UsbDeviceState::Configured);
+
+        // The run loop returns once the host suspends the bus.
+        shared.borrow_mut().bus_events.push_back(Event::Suspend);
+        block_on(dev.run_until_suspend());
+        assert!(dev.status().suspended);
+    }
+
+    #[test]
+    fn cdc_acm_line_state() {
+        use crate::class::cdc_acm::{CdcAcmClass, ParityType, State, StopBits};
+
+        let mut class = None;
+        let (mut dev, shared) = device(|b| class = Some(CdcAcmClass::new(b, leak(State::new()), 64)));
+        let class = class.unwrap();
+
+        // 115200 baud, 2 stop bits, even parity, 7 data bits.
+        let coding = vec![0x00, 0xc2, 0x01, 0x00, 0x02, 0x02, 0x07];
+        shared.borrow_mut().control_out_packets.push_back(coding.clone());
+        control(&mut dev, &shared, setup(0x21, 0x20, 0, 0, 7));
+        let line_coding = class.line_coding();
+        assert_eq!(line_coding.data_rate(), 115_200);
+        assert_eq!(line_coding.stop_bits(), StopBits::Two);
+        assert_eq!(line_coding.parity_type(), ParityType::Even);
+        assert_eq!(line_coding.data_bits(), 7);
+
+        let log = control(&mut dev, &shared, setup(0xa1, 0x21, 0, 0, 7));
+        assert_eq!(
+            log,
+            vec![ControlEvent::DataIn {
+                data: coding,
+                first: true,
+                last: true
+            }]
+        );
+
+        // SET_CONTROL_LINE_STATE with DTR set and RTS clear.
+        control(&mut dev, &shared, setup(0x21, 0x22, 0x0001, 0, 0));
+        assert!(class.dtr());
+        assert!(!class.rts());
+    }
+
+    #[test]
+    fn transfers_with_zero_max_packet_size() {
+        use crate::driver::{EndpointError, EndpointIn, EndpointOut};
+
+        let mut eps = None;
+        let (_dev, shared) = device(|b| {
+            let mut func = b.function(0xff, 0, 0);
+            let mut iface = func.interface();
+            let mut alt = iface.alt_setting(0xff, 0, 0, None);
+            eps = Some((alt.endpoint_interrupt_in(0, 1), alt.endpoint_interrupt_out(0, 1)));
+        });
+        let (mut ep_in, mut ep_out) = eps.unwrap();
+
+        // Nothing fits in a packet, but the transfers fail instead of panicking or hanging.
+        assert_eq!(
+            block_on(ep_in.write_transfer(b"abc", true)),
+            Err(EndpointError::BufferOverflow)
+        );
+        let out = EndpointAddress::from_parts(1, Direction::Out);
+        shared.borrow_mut().out_packets.push_back((out, b"abc".to_vec()));
+        let mut buf = [0; 8];
+        assert_eq!(block_on(ep_out.read_transfer(&mut buf)), Ok(3));
+    }
+
+    #[test]
+    fn rndis_encapsulated_responses() {
+        use crate::class::rndis::{RndisClass, State};
+
+        let mut class = None;
+        let (mut dev, shared) =
+            device(|b| class = Some(RndisClass::new(b, leak(State::new()), [2, 0, 0, 0, 0, 1], 64)));
+        let (_sender, mut receiver) = class.unwrap().split();
+
+        let words = |w: &[u32]| w.iter().flat_map(|x| x.to_le_bytes()).collect::<Vec<u8>>();
+        let get_response = |dev: &mut UsbDevice<'static, TestDriver>| match &control(
+            dev,
+            &shared,
+            setup(0xa1, 0x01, 0, 0, 4096),
+        )[..]
+        {
+            [ControlEvent::DataIn { data, .. }] => data.clone(),
+            log => panic!("unexpected control log {:?}", log),
+        };
+
+        // INITIALIZE
+        shared
+            .borrow_mut()
+            .control_out_packets
+            .push_back(words(&[2, 24, 1, 1, 0, 0x4000]));
+        control(&mut dev, &shared, setup(0x21, 0x00, 0, 0, 24));
+        assert_eq!(
+            get_response(&mut dev),
+            words(&[0x8000_0002, 52, 1, 0, 1, 0, 1, 0, 1, 2048, 0, 0, 0])
+        );
+
+        // KEEPALIVE
+        shared
+            .borrow_mut()
+            .control_out_packets
+            .push_back(words(&[8, 12, 0x1234]));
+        control(&mut dev, &shared, setup(0x21, 0x00, 0, 0, 12));
+        assert_eq!(get_response(&mut dev), words(&[0x8000_0008, 16, 0x1234, 0]));
+
+        // With nothing pending, the response is a single zero byte.
+        assert_eq!(get_response(&mut dev), vec![0]);
+
+        // QUERY(OID_802_3_CURRENT_ADDRESS)
+        shared
+            .borrow_mut()
+            .control_out_packets
+            .push_back(words(&[4, 28, 7, 0x0101_0102, 0, 20, 0]));
+        control(&mut dev, &shared, setup(0x21, 0x00, 0, 0, 28));
+        let mut expected = words(&[0x8000_0004, 30, 7, 0, 6, 16]);
+        expected.extend_from_slice(&[2, 0, 0, 0, 0, 1]);
+        assert_eq!(get_response(&mut dev), expected);
+
+        // SET of an unknown OID is reported as unsupported.
+        shared
+            .borrow_mut()
+            .control_out_packets
+            .push_back(words(&[5, 32, 9, 0xdead_beef, 4, 20, 0, 0]));
+        control(&mut dev, &shared, setup(0x21, 0x00, 0, 0, 32));
+        assert_eq!(get_response(&mut dev), words(&[0x8000_0005, 16, 9, 0xc000_00bb]));
+
+        // SET with an information buffer offset pointing past the message.
+        shared
+            .borrow_mut()
+            .control_out_packets
+            .push_back(words(&[5, 32, 10, 0x0001_010e, 4, u32::MAX, 0, 0x0f]));
+        control(&mut dev, &shared, setup(0x21, 0x00, 0, 0, 32));
+        assert_eq!(get_response(&mut dev), words(&[0x8000_0005, 16, 10, 0xc001_0015]));
+
+        // SET(OID_GEN_CURRENT_PACKET_FILTER) brings the link up, after the receiver has told the
+        // host a response is available.
+        shared
+            .borrow_mut()
+            .control_out_packets
+            .push_back(words(&[5, 32, 11, 0x0001_010e, 4, 20, 0, 0x0f]));
+        control(&mut dev, &shared, setup(0x21, 0x00, 0, 0, 32));
+        assert_eq!(get_response(&mut dev), words(&[0x8000_0005, 16, 11, 0]));
+        block_on(receiver.wait_connection()).unwrap();
+        let notification = (
+            EndpointAddress::from_parts(1, Direction::In),
+            vec![1, 0, 0, 0, 0, 0, 0, 0],
+        );
+        assert_eq!(shared.borrow().in_packets, vec![notification]);
+    }
+
+    #[test]
+    fn uvc_probe_commit() {
+        use crate::class::uvc::{Config as UvcConfig, Format, State, UvcClass};
+
+        let config = UvcConfig {
+            format: Format::Yuy2,
+            width: 4,
+            height: 2,
+            frame_interval: 333_333,
+            max_packet_size: 16,
+        };
+        let mut class = None;
+        let (mut dev, shared) = device(|b| class = Some(UvcClass::new(b, leak(State::new()), config)));
+        let class = class.unwrap();
+
+        let proposal = |format_index: u8, frame_index: u8| {
+            let mut data = vec![0; 34];
+            data[2] = format_index;
+            data[3] = frame_index;
+            data[4..8].copy_from_slice(&666_666u32.to_le_bytes());
+            data[12..14].copy_from_slice(&5000u16.to_le_bytes()); // wCompQuality
+            data
+        };
+
+        // The device replaces what it decides with the only values it supports, and keeps the
+        // compression quality the host chose.
+        shared.borrow_mut().control_out_packets.push_back(proposal(1, 1));
+        let log = control(&mut dev, &shared, setup(0x21, 0x01, 0x0100, 1, 34));
+        assert_eq!(log, vec![ControlEvent::DataOut { len: 34 }, ControlEvent::Accept]);
+        let log = control(&mut dev, &shared, setup(0xa1, 0x81, 0x0100, 1, 34));
+        let [ControlEvent::DataIn { data, .. }] = &log[..] else {
+            panic!("unexpected control log {:?}", log);
+        };
+        assert_eq!(&data[2..8], &[1, 1, 0x15, 0x16, 0x05, 0x00]); // 333_333
+        assert_eq!(&data[12..14], &5000u16.to_le_bytes());
+        assert_eq!(&data[18..26], &[16, 0, 0, 0, 16, 0, 0, 0]);
+
+        // Committing a format or frame the device doesn't have is stalled.
+        for (format_index, frame_index) in [(2, 1), (1, 2), (0, 0)] {
+            shared
+                .borrow_mut()
+                .control_out_packets
+                .push_back(proposal(format_index, frame_index));
+            let log = control(&mut dev, &shared, setup(0x21, 0x01, 0x0200, 1, 34));
+            assert_eq!(log, vec![ControlEvent::DataOut { len: 34 }, ControlEvent::Reject]);
+        }
+        assert_eq!(class.committed(), None);
+
+        shared.borrow_mut().control_out_packets.push_back(data.clone());
+        let log = control(&mut dev, &shared, setup(0x21, 0x01, 0x0200, 1, 34));
+        assert_eq!(log, vec![ControlEvent::DataOut { len: 34 }, ControlEvent::Accept]);
+        let committed = class.committed().unwrap();
+        assert_eq!((committed.format_index, committed.frame_index), (1, 1));
+        assert_eq!(committed.frame_interval, 333_333);
+        assert_eq!(committed.comp_quality, 5000);
+    }
+
+    #[test]
+    fn uvc_streaming() {
+        use crate::class::uvc::{Config as UvcConfig, Format, State, UvcClass};
+
+        let config = UvcConfig {
+            format: Format::Yuy2,
+            width: 4,
+            height: 2,
+            frame_interval: 333_333,
+            max_packet_size: 16,
+        };
+        let mut class = None;
+        let (mut dev, shared) = device(|b| class = Some(UvcClass::new(b, leak(State::new()), config)));
+        let mut class = class.unwrap();
+        control(&mut dev, &shared, setup(0x00, Request::SET_ADDRESS, 5, 0, 0));
+        control(&mut dev, &shared, setup(0x00, Request::SET_CONFIGURATION, 1, 0, 0));
+        assert!(!class.is_streaming());
+
+        // The operational alternate setting starts streaming.
+        let log = control(&mut dev, &shared, setup(0x01, Request::SET_INTERFACE, 1, 1, 0));
+        assert_eq!(log, vec![ControlEvent::Accept]);
+        assert!(class.is_streaming());
+        block_on(class.wait_streaming());
+
+        // Frames are split into packets with a payload header. FID toggles from one frame to the
+        // next, and EOF marks the last packet of a frame.
+        let frame: Vec<u8> = (0..20).collect();
+        block_on(class.send_frame(&frame)).unwrap();
+        block_on(class.send_frame(&[])).unwrap();
+        let ep = EndpointAddress::from_parts(1, Direction::In);
+        let mut first = vec![2, 0x80];
+        first.extend(0..14);
+        let mut last = vec![2, 0x82];
+        last.extend(14..20);
+        assert_eq!(
+            shared.borrow().in_packets,
+            vec![(ep, first), (ep, last), (ep, vec![2, 0x83])]
+        );
+
+        // The zero-bandwidth alternate setting stops it.
+        let log = control(&mut dev, &shared, setup(0x01, Request::SET_INTERFACE, 0, 1, 0));
+        assert_eq!(log, vec![ControlEvent::Accept]);
+        assert!(!class.is_streaming());
+    }
+
+    #[test]
+    fn rndis_packets() {
+        use crate::class::rndis::{RndisClass, State};
+
+        let mut class = None;
+        let (_dev, shared) = device(|b| class = Some(RndisClass::new(b, leak(State::new()), [2, 0, 0, 0, 0, 1], 64)));
+        let (mut sender, mut receiver) = class.unwrap().split();
+
+        let words = |w: &[u32]| w.iter().flat_map(|x| x.to_le_bytes()).collect::<Vec<u8>>();
+        let packet_msg = |data_offset: u32, data_len: u32, data: &[u8]| {
+            let mut msg = words(&[1, 44 + data.len() as u32, data_offset, data_len, 0, 0, 0, 0, 0, 0, 0]);
+            msg.extend_from_slice(data);
+            msg
+        };
+
+        block_on(sender.write_packet(b"ping")).unwrap();
+        let data_in = EndpointAddress::from_parts(2, Direction::In);
+        assert_eq!(shared.borrow().in_packets, vec![(data_in, packet_msg(36, 4, b"ping"))]);
+
+        // Messages with data out of range, even through an overflowing offset, are dropped.
+        let data_out = EndpointAddress::from_parts(1, Direction::Out);
+        shared.borrow_mut().out_packets.extend([
+            (data_out, packet_msg(36, 5, b"pong")),
+            (data_out, packet_msg(u32::MAX - 3, 4, b"pong")),
+            (data_out, packet_msg(36, u32::MAX, b"pong")),
+            (data_out, packet_msg(36, 4, b"pong")),
+        ]);
+        let mut buf = [0; 64];
+        let n = block_on(receiver.read_packet(&mut buf)).unwrap();
+        assert_eq!(&buf[..n], b"pong");
+        assert!(shared.borrow().out_packets.is_empty());
+    }
+
+    #[cfg(feature = "descriptor-stats")]
+    #[test]
+    fn descriptor_fetch_counts() {
+        let (mut dev, shared) = device(|_| {});
+
+        for _ in 0..3 {
+            control(&mut dev, &shared, setup(0x80, Request::GET_DESCRIPTOR, 0x0100, 0, 18));
+        }
+        control(&mut dev, &shared, setup(0x80, Request::GET_DESCRIPTOR, 0x0200, 0, 9));
+        control(&mut dev, &shared, setup(0x80, Request::GET_DESCRIPTOR, 0x2200, 0, 9));
+
+        let counts = dev.take_descriptor_fetch_counts();
+        assert_eq!(
+            counts,
+            DescriptorFetchCounts {
+                device: 3,
+                configuration: 1,
+                other: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(dev.take_descriptor_fetch_counts(), DescriptorFetchCounts::default());
+    }
+
+    #[test]
+    fn clear_endpoint_halt() {
+        struct Unhalted(Rc<RefCell<Vec<EndpointAddress>>>);
+
+        impl Handler for Unhalted {
+            fn endpoint_unhalted(&mut self, ep_addr: EndpointAddress) {
+                self.0.borrow_mut().push(ep_addr);
+            }
+        }
+
+        let unhalted = Rc::new(RefCell::new(Vec::new()));
+        let (mut dev, shared) = device(|b| {
+            let mut func = b.function(0xff, 0, 0);
+            let mut iface = func.interface();
+            let mut alt = iface.alt_setting(0xff, 0, 0, None);
+            alt.endpoint_bulk_in(64);
+            drop(func);
+            b.handler(leak(Unhalted(unhalted.clone())));
+        });
+        let ep_in = EndpointAddress::from_parts(1, Direction::In);
+
+        control(&mut dev, &shared, setup(0x02, Request::SET_FEATURE, 0, 0x81, 0));
+        assert!(shared.borrow().stalled.contains(&ep_in));
+
+        let log = control(&mut dev, &shared, setup(0x02, Request::CLEAR_FEATURE, 0, 0x81, 0));
+        assert_eq!(log, vec![ControlEvent::Accept]);
+        assert!(!shared.borrow().stalled.contains(&ep_in));
+        assert_eq!(*unhalted.borrow(), vec![ep_in]);
+    }
+
+    #[test]
+    fn remote_wakeup_requires_suspend_and_feature() {
+        let (mut dev, shared) = device(|_| {});
+
+        block_on(dev.inner.handle_bus_event(Event::Suspend));
+        assert_eq!(block_on(dev.remote_wakeup()), Err(RemoteWakeupError::InvalidState));
+
+        block_on(dev.inner.handle_bus_event(Event::Resume));
+        control(
+            &mut dev,
+            &shared,
+            setup(0x00, Request::SET_FEATURE, Request::FEATURE_DEVICE_REMOTE_WAKEUP, 0, 0),
+        );
+        assert_eq!(block_on(dev.remote_wakeup()), Err(RemoteWakeupError::InvalidState));
+
+        block_on(dev.inner.handle_bus_event(Event::Suspend));
+        assert_eq!(block_on(dev.remote_wakeup()), Ok(()));
+        assert!(!dev.status().suspended);
+    }
+
+    #[test]
+    fn class_stalls_own_endpoint() {
+        let mut ep = None;
+        let (mut dev, shared) = device(|b| {
+            let mut func = b.function(0xff, 0, 0);
+            let mut iface = func.interface();
+            let mut alt = iface.alt_setting(0xff, 0, 0, None);
+            ep = Some(alt.endpoint_bulk_out(64));
+        });
+        let mut ep = ep.unwrap();
+
+        ep.set_stalled(true).unwrap();
+        let log = control(&mut dev, &shared, setup(0x82, Request::GET_STATUS, 0, 0x01, 2));
+        assert_eq!(
+            log,
+            vec![ControlEvent::DataIn {
+                data: vec![1, 0],
+                first: true,
+                last: true
+            }]
+        );
+
+        ep.set_stalled(false).unwrap();
+        let log = control(&mut dev, &shared, setup(0x82, Request::GET_STATUS, 0, 0x01, 2));
+        assert_eq!(
+            log,
+            vec![ControlEvent::DataIn {
+                data: vec![0, 0],
+                first: true,
+                last: true
+            }]
+        );
+
+        // The class sees the host clearing the halt.
+        ep.set_stalled(true).unwrap();
+        assert_eq!(ep.is_stalled(), Ok(true));
+        control(&mut dev, &shared, setup(0x02, Request::CLEAR_FEATURE, 0, 0x01, 0));
+        assert_eq!(ep.is_stalled(), Ok(false));
+    }
+
+    #[test]
+    fn get_status_requires_length_2() {
+        let (mut dev, shared) = device(|_| {});
+
+        let log = control(&mut dev, &shared, setup(0x80, Request::GET_STATUS, 0, 0, 1));
+        assert_eq!(log, vec![ControlEvent::Reject]);
+        let log = control(&mut dev, &shared, setup(0x82, Request::GET_STATUS, 0, 0x80, 64));
+        assert_eq!(log, vec![ControlEvent::Reject]);
+
+        let log = control(&mut dev, &shared, setup(0x80, Request::GET_STATUS, 0, 0, 2));
+        assert_eq!(
+            log,
+            vec![ControlEvent::DataIn {
+                data: vec![0, 0],
+                first: true,
+                last: true
+            }]
+        );
+    }
+
+    #[test]
+    fn self_powered_from_config() {
+        let mut config = Config::new(0xc0de, 0xcafe);
+        config.self_powered = true;
+        config.max_power = 0;
+        let (mut dev, shared) = device_with_config(config, |_| {});
+
+        let log = control(&mut dev, &shared, setup(0x80, Request::GET_DESCRIPTOR, 0x0200, 0, 9));
+        assert!(matches!(&log[..], [ControlEvent::DataIn { data, .. }] if data[7..9] == [0xc0, 0]));
+
+        let log = control(&mut dev, &shared, setup(0x80, Request::GET_STATUS, 0, 0, 2));
+        assert_eq!(
+            log,
+            vec![ControlEvent::DataIn {
+                data: vec![1, 0],
+                first: true,
+                last: true
+            }]
+        );
+    }
+
+    #[test]
+    fn device_descriptor_prefix_before_address() {
+        let mut config = Config::new(0xc0de, 0xcafe);
+        config.max_packet_size_0 = 8;
+        config.full_speed_only = true;
+        let (mut dev, shared) = device_with_config(config, |_| {});
+        let prefix = dev.inner.device_descriptor[..8].to_vec();
+        assert_eq!(prefix[7], 8);
+
+        // The host first asks for exactly 8 bytes to learn bMaxPacketSize0.
+        let log = control(&mut dev, &shared, setup(0x80, Request::GET_DESCRIPTOR, 0x0100, 0, 8));
+        let expected = vec![ControlEvent::DataIn {
+            data: prefix,
+            first: true,
+            last: true,
+        }];
+        assert_eq!(log, expected);
+
+        // Hosts that ask for more get a single packet until the device is addressed.
+        let log = control(&mut dev, &shared, setup(0x80, Request::GET_DESCRIPTOR, 0x0100, 0, 64));
+        assert_eq!(log, expected);
+    }
+
+    #[test]
+    fn set_descriptor() {
+        struct Strings(Rc<RefCell<Vec<u8>>>);
+
+        impl Handler for Strings {
+            fn set_descriptor(
+                &mut self,
+                descriptor_type: u8,
+                index: u8,
+                lang_id: u16,
+                data: &[u8],
+            ) -> Option<OutResponse> {
+                (descriptor_type == descriptor_type::STRING && index == 4 && lang_id == 0x0409).then(|| {
+                    self.0.borrow_mut().extend_from_slice(data);
+                    OutResponse::Accepted
+                })
+            }
+        }
+
+        let received = Rc::new(RefCell::new(Vec::new()));
+        let (mut dev, shared) = device(|b| b.handler(leak(Strings(received.clone()))));
+
+        shared.borrow_mut().control_out_packets.push_back(vec![4, 3, b'A', 0]);
+        let log = control(
+            &mut dev,
+            &shared,
+            setup(0x00, Request::SET_DESCRIPTOR, 0x0304, 0x0409, 4),
+        );
+        assert_eq!(log.last(), Some(&ControlEvent::Accept));
+        assert_eq!(*received.borrow(), [4, 3, b'A', 0]);
+
+        // Descriptors no handler takes are stalled.
+        shared.borrow_mut().control_out_packets.push_back(vec![9, 2, 0, 0]);
+        let log = control(&mut dev, &shared, setup(0x00, Request::SET_DESCRIPTOR, 0x0200, 0, 4));
+        assert_eq!(log.last(), Some(&ControlEvent::Reject));
+    }
+
+    #[test]
+    fn large_control_out() {
+        struct Sink(Rc<Cell<usize>>);
+
+        impl Handler for Sink {
+            fn control_out(&mut self, _req: Request, data: &[u8]) -> Option<OutResponse> {
+                self.0.set(data.len());
+                Some(OutResponse::Accepted)
+            }
+        }
+
+        let received = Rc::new(Cell::new(0));
+        let (driver, shared) = TestDriver::new();
+        let mut builder = Builder::new(
+            driver,
+            Config::new(0xc0de, 0xcafe),
+            leak([0; 256]),
+            leak([0; 256]),
+            leak([0; 256]),
+            leak([0; 512]),
+        );
+        builder.handler(leak(Sink(received.clone())));
+        let mut dev = builder.build();
+
+        // The data stage is read packet by packet into the whole control buffer.
+        for _ in 0..4 {
+            shared.borrow_mut().control_out_packets.push_back(vec![0xaa; 64]);
+        }
+        shared.borrow_mut().control_out_packets.push_back(vec![0xaa; 44]);
+        let log = control(&mut dev, &shared, setup(0x40, 0x01, 0, 0, 300));
+        assert_eq!(log.last(), Some(&ControlEvent::Accept));
+        assert_eq!(received.get(), 300);
+
+        // Requests that don't fit are stalled without reading the data stage.
+        received.set(0);
+        let log = control(&mut dev, &shared, setup(0x40, 0x01, 0, 0, 513));
+        assert_eq!(log, vec![ControlEvent::Reject]);
+        assert_eq!(received.get(), 0);
+    }
+
+    #[test]
+    fn function_iad_counts_interfaces() {
+        let (dev, _shared) = device(|b| {
+            b.function(0xff, 0, 0).interface().alt_setting(0xff, 0, 0, None);
+
+            let mut func = b.function(0x02, 0x02, 0x00);
+            func.interface().alt_setting(0x02, 0x02, 0x00, None);
+            func.interface().alt_setting(0x0a, 0x00, 0x00, None);
+        });
+
+        // Configuration descriptor, IAD, interface 0, then the second function's IAD.
+        let iad = &dev.inner.config_descriptor[9 + 8 + 9..][..8];
+        assert_eq!(iad, &[8, descriptor_type::IAD, 1, 2, 0x02, 0x02, 0x00, 0]);
+    }
+
+    #[test]
+    fn custom_descriptor_type() {
+        struct Blob;
+
+        impl Handler for Blob {
+            fn get_descriptor<'a>(
+                &'a mut self,
+                descriptor_type: u8,
+                index: u8,
+                _buf: &'a mut [u8],
+            ) -> Option<InResponse<'a>> {
+                (descriptor_type == 0x40 && index == 0).then_some(InResponse::Accepted(&[4, 0x40, 1, 2]))
+            }
+        }
+
+        let (mut dev, shared) = device(|b| b.handler(leak(Blob)));
+
+        let log = control(&mut dev, &shared, setup(0x80, Request::GET_DESCRIPTOR, 0x4000, 0, 255));
+        assert_eq!(
+            log,
+            vec![ControlEvent::DataIn {
+                data: vec![4, 0x40, 1, 2],
+                first: true,
+                last: true
+            }]
+        );
+
+        let log = control(&mut dev, &shared, setup(0x80, Request::GET_DESCRIPTOR, 0x4100, 0, 255));
+        assert_eq!(log, vec![ControlEvent::Reject]);
+    }
+
+    #[test]
+    fn control_in_status_stage_from_last_packet() {
+        let (mut dev, shared) = device(|b| {
+            let mut func = b.function(0xff, 0, 0);
+            let mut iface = func.interface();
+            let mut alt = iface.alt_setting(0xff, 0, 0, None);
+            alt.descriptor(descriptor_type::CS_INTERFACE, &[0; 100]);
+        });
+        control(&mut dev, &shared, setup(0x00, Request::SET_ADDRESS, 5, 0, 0));
+
+        let config_descriptor = dev.inner.config_descriptor.to_vec();
+        assert!(config_descriptor.len() > 64);
+        let len = config_descriptor.len() as u16;
+        let log = control(&mut dev, &shared, setup(0x80, Request::GET_DESCRIPTOR, 0x0200, 0, len));
+
+        // The driver ACKs the host's OUT status stage after the packet marked `last`, so the stack
+        // must not call accept() as well.
+        let mut data = Vec::new();
+        for (i, event) in log.iter().enumerate() {
+            let ControlEvent::DataIn {
+                data: packet,
+                first,
+                last,
+            } = event
+            else {
+                panic!("unexpected control event {:?}", event);
+            };
+            assert_eq!(*first, i == 0);
+            assert_eq!(*last, i == log.len() - 1);
+            data.extend_from_slice(packet);
+        }
+        assert_eq!(data, config_descriptor);
+    }
+
+    #[test]
+    fn unknown_interface_stalls() {
+        let (mut dev, shared) = device(|b| {
+            b.function(0xff, 0, 0).interface().alt_setting(0xff, 0, 0, None);
+        });
+        control(&mut dev, &shared, setup(0x00, Request::SET_ADDRESS, 5, 0, 0));
+        control(&mut dev, &shared, setup(0x00, Request::SET_CONFIGURATION, 1, 0, 0));
+
+        let log = control(&mut dev, &shared, setup(0x01, Request::SET_INTERFACE, 0, 1, 0));
+        assert_eq!(log, vec![ControlEvent::Reject]);
+        let log = control(&mut dev, &shared, setup(0x81, Request::GET_INTERFACE, 0, 1, 1));
+        assert_eq!(log, vec![ControlEvent::Reject]);
+    }
+
+    #[test]
+    fn unsupported_alternate_setting_stalls() {
+        struct AltSettings(Rc<RefCell<Vec<u8>>>);
+
+        impl Handler for AltSettings {
+            fn set_alternate_setting(&mut self, _iface: InterfaceNumber, alternate_setting: u8) {
+                self.0.borrow_mut().push(alternate_setting);
+            }
+        }
+
+        let selected = Rc::new(RefCell::new(Vec::new()));
+        let (mut dev, shared) = device(|b| {
+            let mut func = b.function(0xff, 0, 0);
+            let mut iface = func.interface();
+            iface.alt_setting(0xff, 0, 0, None);
+            iface.alt_setting(0xff, 0, 0, None);
+            func.handler(leak(AltSettings(selected.clone())));
+        });
+        control(&mut dev, &shared, setup(0x00, Request::SET_ADDRESS, 5, 0, 0));
+        control(&mut dev, &shared, setup(0x00, Request::SET_CONFIGURATION, 1, 0, 0));
+
+        let log = control(&mut dev, &shared, setup(0x01, Request::SET_INTERFACE, 5, 0, 0));
+        assert_eq!(log, vec![ControlEvent::Reject]);
+        assert!(selected.borrow().is_empty());
+
+        let log = control(&mut dev, &shared, setup(0x01, Request::SET_INTERFACE, 1, 0, 0));
+        assert_eq!(log, vec![ControlEvent::Accept]);
+        assert_eq!(*selected.borrow(), vec![1]);
+    }
+
+    #[test]
+    fn interface_requests_routed_to_owner() {
+        /// A careless class answering class requests for any interface.
+        struct Greedy(u8);
+
+        impl Handler for Greedy {
+            fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+                if req.request_type != RequestType::Class {
+                    return None;
+                }
+                buf[0] = self.0;
+                Some(InResponse::Accepted(&buf[..1]))
+            }
+        }
+
+        let (mut dev, shared) = device(|b| {
+            for id in [0xa0, 0xb0] {
+                let mut func = b.function(0xff, 0, 0);
+                func.interface().alt_setting(0xff, 0, 0, None);
+                func.handler(leak(Greedy(id)));
+            }
+            b.function(0xff, 0, 0).interface().alt_setting(0xff, 0, 0, None);
+            b.handler(leak(Greedy(0xc0)));
+        });
+
+        for (iface, id) in [(0, 0xa0), (1, 0xb0)] {
+            let log = control(&mut dev, &shared, setup(0xa1, 0x01, 0, iface, 1));
+            assert_eq!(
+                log,
+                vec![ControlEvent::DataIn {
+                    data: vec![id],
+                    first: true,
+                    last: true
+                }]
+            );
+        }
+
+        // Interface 2 has no owner, so its requests go to the handler owning no interface.
+        let log = control(&mut dev, &shared, setup(0xa1, 0x01, 0, 2, 1));
+        assert_eq!(
+            log,
+            vec![ControlEvent::DataIn {
+                data: vec![0xc0],
+                first: true,
+                last: true
+            }]
+        );
+
+        // Requests for an interface that doesn't exist are routed the same way.
+        let log = control(&mut dev, &shared, setup(0xa1, 0x01, 0, 3, 1));
+        assert_eq!(
+            log,
+            vec![ControlEvent::DataIn {
+                data: vec![0xc0],
+                first: true,
+                last: true
+            }]
+        );
+    }
+
+    #[test]
+    fn class_sees_assigned_interface_number() {
+        struct Class(InterfaceNumber);
+
+        impl Handler for Class {
+            fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+                if !req.is_class_interface(self.0) {
+                    return None;
+                }
+                buf[0] = self.0 .0;
+                Some(InResponse::Accepted(&buf[..1]))
+            }
+        }
+
+        fn add_class(b: &mut Builder<'static, TestDriver>) -> InterfaceNumber {
+            let mut func = b.function(0xff, 0, 0);
+            let mut iface = func.interface();
+            let number = iface.interface_number();
+            iface.alt_setting(0xff, 0, 0, None);
+            func.handler(leak(Class(number)));
+            number
+        }
+
+        let mut numbers = Vec::new();
+        let (mut dev, shared) = device(|b| {
+            for _ in 0..3 {
+                numbers.push(add_class(b));
+            }
+        });
+        assert_eq!(
+            numbers,
+            vec![InterfaceNumber(0), InterfaceNumber(1), InterfaceNumber(2)]
+        );
+
+        for n in 0..3u8 {
+            let log = control(&mut dev, &shared, setup(0xa1, 0x01, 0, n as u16, 1));
+            assert_eq!(
+                log,
+                vec![ControlEvent::DataIn {
+                    data: vec![n],
+                    first: true,
+                    last: true
+                }]
+            );
+        }
+    }
+
+    #[test]
+    fn link_power_state() {
+        let (mut dev, _shared) = device(|_| {});
+        assert_eq!(dev.link_power_state(), LinkPowerState::Off);
+
+        block_on(dev.inner.handle_bus_event(Event::PowerDetected));
+        assert_eq!(dev.link_power_state(), LinkPowerState::On);
+        block_on(dev.inner.handle_bus_event(Event::Suspend));
+        assert_eq!(dev.link_power_state(), LinkPowerState::Suspend);
+        block_on(dev.inner.handle_bus_event(Event::Resume));
+        assert_eq!(dev.link_power_state(), LinkPowerState::On);
+        block_on(dev.inner.handle_bus_event(Event::PowerRemoved));
+        assert_eq!(dev.link_power_state(), LinkPowerState::Off);
+    }
+
+    #[test]
+    fn configured_watch() {
+        use embassy_futures::poll_once;
+
+        let watch = leak(ConfiguredWatch::new());
+        let (mut dev, shared) = device(|_| {});
+        dev.set_configured_watch(watch);
+        assert!(poll_once(watch.wait_configured()).is_pending());
+
+        control(&mut dev, &shared, setup(0x00, Request::SET_ADDRESS, 5, 0, 0));
+        control(&mut dev, &shared, setup(0x00, Request::SET_CONFIGURATION, 1, 0, 0));
+        dev.signal_state_change();
+        assert!(watch.is_configured());
+        assert!(poll_once(watch.wait_configured()).is_ready());
+
+        // After a bus reset the device has to be configured again.
+        block_on(dev.inner.handle_bus_event(Event::Reset));
+        dev.signal_state_change();
+        assert!(poll_once(watch.wait_configured()).is_pending());
+        control(&mut dev, &shared, setup(0x00, Request::SET_ADDRESS, 5, 0, 0));
+        control(&mut dev, &shared, setup(0x00, Request::SET_CONFIGURATION, 1, 0, 0));
+        dev.signal_state_change();
+        assert!(poll_once(watch.wait_configured()).is_ready());
+    }
+
+    #[test]
+    fn state_signal() {
+        let signal = leak(Signal::new());
+        let (mut dev, shared) = device(|_| {});
+        dev.set_state_signal(signal);
+        let mut states = Vec::new();
+        let mut step = |dev: &mut UsbDevice<'static, TestDriver>| {
+            dev.signal_state_change();
+            let status = signal.try_take();
+            states.push(status.map(|s| (s.state, s.suspended)));
+        };
+
+        block_on(dev.inner.handle_bus_event(Event::PowerDetected));
+        step(&mut dev);
+        control(&mut dev, &shared, setup(0x00, Request::SET_ADDRESS, 5, 0, 0));
+        step(&mut dev);
+        control(&mut dev, &shared, setup(0x00, Request::SET_CONFIGURATION, 1, 0, 0));
+        step(&mut dev);
+        control(&mut dev, &shared, setup(0x00, Request::SET_CONFIGURATION, 1, 0, 0));
+        step(&mut dev);
+        block_on(dev.inner.handle_bus_event(Event::Suspend));
+        step(&mut dev);
+        block_on(dev.inner.handle_bus_event(Event::Resume));
+        step(&mut dev);
+        block_on(dev.disable());
+        step(&mut dev);
+
+        assert_eq!(dev.state(), UsbDeviceState::Disabled);
+        assert_eq!(
+            states,
+            vec![
+                Some((UsbDeviceState::Default, false)),
+                Some((UsbDeviceState::Addressed, false)),
+                Some((UsbDeviceState::Configured, false)),
+                None,
+                Some((UsbDeviceState::Configured, true)),
+                Some((UsbDeviceState::Configured, false)),
+                Some((UsbDeviceState::Disabled, false)),
+            ]
+        );
+    }
+}
@@ -12,7 +12,7 @@ use embassy_rp::peripherals::USB;
 use embassy_rp::usb::{Driver, Instance, InterruptHandler};
 use embassy_usb::class::cdc_acm::{CdcAcmClass, State};
 use embassy_usb::driver::EndpointError;
-use embassy_usb::UsbDevice;
+use embassy_usb::{Buffers, UsbDevice};
 use static_cell::StaticCell;
 use {defmt_rtt as _, panic_probe as _};
 
@@ -41,21 +41,11 @@ async fn main(spawner: Spawner) {
     };
 
     // Create embassy-usb DeviceBuilder using the driver and config.
-    // It needs some buffers for building the descriptors.
+    // It needs some buffers for building the descriptors: 256 bytes each for the config and BOS
+    // descriptors, none for msos descriptors, and 64 bytes for control requests.
     let mut builder = {
-        static CONFIG_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
-        static BOS_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
-        static CONTROL_BUF: StaticCell<[u8; 64]> = StaticCell::new();
-
-        let builder = embassy_usb::Builder::new(
-            driver,
-            config,
-            CONFIG_DESCRIPTOR.init([0; 256]),
-            BOS_DESCRIPTOR.init([0; 256]),
-            &mut [], // no msos descriptors
-            CONTROL_BUF.init([0; 64]),
-        );
-        builder
+        static BUFFERS: StaticCell<Buffers<256, 256, 0, 64>> = StaticCell::new();
+        embassy_usb::Builder::with_buffers(driver, config, BUFFERS.init(Buffers::new()))
     };
 
     // Create classes on the builder.
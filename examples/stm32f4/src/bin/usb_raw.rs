@@ -158,8 +158,8 @@ async fn main(_spawner: Spawner) {
     let mut interface = function.interface();
     let _alternate = interface.alt_setting(0xFF, 0, 0, None);
     handler.if_num = interface.interface_number();
+    function.handler(&mut handler);
     drop(function);
-    builder.handler(&mut handler);
 
     // Build the builder.
     let mut usb = builder.build();
@@ -73,6 +73,7 @@ async fn main(_spawner: Spawner) {
         request_handler: Some(&mut request_handler),
         poll_ms: 60,
         max_packet_size: 8,
+        country_code: 0,
     };
 
     let mut writer = HidWriter::<_, 5>::new(&mut builder, &mut state, config);